@@ -0,0 +1,180 @@
+// Band-limited resampling from the CPU clock to the output sample rate,
+// after blargg's blip_buf: http://www.slack.net/~ant/libs/audio.html#Blip_Buffer
+//
+// The APU output is a sum of square-ish waves that only change level at CPU
+// cycle boundaries. Instead of sampling it (which aliases every edge above
+// Nyquist back into the audible range) each level change is recorded as a
+// windowed-sinc impulse placed at its exact fractional position between output
+// samples, and the output is the running sum of those impulses: a band-limited
+// step for every edge. Unlike blip_buf the integrator doesn't leak: DC is
+// left in for the raw output and removed by the filter chain otherwise.
+
+// sub-sample resolution of step positions
+const PHASES: usize = 32;
+const HALF_WIDTH: usize = 8;
+const KERNEL_WIDTH: usize = HALF_WIDTH * 2;
+// fraction of the output Nyquist frequency let through by the kernel
+const CUTOFF: f64 = 0.9;
+
+pub struct BlipBuffer {
+    // output samples per input clock
+    factor: f64,
+    // position of clock 0 of the current frame, in output samples from buffer[0]
+    frame_start: f64,
+    // whole samples before frame_start that no future step can change anymore
+    avail: usize,
+    buffer: Vec<f32>,
+    integrator: f32,
+    kernels: Vec<[f32; KERNEL_WIDTH]>,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: f64, sample_rate: f64) -> Self {
+        BlipBuffer {
+            factor: sample_rate / clock_rate,
+            frame_start: 0.0,
+            avail: 0,
+            buffer: vec![0.0; 4096],
+            integrator: 0.0,
+            kernels: build_kernels(),
+        }
+    }
+
+    pub fn set_rates(&mut self, clock_rate: f64, sample_rate: f64) {
+        self.factor = sample_rate / clock_rate;
+    }
+
+    /// Records a change of the input level by `delta` at `clock` cycles into the current frame
+    pub fn add_delta(&mut self, clock: u32, delta: f32) {
+        let position = self.frame_start + clock as f64 * self.factor;
+        let index = position as usize;
+        let phase = ((position - index as f64) * PHASES as f64) as usize;
+
+        if self.buffer.len() < index + KERNEL_WIDTH {
+            self.buffer.resize((index + KERNEL_WIDTH) * 2, 0.0);
+        }
+        let kernel = &self.kernels[phase];
+        for (sample, weight) in self.buffer[index..index + KERNEL_WIDTH].iter_mut().zip(kernel.iter()) {
+            *sample += delta * weight;
+        }
+    }
+
+    /// Ends the current frame after `clocks` cycles, making its samples available
+    pub fn end_frame(&mut self, clocks: u32) {
+        self.frame_start += clocks as f64 * self.factor;
+        self.avail = self.frame_start as usize;
+    }
+
+    pub fn samples_avail(&self) -> usize {
+        self.avail
+    }
+
+    /// Appends every available sample to `out`
+    pub fn read_samples(&mut self, out: &mut Vec<f32>) {
+        let count = self.avail;
+        for delta in self.buffer[..count].iter() {
+            self.integrator += delta;
+            out.push(self.integrator);
+        }
+
+        // tails of the kernels that reach past the frame move to the front
+        self.buffer.copy_within(count.., 0);
+        let len = self.buffer.len();
+        for sample in self.buffer[len - count..].iter_mut() {
+            *sample = 0.0;
+        }
+        self.frame_start -= count as f64;
+        self.avail = 0;
+    }
+}
+
+// Windowed-sinc impulses, one per sub-sample phase, each summing to 1 so that a
+// step of `delta` settles exactly at `delta`.
+fn build_kernels() -> Vec<[f32; KERNEL_WIDTH]> {
+    use std::f64::consts::PI;
+
+    (0..PHASES)
+        .map(|phase| {
+            let mut kernel = [0.0f64; KERNEL_WIDTH];
+            let center = (HALF_WIDTH - 1) as f64 + phase as f64 / PHASES as f64;
+            for (k, weight) in kernel.iter_mut().enumerate() {
+                let x = k as f64 - center;
+                let sinc = if x == 0.0 { 1.0 } else { (PI * x * CUTOFF).sin() / (PI * x * CUTOFF) };
+                // Blackman window over the kernel span
+                let w = (x + HALF_WIDTH as f64) / KERNEL_WIDTH as f64;
+                let window = if (0.0..=1.0).contains(&w) {
+                    0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos()
+                } else {
+                    0.0
+                };
+                *weight = sinc * window;
+            }
+            let sum: f64 = kernel.iter().sum();
+            let mut normalized = [0.0f32; KERNEL_WIDTH];
+            for (out, weight) in normalized.iter_mut().zip(kernel.iter()) {
+                *out = (weight / sum) as f32;
+            }
+            normalized
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CLOCK_RATE: f64 = 1_789_773.0;
+
+    #[test]
+    fn test_step_settles_at_delta() {
+        let mut blip = BlipBuffer::new(CLOCK_RATE, 44100.0);
+        blip.add_delta(100, 0.5);
+        blip.end_frame(2000);
+
+        let mut out = Vec::new();
+        blip.read_samples(&mut out);
+        assert_eq!(out.len(), 49);
+        assert!(out[0].abs() < 0.01);
+        assert!((out[30] - 0.5).abs() < 0.02, "got {}", out[30]);
+    }
+
+    #[test]
+    fn test_no_samples_are_lost_across_frames() {
+        let mut blip = BlipBuffer::new(CLOCK_RATE, 48000.0);
+        let mut out = Vec::new();
+        for _ in 0..1000 {
+            blip.end_frame(1789);
+            blip.read_samples(&mut out);
+        }
+        let expected = (1789.0 * 1000.0 * 48000.0 / CLOCK_RATE) as usize;
+        assert_eq!(out.len(), expected);
+    }
+
+    #[test]
+    fn test_ultrasonic_square_is_filtered_out() {
+        // level changes every 30 clocks: a ~30 kHz square wave
+        let energy = |half_period: u32| {
+            let mut blip = BlipBuffer::new(CLOCK_RATE, 44100.0);
+            let mut out = Vec::new();
+            let mut level = 1.0;
+            for frame in 0..20 {
+                for clock in (0..30_000).step_by(half_period as usize) {
+                    blip.add_delta(clock, level);
+                    level = -level;
+                }
+                blip.end_frame(30_000);
+                if frame < 2 {
+                    blip.read_samples(&mut Vec::new()); // skip the attack
+                } else {
+                    blip.read_samples(&mut out);
+                }
+            }
+            let mean = out.iter().sum::<f32>() / out.len() as f32;
+            out.iter().map(|s| (s - mean) * (s - mean)).sum::<f32>() / out.len() as f32
+        };
+
+        let audible = energy(1500); // ~600 Hz
+        let ultrasonic = energy(30);
+        assert!(ultrasonic < audible * 0.01, "{} vs {}", ultrasonic, audible);
+    }
+}
@@ -0,0 +1,102 @@
+// Volume envelope of the pulse and noise channels: either a constant volume or
+// a sawtooth decaying from 15 to 0, optionally looping.
+// http://wiki.nesdev.com/w/index.php/APU_Envelope
+pub struct Envelope {
+    start: bool,
+    // also halts the length counter of the channel
+    pub loop_flag: bool,
+    constant_volume: bool,
+    // constant volume, or the period of the decay divider
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Envelope {
+            start: false,
+            loop_flag: false,
+            constant_volume: false,
+            volume: 0,
+            divider: 0,
+            decay: 0,
+        }
+    }
+
+    /// --LC VVVV of $4000 / $4004 / $400C
+    pub fn write(&mut self, value: u8) {
+        self.loop_flag = (value & 0x20) != 0;
+        self.constant_volume = (value & 0x10) != 0;
+        self.volume = value & 0x0F;
+    }
+
+    /// Restarts the decay, done by writes to the length counter register
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    /// Clocked by the quarter frames of the frame counter
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.loop_flag {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant_volume {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Envelope::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_envelope_decay() {
+        let mut envelope = Envelope::new();
+        envelope.write(0x01); // decay, period 2 quarter frames
+        envelope.restart();
+        envelope.clock();
+        assert_eq!(envelope.output(), 15);
+
+        for _ in 0..2 * 15 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.output(), 0);
+        for _ in 0..4 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.output(), 0);
+
+        // looping starts over from 15
+        envelope.write(0x21);
+        envelope.clock();
+        envelope.clock();
+        assert_eq!(envelope.output(), 15);
+
+        envelope.write(0x17);
+        assert_eq!(envelope.output(), 7);
+    }
+}
@@ -0,0 +1,23 @@
+// Sound chips on the cartridge. The Famicom routes the cartridge's audio
+// back into its own mix, so their channels play alongside the APU's five.
+// http://wiki.nesdev.com/w/index.php/Expansion_audio
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// level of an APU pulse channel at volume 15 in the mix, the reference the
+// chips are scaled against
+pub const PULSE_FULL_VOLUME: f32 = 95.88 / (8128.0 / 15.0 + 100.0);
+
+pub trait ExpansionAudio {
+    /// Names of the channels, shown next to the APU's in the channel controls
+    fn channel_names(&self) -> &'static [&'static str];
+
+    /// Advances one CPU cycle
+    fn tick(&mut self);
+
+    /// Current level of every channel, in the units of the APU mix
+    fn output(&self, levels: &mut [f32]);
+}
+
+/// The chip is written to by the mapper and played by the APU
+pub type SharedExpansionAudio = Rc<RefCell<dyn ExpansionAudio>>;
@@ -0,0 +1,165 @@
+// The frame counter ("frame sequencer") clocks the envelopes, linear counter,
+// length counters and sweep units, and in 4-step mode raises the frame IRQ.
+// http://wiki.nesdev.com/w/index.php/APU_Frame_Counter
+use crate::region::Region;
+
+pub struct FrameCounter {
+    // CPU cycles of the five steps, see Region::apu_frame_steps
+    steps: &'static [u16; 5],
+    cycle: u16,
+    five_step: bool,
+    irq_inhibit: bool,
+    pub irq_flag: bool,
+    // value written to $4017 and CPU cycles left until it takes effect
+    pending_write: Option<(u8, u8)>,
+    // whether the current CPU cycle is the second half of an APU cycle
+    odd_cycle: bool,
+}
+
+impl FrameCounter {
+    pub fn new() -> Self {
+        FrameCounter {
+            steps: Region::Ntsc.apu_frame_steps(),
+            cycle: 0,
+            five_step: false,
+            irq_inhibit: false,
+            irq_flag: false,
+            pending_write: None,
+            odd_cycle: false,
+        }
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.steps = region.apu_frame_steps();
+    }
+
+    /// $4017 write: MI-- ----, mode (0 = 4-step, 1 = 5-step) and IRQ inhibit
+    pub fn write(&mut self, value: u8) {
+        self.irq_inhibit = (value & 0x40) != 0;
+        if self.irq_inhibit {
+            self.irq_flag = false;
+        }
+        // the sequencer restarts 3 or 4 CPU cycles later, depending on
+        // whether the write landed on an APU cycle or between two
+        let delay = if self.odd_cycle { 4 } else { 3 };
+        self.pending_write = Some((value, delay));
+    }
+
+    /// Advances one CPU cycle, returning whether a quarter and a half frame were clocked
+    pub fn tick(&mut self) -> (bool, bool) {
+        self.odd_cycle = !self.odd_cycle;
+        self.cycle += 1;
+
+        let [step1, step2, step3, step4, step5] = *self.steps;
+        let mut clocks = match self.cycle {
+            c if c == step1 => (true, false),
+            c if c == step2 => (true, true),
+            c if c == step3 => (true, false),
+            _ => (false, false),
+        };
+
+        if self.five_step {
+            if self.cycle == step5 {
+                clocks = (true, true);
+            } else if self.cycle == step5 + 1 {
+                self.cycle = 0;
+            }
+        } else {
+            // the IRQ flag is set on three consecutive cycles around the last step
+            if self.cycle == step4 {
+                clocks = (true, true);
+            }
+            if (step4 - 1..=step4 + 1).contains(&self.cycle) {
+                self.raise_irq();
+            }
+            if self.cycle == step4 + 1 {
+                self.cycle = 0;
+            }
+        }
+
+        if let Some((value, delay)) = self.pending_write {
+            if delay > 1 {
+                self.pending_write = Some((value, delay - 1));
+            } else {
+                self.pending_write = None;
+                self.cycle = 0;
+                self.five_step = (value & 0x80) != 0;
+                // entering 5-step mode clocks everything right away
+                if self.five_step {
+                    clocks = (true, true);
+                }
+            }
+        }
+        clocks
+    }
+
+    fn raise_irq(&mut self) {
+        if !self.irq_inhibit {
+            self.irq_flag = true;
+        }
+    }
+}
+
+impl Default for FrameCounter {
+    fn default() -> Self {
+        FrameCounter::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // runs `cycles` CPU cycles, returning the cycles (1-based) of every quarter and half frame
+    fn run(counter: &mut FrameCounter, cycles: u32) -> (Vec<u32>, Vec<u32>) {
+        let (mut quarters, mut halves) = (Vec::new(), Vec::new());
+        for cycle in 1..=cycles {
+            let (quarter, half) = counter.tick();
+            if quarter {
+                quarters.push(cycle);
+            }
+            if half {
+                halves.push(cycle);
+            }
+        }
+        (quarters, halves)
+    }
+
+    #[test]
+    fn test_four_step_sequence() {
+        let mut counter = FrameCounter::new();
+        let (quarters, halves) = run(&mut counter, 29829);
+        assert_eq!(quarters, vec![7457, 14913, 22371, 29829]);
+        assert_eq!(halves, vec![14913, 29829]);
+        assert!(counter.irq_flag);
+
+        // the sequence repeats every 29830 cycles
+        let (quarters, _) = run(&mut counter, 29830);
+        assert_eq!(quarters, vec![7458, 14914, 22372, 29830]);
+
+        let mut inhibited = FrameCounter::new();
+        inhibited.write(0x40);
+        run(&mut inhibited, 29830);
+        assert!(!inhibited.irq_flag);
+    }
+
+    #[test]
+    fn test_five_step_sequence() {
+        let mut counter = FrameCounter::new();
+        counter.write(0x80);
+        let (quarters, halves) = run(&mut counter, 3 + 37282);
+        // immediate clock after the write delay, then the sequence
+        assert_eq!(quarters, vec![3, 3 + 7457, 3 + 14913, 3 + 22371, 3 + 37281]);
+        assert_eq!(halves, vec![3, 3 + 14913, 3 + 37281]);
+        assert!(!counter.irq_flag);
+    }
+
+    #[test]
+    fn test_write_delay_depends_on_cycle_parity() {
+        let mut counter = FrameCounter::new();
+        counter.tick();
+        counter.write(0x80);
+        let (quarters, _) = run(&mut counter, 4);
+        assert_eq!(quarters, vec![4]);
+    }
+}
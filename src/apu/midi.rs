@@ -0,0 +1,216 @@
+// Transcribes the APU channels to a Standard MIDI File, one track per channel.
+// The channel states are sampled every quarter frame: a note starts when a
+// channel becomes audible, moves to another key or gets louder (a new
+// envelope), and ends when it goes silent.
+// http://www.music.mcgill.ca/~ich/classes/mumt306/StandardMIDIfileformat.html
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// 120 BPM at 480 ticks per quarter note: 960 ticks per second
+const TICKS_PER_QUARTER: u16 = 480;
+const TICKS_PER_SECOND: f64 = 960.0;
+const TEMPO_US_PER_QUARTER: u32 = 500_000;
+
+// General MIDI percussion channel, where the noise goes
+const DRUM_CHANNEL: u8 = 9;
+// noise period 15 (the lowest) lands on this key, shorter periods go up from it
+const NOISE_BASE_KEY: u8 = 35;
+
+/// What a channel plays at the moment: MIDI key and volume (0-15)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Voice {
+    pub key: u8,
+    pub volume: u8,
+}
+
+/// Nearest MIDI key of a frequency, A4 = 440 Hz = key 69
+pub fn key_for_frequency(frequency: f64) -> u8 {
+    let key = 69.0 + 12.0 * (frequency / 440.0).log2();
+    key.round().clamp(0.0, 127.0) as u8
+}
+
+/// Drum key of a noise period index (0-15)
+pub fn noise_key(period_index: u8) -> u8 {
+    NOISE_BASE_KEY + (15 - period_index.min(15))
+}
+
+fn velocity(volume: u8) -> u8 {
+    (volume as u32 * 127).div_ceil(15).clamp(1, 127) as u8
+}
+
+struct Track {
+    name: &'static str,
+    channel: u8,
+    // (tick, message)
+    events: Vec<(u64, [u8; 3])>,
+    playing: Option<Voice>,
+}
+
+impl Track {
+    fn note_off(&mut self, tick: u64) {
+        if let Some(voice) = self.playing.take() {
+            self.events.push((tick, [0x80 | self.channel, voice.key, 0]));
+        }
+    }
+
+    fn update(&mut self, tick: u64, voice: Option<Voice>) {
+        let restart = match (self.playing, voice) {
+            (Some(playing), Some(voice)) => playing.key != voice.key || voice.volume > playing.volume,
+            (None, Some(_)) => true,
+            (_, None) => {
+                self.note_off(tick);
+                false
+            }
+        };
+        if restart {
+            self.note_off(tick);
+            let voice = voice.unwrap();
+            self.events.push((tick, [0x90 | self.channel, voice.key, velocity(voice.volume)]));
+        }
+        // a decaying envelope keeps the note, but a later rise has to be measured from here
+        if let (Some(playing), Some(voice)) = (self.playing.as_mut(), voice) {
+            playing.volume = voice.volume;
+        } else {
+            self.playing = voice;
+        }
+    }
+
+    fn chunk(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        // track name
+        data.extend_from_slice(&[0x00, 0xFF, 0x03, self.name.len() as u8]);
+        data.extend_from_slice(self.name.as_bytes());
+        let mut last_tick = 0;
+        for (tick, message) in self.events.iter() {
+            write_var_len(&mut data, (tick - last_tick) as u32);
+            data.extend_from_slice(message);
+            last_tick = *tick;
+        }
+        data.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+        data
+    }
+}
+
+fn write_var_len(data: &mut Vec<u8>, mut value: u32) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    data.extend(bytes.iter().rev());
+}
+
+fn write_chunk(out: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+pub struct MidiTranscriber {
+    path: String,
+    cpu_clock_hz: f64,
+    start_cycle: u64,
+    // pulse 1, pulse 2, triangle, noise
+    tracks: [Track; 4],
+}
+
+impl MidiTranscriber {
+    pub fn new(path: &str, cpu_clock_hz: f64, cycle: u64) -> Self {
+        let track = |name, channel| Track { name, channel, events: Vec::new(), playing: None };
+        MidiTranscriber {
+            path: path.to_string(),
+            cpu_clock_hz,
+            start_cycle: cycle,
+            tracks: [track("pulse1", 0), track("pulse2", 1), track("triangle", 2), track("noise", DRUM_CHANNEL)],
+        }
+    }
+
+    fn tick_at(&self, cycle: u64) -> u64 {
+        let seconds = cycle.saturating_sub(self.start_cycle) as f64 / self.cpu_clock_hz;
+        (seconds * TICKS_PER_SECOND) as u64
+    }
+
+    /// What the pulse, triangle and noise channels play at APU cycle `cycle`
+    pub fn sample(&mut self, cycle: u64, voices: [Option<Voice>; 4]) {
+        let tick = self.tick_at(cycle);
+        for (track, voice) in self.tracks.iter_mut().zip(voices.iter()) {
+            track.update(tick, *voice);
+        }
+    }
+
+    /// The file as it would be written at APU cycle `cycle`, with all notes ended
+    pub fn to_bytes(&mut self, cycle: u64) -> Vec<u8> {
+        let tick = self.tick_at(cycle);
+        for track in self.tracks.iter_mut() {
+            track.note_off(tick);
+        }
+
+        let mut out = Vec::new();
+        let mut header = Vec::new();
+        header.extend_from_slice(&1u16.to_be_bytes()); // format 1: simultaneous tracks
+        header.extend_from_slice(&(self.tracks.len() as u16 + 1).to_be_bytes());
+        header.extend_from_slice(&TICKS_PER_QUARTER.to_be_bytes());
+        write_chunk(&mut out, b"MThd", &header);
+
+        let tempo = TEMPO_US_PER_QUARTER.to_be_bytes();
+        write_chunk(&mut out, b"MTrk", &[0x00, 0xFF, 0x51, 0x03, tempo[1], tempo[2], tempo[3], 0x00, 0xFF, 0x2F, 0x00]);
+        for track in self.tracks.iter() {
+            write_chunk(&mut out, b"MTrk", &track.chunk());
+        }
+        out
+    }
+
+    /// Ends all notes at APU cycle `cycle` and writes the file
+    pub fn finish(mut self, cycle: u64) -> Result<(), String> {
+        let bytes = self.to_bytes(cycle);
+        let file = File::create(&self.path).map_err(|e| format!("Can't create {}: {}", self.path, e))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&bytes).map_err(|e| e.to_string())?;
+        writer.flush().map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(key_for_frequency(440.0), 69);
+        assert_eq!(key_for_frequency(261.63), 60);
+        // pulse timer 253 on NTSC is A4 within a few cents
+        assert_eq!(key_for_frequency(1_789_773.0 / (16.0 * 254.0)), 69);
+        assert_eq!(noise_key(15), NOISE_BASE_KEY);
+        assert_eq!(noise_key(0), NOISE_BASE_KEY + 15);
+    }
+
+    #[test]
+    fn test_note_events() {
+        // one second is 960 ticks
+        let clock = 1_000_000.0;
+        let mut midi = MidiTranscriber::new("unused.mid", clock, 0);
+        let a4 = |volume| Some(Voice { key: 69, volume });
+        midi.sample(0, [a4(15), None, None, None]);
+        // decaying: same note
+        midi.sample(250_000, [a4(10), None, None, None]);
+        // louder again: struck anew
+        midi.sample(500_000, [a4(15), None, None, None]);
+        midi.sample(750_000, [None, None, None, None]);
+        let bytes = midi.to_bytes(1_000_000);
+
+        assert_eq!(&bytes[0..4], b"MThd");
+        assert_eq!(&bytes[8..14], &[0, 1, 0, 5, 0x01, 0xE0]);
+        // header, tempo track, then pulse 1
+        let pulse1 = 14 + 8 + 11 + 8;
+        let expected = [
+            0x00, 0xFF, 0x03, 6, b'p', b'u', b'l', b's', b'e', b'1', //
+            0x00, 0x90, 69, 127, //
+            0x83, 0x60, 0x80, 69, 0, // 480 ticks later
+            0x00, 0x90, 69, 127, //
+            0x81, 0x70, 0x80, 69, 0, // 240 ticks later
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        assert_eq!(&bytes[pulse1..pulse1 + expected.len()], &expected[..]);
+    }
+}
@@ -0,0 +1,235 @@
+// The 2A03 mixes its channels through resistor networks whose output is not
+// linear in the channel levels; the NES then runs the result through two
+// high-pass and one low-pass RC filter on its way to the A/V connector.
+// http://wiki.nesdev.com/w/index.php/APU_Mixer
+
+pub struct Mixer {
+    // indexed by pulse1 + pulse2
+    pulse_table: [f32; 31],
+    // indexed by 3 * triangle + 2 * noise + dmc
+    tnd_table: [f32; 203],
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        let mut pulse_table = [0.0; 31];
+        for (n, out) in pulse_table.iter_mut().enumerate().skip(1) {
+            *out = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+        let mut tnd_table = [0.0; 203];
+        for (n, out) in tnd_table.iter_mut().enumerate().skip(1) {
+            *out = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+        Mixer { pulse_table, tnd_table }
+    }
+
+    /// Mixes raw channel levels (0-15, DMC 0-127) into 0.0..~1.0
+    pub fn mix(&self, pulse1: u8, pulse2: u8, triangle: u8, noise: u8, dmc: u8) -> f32 {
+        let pulse = self.pulse_table[(pulse1 + pulse2) as usize];
+        let tnd = self.tnd_table[3 * triangle as usize + 2 * noise as usize + dmc as usize];
+        pulse + tnd
+    }
+
+    /// Same curve as `mix` for levels that aren't whole numbers, e.g. after a gain
+    pub fn mix_scaled(&self, pulse1: f32, pulse2: f32, triangle: f32, noise: f32, dmc: f32) -> f32 {
+        let pulse = pulse1 + pulse2;
+        let tnd = triangle / 8227.0 + noise / 12241.0 + dmc / 22638.0;
+        let pulse_out = if pulse > 0.0 { 95.88 / (8128.0 / pulse + 100.0) } else { 0.0 };
+        let tnd_out = if tnd > 0.0 { 159.79 / (1.0 / tnd + 100.0) } else { 0.0 };
+        pulse_out + tnd_out
+    }
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Mixer::new()
+    }
+}
+
+/// Mute, solo and gain of each channel, for listening to parts of the music
+pub struct ChannelControls {
+    gains: Vec<f32>,
+    muted: Vec<bool>,
+    solo: Option<usize>,
+}
+
+impl ChannelControls {
+    pub fn new(channels: usize) -> Self {
+        ChannelControls {
+            gains: vec![1.0; channels],
+            muted: vec![false; channels],
+            solo: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.gains.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.gains.is_empty()
+    }
+
+    pub fn gain(&self, channel: usize) -> f32 {
+        self.gains[channel]
+    }
+
+    pub fn set_gain(&mut self, channel: usize, gain: f32) {
+        self.gains[channel] = gain.max(0.0);
+    }
+
+    pub fn is_muted(&self, channel: usize) -> bool {
+        self.muted[channel]
+    }
+
+    pub fn set_muted(&mut self, channel: usize, muted: bool) {
+        self.muted[channel] = muted;
+    }
+
+    pub fn solo(&self) -> Option<usize> {
+        self.solo
+    }
+
+    pub fn set_solo(&mut self, solo: Option<usize>) {
+        self.solo = solo;
+    }
+
+    /// Gain the channel is actually mixed with
+    pub fn effective_gain(&self, channel: usize) -> f32 {
+        let silenced = match self.solo {
+            Some(solo) => solo != channel,
+            None => self.muted[channel],
+        };
+        if silenced {
+            0.0
+        } else {
+            self.gains[channel]
+        }
+    }
+
+    /// Whether every channel plays as is, so mixing can skip the gains
+    pub fn is_neutral(&self) -> bool {
+        self.solo.is_none() && !self.muted.iter().any(|m| *m) && self.gains.iter().all(|g| *g == 1.0)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum FilterKind {
+    HighPass,
+    LowPass,
+}
+
+// first order RC filter
+#[derive(Clone, Copy)]
+struct Filter {
+    kind: FilterKind,
+    alpha: f32,
+    prev_input: f32,
+    prev_output: f32,
+}
+
+impl Filter {
+    fn new(kind: FilterKind, cutoff_hz: f32, sample_rate: u32) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff_hz);
+        let dt = 1.0 / sample_rate as f32;
+        let alpha = match kind {
+            FilterKind::HighPass => rc / (rc + dt),
+            FilterKind::LowPass => dt / (rc + dt),
+        };
+        Filter {
+            kind,
+            alpha,
+            prev_input: 0.0,
+            prev_output: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = match self.kind {
+            FilterKind::HighPass => self.alpha * (self.prev_output + input - self.prev_input),
+            FilterKind::LowPass => self.prev_output + self.alpha * (input - self.prev_output),
+        };
+        self.prev_input = input;
+        self.prev_output = output;
+        output
+    }
+}
+
+/// The filters between the mixer and the audio output of a front-loading NES
+pub struct FilterChain {
+    filters: [Filter; 3],
+}
+
+impl FilterChain {
+    pub fn new(sample_rate: u32) -> Self {
+        FilterChain {
+            filters: [
+                Filter::new(FilterKind::HighPass, 90.0, sample_rate),
+                Filter::new(FilterKind::HighPass, 440.0, sample_rate),
+                Filter::new(FilterKind::LowPass, 14_000.0, sample_rate),
+            ],
+        }
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples.iter_mut() {
+            for filter in self.filters.iter_mut() {
+                *sample = filter.process(*sample);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mixer_is_nonlinear() {
+        let mixer = Mixer::new();
+        assert_eq!(mixer.mix(0, 0, 0, 0, 0), 0.0);
+        assert!((mixer.mix(15, 15, 0, 0, 0) - 0.2575).abs() < 0.0005);
+        assert!((mixer.mix(0, 0, 15, 15, 127) - 0.7425).abs() < 0.0005);
+        // two pulses at full volume are less than twice as loud as one
+        assert!(mixer.mix(15, 15, 0, 0, 0) < 2.0 * mixer.mix(15, 0, 0, 0, 0));
+    }
+
+    #[test]
+    fn test_scaled_mix_matches_tables() {
+        let mixer = Mixer::new();
+        for (p1, p2, t, n, d) in [(15, 15, 0, 0, 0), (3, 0, 15, 7, 64), (0, 0, 15, 15, 127), (8, 9, 1, 2, 3)] {
+            let table = mixer.mix(p1, p2, t, n, d);
+            let scaled = mixer.mix_scaled(p1 as f32, p2 as f32, t as f32, n as f32, d as f32);
+            assert!((table - scaled).abs() < 0.002, "{} vs {}", table, scaled);
+        }
+    }
+
+    #[test]
+    fn test_channel_controls() {
+        let mut controls = ChannelControls::new(5);
+        assert!(controls.is_neutral());
+        controls.set_gain(1, 0.5);
+        controls.set_muted(2, true);
+        assert_eq!(controls.effective_gain(1), 0.5);
+        assert_eq!(controls.effective_gain(2), 0.0);
+
+        // solo overrides mutes, including the soloed channel's own
+        controls.set_solo(Some(2));
+        assert_eq!(controls.effective_gain(2), 1.0);
+        assert_eq!(controls.effective_gain(0), 0.0);
+        assert!(!controls.is_neutral());
+    }
+
+    #[test]
+    fn test_filter_chain_removes_dc_and_treble() {
+        let rate = 44100;
+        let mut dc = vec![0.5; rate as usize / 10];
+        FilterChain::new(rate).process(&mut dc);
+        assert!(dc.last().unwrap().abs() < 0.001);
+
+        // alternating samples: the Nyquist frequency, far above 14 kHz
+        let mut treble: Vec<f32> = (0..1000).map(|n| if n % 2 == 0 { 0.5 } else { -0.5 }).collect();
+        FilterChain::new(rate).process(&mut treble);
+        assert!(treble[900..].iter().all(|s| s.abs() < 0.3));
+    }
+}
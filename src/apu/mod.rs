@@ -0,0 +1,1225 @@
+pub mod blip;
+pub mod envelope;
+pub mod expansion;
+pub mod frame_counter;
+pub mod midi;
+pub mod mixer;
+pub mod output;
+pub mod recorder;
+pub mod vgm;
+pub mod wav;
+
+use crate::region::Region;
+use blip::BlipBuffer;
+use envelope::Envelope;
+use expansion::SharedExpansionAudio;
+use frame_counter::FrameCounter;
+use midi::{MidiTranscriber, Voice};
+use mixer::{ChannelControls, FilterChain, Mixer};
+use output::AudioOutput;
+use recorder::Recorder;
+use std::fs::File;
+use std::io::BufWriter;
+use vgm::VgmWriter;
+
+// APU Register addresses
+const APU_PULSE1_DUTY: u16 = 0x4000;
+const APU_PULSE1_SWEEP: u16 = 0x4001;
+const APU_PULSE1_TIMER_LOW: u16 = 0x4002;
+const APU_PULSE1_TIMER_HIGH: u16 = 0x4003;
+const APU_PULSE2_DUTY: u16 = 0x4004;
+const APU_PULSE2_SWEEP: u16 = 0x4005;
+const APU_PULSE2_TIMER_LOW: u16 = 0x4006;
+const APU_PULSE2_TIMER_HIGH: u16 = 0x4007;
+const APU_TRIANGLE_LINEAR: u16 = 0x4008;
+const APU_TRIANGLE_TIMER_LOW: u16 = 0x400A;
+const APU_TRIANGLE_TIMER_HIGH: u16 = 0x400B;
+const APU_NOISE_VOLUME: u16 = 0x400C;
+const APU_NOISE_PERIOD: u16 = 0x400E;
+const APU_NOISE_LENGTH: u16 = 0x400F;
+const APU_DMC_FREQ: u16 = 0x4010;
+const APU_DMC_RAW: u16 = 0x4011;
+const APU_DMC_START: u16 = 0x4012;
+const APU_DMC_LENGTH: u16 = 0x4013;
+const APU_STATUS: u16 = 0x4015;
+const APU_FRAME_COUNTER: u16 = 0x4017;
+
+// Sample rate and buffer size
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+const BUFFER_SIZE: usize = 1024;
+// samples handed to the audio device at a time
+const OUTPUT_CHUNK: usize = 256;
+// Names of the channels, in the order the mixing controls and stems use
+pub const CHANNEL_NAMES: [&str; 5] = ["pulse1", "pulse2", "triangle", "noise", "dmc"];
+
+// CPU cycles between two reads from the resampler
+const BLIP_FRAME: u32 = 1024;
+
+// Duty cycles for pulse waves
+const DUTY_CYCLES: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0], // 12.5%
+    [0, 1, 1, 0, 0, 0, 0, 0], // 25%
+    [0, 1, 1, 1, 1, 0, 0, 0], // 50%
+    [1, 0, 0, 1, 1, 1, 1, 1], // 75%
+];
+
+// Length counter values, indexed by bits 3-7 of the length registers
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// Triangle wave lookup table
+const TRIANGLE_WAVE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8,
+    7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7,
+    8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+pub struct PulseChannel {
+    enabled: bool,
+    duty_cycle: u8,
+    duty_step: u8,
+    timer: u16,
+    timer_value: u16,
+    // the timer is clocked every other CPU cycle
+    apu_cycle: bool,
+    length_counter: u8,
+    envelope: Envelope,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_shift: u8,
+    sweep_negate: bool,
+    sweep_reload: bool,
+    sweep_counter: u8,
+    // pulse 1 negates the sweep change with one's complement, pulse 2 with two's
+    ones_complement_negate: bool,
+    // cartridge pulses like the MMC5's have no sweep unit to mute them
+    sweep_unit: bool,
+}
+
+impl PulseChannel {
+    pub fn new(ones_complement_negate: bool) -> Self {
+        PulseChannel {
+            enabled: false,
+            duty_cycle: 0,
+            duty_step: 0,
+            timer: 0,
+            timer_value: 0,
+            apu_cycle: false,
+            length_counter: 0,
+            envelope: Envelope::new(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_shift: 0,
+            sweep_negate: false,
+            sweep_reload: false,
+            sweep_counter: 0,
+            ones_complement_negate,
+            sweep_unit: true,
+        }
+    }
+
+    /// A pulse without a sweep unit, which plays any period
+    pub fn without_sweep() -> Self {
+        PulseChannel { sweep_unit: false, ..PulseChannel::new(false) }
+    }
+
+    pub fn write_duty(&mut self, value: u8) {
+        self.duty_cycle = (value >> 6) & 0x03;
+        self.envelope.write(value);
+    }
+
+    fn write_sweep(&mut self, value: u8) {
+        self.sweep_enabled = (value & 0x80) != 0;
+        self.sweep_period = (value >> 4) & 0x07;
+        self.sweep_negate = (value & 0x08) != 0;
+        self.sweep_shift = value & 0x07;
+        self.sweep_reload = true;
+    }
+
+    pub fn write_timer_low(&mut self, value: u8) {
+        self.timer = (self.timer & 0xFF00) | value as u16;
+    }
+
+    pub fn write_timer_high(&mut self, value: u8) {
+        self.timer = (self.timer & 0x00FF) | ((value & 0x07) as u16) << 8;
+        if self.enabled {
+            self.length_counter = LENGTH_TABLE[(value >> 3) as usize];
+        }
+        self.envelope.restart();
+        self.duty_step = 0;
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer >> self.sweep_shift;
+        if !self.sweep_negate {
+            self.timer + change
+        } else if self.ones_complement_negate {
+            self.timer.saturating_sub(change + 1)
+        } else {
+            self.timer.saturating_sub(change)
+        }
+    }
+
+    /// Enabled by the status register, disabling clears the length counter
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.length_counter > 0
+    }
+
+    // the sweep unit silences the channel even when it is disabled
+    fn sweep_muted(&self) -> bool {
+        self.sweep_unit && (self.timer < 8 || self.sweep_target() > 0x7FF)
+    }
+
+    pub fn tick(&mut self) -> u8 {
+        self.apu_cycle = !self.apu_cycle;
+        if !self.enabled || self.length_counter == 0 {
+            return 0;
+        }
+
+        // counts down to 0, then reloads: a period of timer + 1 APU cycles
+        if self.apu_cycle {
+            if self.timer_value > 0 {
+                self.timer_value -= 1;
+            } else {
+                self.timer_value = self.timer;
+                self.duty_step = (self.duty_step + 1) % 8;
+            }
+        }
+
+        if self.sweep_muted() {
+            return 0;
+        }
+        let duty_value = DUTY_CYCLES[self.duty_cycle as usize][self.duty_step as usize];
+        duty_value * self.envelope.output()
+    }
+
+    pub fn quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    /// Note the channel is playing, for the MIDI transcription
+    fn voice(&self, cpu_clock_hz: f64) -> Option<Voice> {
+        let volume = self.envelope.output();
+        if !self.enabled || self.length_counter == 0 || self.sweep_muted() || volume == 0 {
+            return None;
+        }
+        let frequency = cpu_clock_hz / (16.0 * (self.timer as f64 + 1.0));
+        Some(Voice { key: midi::key_for_frequency(frequency), volume })
+    }
+
+    pub fn half_frame(&mut self) {
+        // the loop flag of the envelope doubles as the length counter halt
+        if self.length_counter > 0 && !self.envelope.loop_flag {
+            self.length_counter -= 1;
+        }
+
+        if self.sweep_counter == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.sweep_muted() {
+            self.timer = self.sweep_target();
+        }
+        if self.sweep_counter == 0 || self.sweep_reload {
+            self.sweep_counter = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_counter -= 1;
+        }
+    }
+}
+
+pub struct TriangleChannel {
+    enabled: bool,
+    timer: u16,
+    timer_value: u16,
+    length_counter: u8,
+    linear_counter: u8,
+    linear_counter_reload: u8,
+    linear_counter_reload_flag: bool,
+    // halts the length counter and keeps reloading the linear counter
+    control_flag: bool,
+    triangle_step: u8,
+}
+
+impl TriangleChannel {
+    fn new() -> Self {
+        TriangleChannel {
+            enabled: false,
+            timer: 0,
+            timer_value: 0,
+            length_counter: 0,
+            linear_counter: 0,
+            linear_counter_reload: 0,
+            linear_counter_reload_flag: false,
+            control_flag: false,
+            triangle_step: 0,
+        }
+    }
+
+    fn write_linear(&mut self, value: u8) {
+        self.linear_counter_reload = value & 0x7F;
+        self.control_flag = (value & 0x80) != 0;
+    }
+
+    fn write_timer_low(&mut self, value: u8) {
+        self.timer = (self.timer & 0xFF00) | value as u16;
+    }
+
+    fn write_timer_high(&mut self, value: u8) {
+        self.timer = (self.timer & 0x00FF) | ((value & 0x07) as u16) << 8;
+        if self.enabled {
+            self.length_counter = LENGTH_TABLE[(value >> 3) as usize];
+        }
+        self.linear_counter_reload_flag = true;
+    }
+
+    fn tick(&mut self) -> u8 {
+        // a silenced triangle stops stepping but keeps outputting its current level
+        if self.enabled && self.length_counter > 0 && self.linear_counter > 0 {
+            // counts down to 0, then reloads: a period of timer + 1
+            if self.timer_value > 0 {
+                self.timer_value -= 1;
+            } else {
+                self.timer_value = self.timer;
+                self.triangle_step = (self.triangle_step + 1) % 32;
+            }
+        }
+
+        TRIANGLE_WAVE[self.triangle_step as usize]
+    }
+
+    fn quarter_frame(&mut self) {
+        if self.linear_counter_reload_flag {
+            self.linear_counter = self.linear_counter_reload;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control_flag {
+            self.linear_counter_reload_flag = false;
+        }
+    }
+
+    fn half_frame(&mut self) {
+        if self.length_counter > 0 && !self.control_flag {
+            self.length_counter -= 1;
+        }
+    }
+
+    /// Note the channel is playing, for the MIDI transcription. The triangle has no
+    /// volume control, and periods below 2 are ultrasonic.
+    fn voice(&self, cpu_clock_hz: f64) -> Option<Voice> {
+        if !self.enabled || self.length_counter == 0 || self.linear_counter == 0 || self.timer < 2 {
+            return None;
+        }
+        let frequency = cpu_clock_hz / (32.0 * (self.timer as f64 + 1.0));
+        Some(Voice { key: midi::key_for_frequency(frequency), volume: 15 })
+    }
+}
+
+pub struct NoiseChannel {
+    enabled: bool,
+    timer: u16,
+    timer_value: u16,
+    length_counter: u8,
+    envelope: Envelope,
+    shift_register: u16,
+    mode: bool,
+    periods: &'static [u16; 16],
+}
+
+impl NoiseChannel {
+    fn new() -> Self {
+        NoiseChannel {
+            enabled: false,
+            timer: 0,
+            timer_value: 0,
+            length_counter: 0,
+            envelope: Envelope::new(),
+            shift_register: 1,
+            mode: false,
+            periods: Region::Ntsc.noise_periods(),
+        }
+    }
+
+    fn write_volume(&mut self, value: u8) {
+        self.envelope.write(value);
+    }
+
+    fn write_period(&mut self, value: u8) {
+        self.mode = (value & 0x80) != 0;
+        let period_index = value & 0x0F;
+        self.timer = self.periods[period_index as usize];
+    }
+
+    fn write_length(&mut self, value: u8) {
+        if self.enabled {
+            self.length_counter = LENGTH_TABLE[(value >> 3) as usize];
+        }
+        self.envelope.restart();
+    }
+
+    fn tick(&mut self) -> u8 {
+        if !self.enabled || self.length_counter == 0 {
+            return 0;
+        }
+
+        // the period table is in CPU cycles already
+        if self.timer_value > 0 {
+            self.timer_value -= 1;
+        } else {
+            self.timer_value = self.timer.saturating_sub(1);
+            
+            let feedback = if self.mode {
+                (self.shift_register & 0x40) != 0
+            } else {
+                (self.shift_register & 0x02) != 0
+            } ^ ((self.shift_register & 0x01) != 0);
+            
+            self.shift_register >>= 1;
+            if feedback {
+                self.shift_register |= 0x4000;
+            }
+        }
+
+        // the channel is silent while bit 0 of the shift register is set
+        if self.shift_register & 0x01 != 0 {
+            return 0;
+        }
+        self.envelope.output()
+    }
+
+    fn quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    fn half_frame(&mut self) {
+        if self.length_counter > 0 && !self.envelope.loop_flag {
+            self.length_counter -= 1;
+        }
+    }
+
+    /// Drum the channel is playing, for the MIDI transcription: one key per period
+    fn voice(&self) -> Option<Voice> {
+        let volume = self.envelope.output();
+        if !self.enabled || self.length_counter == 0 || volume == 0 {
+            return None;
+        }
+        let period_index = self.periods.iter().position(|period| *period == self.timer)?;
+        Some(Voice { key: midi::noise_key(period_index as u8), volume })
+    }
+}
+
+// Plays 1-bit delta encoded samples that it fetches from CPU memory by DMA.
+// http://wiki.nesdev.com/w/index.php/APU_DMC
+pub struct DMCChannel {
+    timer: u16,
+    timer_value: u16,
+    sample_buffer: u8,
+    sample_buffer_empty: bool,
+    shift_register: u8,
+    bits_remaining: u8,
+    // the output unit doesn't move the level during a cycle started without a sample byte
+    silence: bool,
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    loop_flag: bool,
+    irq_enabled: bool,
+    irq_flag: bool,
+    output_level: u8,
+    rates: &'static [u16; 16],
+}
+
+impl DMCChannel {
+    fn new() -> Self {
+        DMCChannel {
+            timer: Region::Ntsc.dmc_rates()[0],
+            timer_value: 0,
+            sample_buffer: 0,
+            sample_buffer_empty: true,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            sample_address: 0,
+            sample_length: 0,
+            current_address: 0,
+            bytes_remaining: 0,
+            loop_flag: false,
+            irq_enabled: false,
+            irq_flag: false,
+            output_level: 0,
+            rates: Region::Ntsc.dmc_rates(),
+        }
+    }
+
+    fn write_freq(&mut self, value: u8) {
+        self.loop_flag = (value & 0x40) != 0;
+        self.irq_enabled = (value & 0x80) != 0;
+        if !self.irq_enabled {
+            self.irq_flag = false;
+        }
+        let rate_index = value & 0x0F;
+        self.timer = self.rates[rate_index as usize];
+    }
+
+    fn write_raw(&mut self, value: u8) {
+        self.output_level = value & 0x7F;
+    }
+
+    fn write_start(&mut self, value: u8) {
+        self.sample_address = 0xC000 | ((value as u16) << 6);
+    }
+
+    fn write_length(&mut self, value: u8) {
+        self.sample_length = ((value as u16) << 4) | 1;
+    }
+
+    // $4015 bit 4: stops the sample, or starts it over if it has already finished
+    fn set_enabled(&mut self, enabled: bool) {
+        self.irq_flag = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    /// Address of the next sample byte once the sample buffer needs refilling
+    fn dma_request(&self) -> Option<u16> {
+        if self.sample_buffer_empty && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    fn dma_complete(&mut self, data: u8) {
+        self.sample_buffer = data;
+        self.sample_buffer_empty = false;
+        // the address wraps around to $8000, not $0000
+        self.current_address = if self.current_address == 0xFFFF {
+            0x8000
+        } else {
+            self.current_address + 1
+        };
+
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    fn tick(&mut self) -> u8 {
+        // the output unit keeps running after the sample ends, so the level
+        // holds (and $4011 writes are heard) while no sample is playing
+        if self.timer_value > 0 {
+            self.timer_value -= 1;
+        } else {
+            self.timer_value = self.timer - 1;
+
+            if !self.silence {
+                let bit = (self.shift_register & 0x01) != 0;
+                if bit && self.output_level < 126 {
+                    self.output_level += 2;
+                } else if !bit && self.output_level > 1 {
+                    self.output_level -= 2;
+                }
+            }
+            self.shift_register >>= 1;
+            self.bits_remaining -= 1;
+
+            if self.bits_remaining == 0 {
+                self.bits_remaining = 8;
+                if self.sample_buffer_empty {
+                    self.silence = true;
+                } else {
+                    self.silence = false;
+                    self.shift_register = self.sample_buffer;
+                    self.sample_buffer_empty = true;
+                }
+            }
+        }
+
+        self.output_level
+    }
+}
+
+pub struct APU {
+    pulse1: PulseChannel,
+    pulse2: PulseChannel,
+    triangle: TriangleChannel,
+    noise: NoiseChannel,
+    dmc: DMCChannel,
+    frame_counter: FrameCounter,
+    audio_buffer: Vec<f32>,
+
+    output: Option<AudioOutput>,
+    sample_rate: u32,
+    region: Region,
+    cpu_clock_hz: f64,
+    // CPU cycles run since power on
+    cycles: u64,
+    mixer: Mixer,
+    controls: ChannelControls,
+    filters: FilterChain,
+    // skip the filter chain, e.g. for analysis tools
+    raw_output: bool,
+    blip: BlipBuffer,
+    recorder: Option<Recorder>,
+    vgm: Option<VgmWriter<BufWriter<File>>>,
+    midi: Option<MidiTranscriber>,
+    // sound chip on the cartridge, its channels follow the APU's in the controls
+    expansion: Option<SharedExpansionAudio>,
+    // level of every channel in the mix during the current cycle
+    channel_levels: Vec<f32>,
+    // CPU cycles into the current resampler frame and the mixed level at the last change
+    blip_clock: u32,
+    last_level: f32,
+}
+
+impl APU {
+    pub fn new() -> Self {
+        APU {
+            pulse1: PulseChannel::new(true),
+            pulse2: PulseChannel::new(false),
+            triangle: TriangleChannel::new(),
+            noise: NoiseChannel::new(),
+            dmc: DMCChannel::new(),
+            frame_counter: FrameCounter::new(),
+            audio_buffer: Vec::new(),
+            output: None,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            region: Region::Ntsc,
+            cpu_clock_hz: Region::Ntsc.cpu_clock_hz(),
+            cycles: 0,
+            mixer: Mixer::new(),
+            controls: ChannelControls::new(CHANNEL_NAMES.len()),
+            filters: FilterChain::new(DEFAULT_SAMPLE_RATE),
+            raw_output: false,
+            blip: BlipBuffer::new(Region::Ntsc.cpu_clock_hz(), DEFAULT_SAMPLE_RATE as f64),
+            recorder: None,
+            vgm: None,
+            midi: None,
+            expansion: None,
+            channel_levels: vec![0.0; CHANNEL_NAMES.len()],
+            blip_clock: 0,
+            last_level: 0.0,
+        }
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.noise.periods = region.noise_periods();
+        self.dmc.rates = region.dmc_rates();
+        self.frame_counter.set_region(region);
+        self.region = region;
+        self.cpu_clock_hz = region.cpu_clock_hz();
+        self.blip.set_rates(self.cpu_clock_hz, self.sample_rate as f64);
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.blip.set_rates(self.cpu_clock_hz, sample_rate as f64);
+        self.filters = FilterChain::new(sample_rate);
+    }
+
+    /// Outputs the mixer level as is, without the high-pass and low-pass filters of the console
+    pub fn set_raw_output(&mut self, raw: bool) {
+        self.raw_output = raw;
+    }
+
+    /// Mixes a cartridge sound chip into the output
+    pub fn set_expansion_audio(&mut self, expansion: SharedExpansionAudio) {
+        let channels = CHANNEL_NAMES.len() + expansion.borrow().channel_names().len();
+        self.controls = ChannelControls::new(channels);
+        self.channel_levels = vec![0.0; channels];
+        self.expansion = Some(expansion);
+    }
+
+    pub fn init_audio(&mut self, sdl_context: &sdl2::Sdl, sample_rate: u32) -> Result<(), String> {
+        let output = AudioOutput::open(sdl_context, sample_rate)?;
+        self.set_sample_rate(output.sample_rate());
+        self.output = Some(output);
+        Ok(())
+    }
+
+    pub fn write_register(&mut self, addr: u16, value: u8) {
+        if let Some(vgm) = self.vgm.as_mut() {
+            if let Err(e) = vgm.write_register(self.cycles, addr, value) {
+                eprintln!("VGM log stopped: {}", e);
+                self.vgm = None;
+            }
+        }
+
+        match addr {
+            APU_PULSE1_DUTY => self.pulse1.write_duty(value),
+            APU_PULSE1_SWEEP => self.pulse1.write_sweep(value),
+            APU_PULSE1_TIMER_LOW => self.pulse1.write_timer_low(value),
+            APU_PULSE1_TIMER_HIGH => self.pulse1.write_timer_high(value),
+            
+            APU_PULSE2_DUTY => self.pulse2.write_duty(value),
+            APU_PULSE2_SWEEP => self.pulse2.write_sweep(value),
+            APU_PULSE2_TIMER_LOW => self.pulse2.write_timer_low(value),
+            APU_PULSE2_TIMER_HIGH => self.pulse2.write_timer_high(value),
+            
+            APU_TRIANGLE_LINEAR => self.triangle.write_linear(value),
+            APU_TRIANGLE_TIMER_LOW => self.triangle.write_timer_low(value),
+            APU_TRIANGLE_TIMER_HIGH => self.triangle.write_timer_high(value),
+            
+            APU_NOISE_VOLUME => self.noise.write_volume(value),
+            APU_NOISE_PERIOD => self.noise.write_period(value),
+            APU_NOISE_LENGTH => self.noise.write_length(value),
+            
+            APU_DMC_FREQ => self.dmc.write_freq(value),
+            APU_DMC_RAW => self.dmc.write_raw(value),
+            APU_DMC_START => self.dmc.write_start(value),
+            APU_DMC_LENGTH => self.dmc.write_length(value),
+            
+            APU_STATUS => {
+                self.pulse1.set_enabled((value & 0x01) != 0);
+                self.pulse2.set_enabled((value & 0x02) != 0);
+                self.triangle.enabled = (value & 0x04) != 0;
+                self.noise.enabled = (value & 0x08) != 0;
+                self.dmc.set_enabled((value & 0x10) != 0);
+
+                // disabling a channel clears its length counter
+                if !self.triangle.enabled { self.triangle.length_counter = 0; }
+                if !self.noise.enabled { self.noise.length_counter = 0; }
+            }
+            
+            APU_FRAME_COUNTER => self.frame_counter.write(value),
+            
+            _ => {}
+        }
+    }
+
+    pub fn read_register(&mut self, addr: u16) -> u8 {
+        match addr {
+            APU_STATUS => {
+                let mut status = 0;
+                if self.pulse1.is_playing() { status |= 0x01; }
+                if self.pulse2.is_playing() { status |= 0x02; }
+                if self.triangle.length_counter > 0 { status |= 0x04; }
+                if self.noise.length_counter > 0 { status |= 0x08; }
+                if self.dmc.bytes_remaining > 0 { status |= 0x10; }
+                if self.frame_counter.irq_flag { status |= 0x40; }
+                if self.dmc.irq_flag { status |= 0x80; }
+                // reading acknowledges the frame interrupt
+                self.frame_counter.irq_flag = false;
+                status
+            }
+            _ => 0
+        }
+    }
+
+    pub fn tick(&mut self) {
+        self.cycles += 1;
+
+        // Tick all channels
+        let pulse1_out = self.pulse1.tick();
+        let pulse2_out = self.pulse2.tick();
+        let triangle_out = self.triangle.tick();
+        let noise_out = self.noise.tick();
+        let dmc_out = self.dmc.tick();
+
+        let mut mixed = if self.controls.is_neutral() {
+            self.mixer.mix(pulse1_out, pulse2_out, triangle_out, noise_out, dmc_out)
+        } else {
+            let gain = |channel: usize, level: u8| level as f32 * self.controls.effective_gain(channel);
+            self.mixer.mix_scaled(
+                gain(0, pulse1_out),
+                gain(1, pulse2_out),
+                gain(2, triangle_out),
+                gain(3, noise_out),
+                gain(4, dmc_out),
+            )
+        };
+
+        // the cartridge's audio adds to the APU's linearly
+        if let Some(expansion) = self.expansion.as_ref() {
+            let mut chip = expansion.borrow_mut();
+            chip.tick();
+            chip.output(&mut self.channel_levels[CHANNEL_NAMES.len()..]);
+            for (channel, level) in self.channel_levels.iter().enumerate().skip(CHANNEL_NAMES.len()) {
+                mixed += level * self.controls.effective_gain(channel);
+            }
+        }
+
+        // only level changes go to the resampler, which band-limits them
+        if mixed != self.last_level {
+            self.blip.add_delta(self.blip_clock, mixed - self.last_level);
+            self.last_level = mixed;
+        }
+        if let Some(recorder) = self.recorder.as_mut().filter(|r| r.has_stems()) {
+            let mixer = &self.mixer;
+            self.channel_levels[..CHANNEL_NAMES.len()].copy_from_slice(&[
+                mixer.mix(pulse1_out, 0, 0, 0, 0),
+                mixer.mix(0, pulse2_out, 0, 0, 0),
+                mixer.mix(0, 0, triangle_out, 0, 0),
+                mixer.mix(0, 0, 0, noise_out, 0),
+                mixer.mix(0, 0, 0, 0, dmc_out),
+            ]);
+            recorder.add_stem_levels(self.blip_clock, &self.channel_levels);
+        }
+        self.blip_clock += 1;
+        if self.blip_clock == BLIP_FRAME {
+            self.end_blip_frame();
+        }
+
+        let (quarter_frame, half_frame) = self.frame_counter.tick();
+        if quarter_frame {
+            self.pulse1.quarter_frame();
+            self.pulse2.quarter_frame();
+            self.triangle.quarter_frame();
+            self.noise.quarter_frame();
+
+            if let Some(midi) = self.midi.as_mut() {
+                midi.sample(self.cycles, [
+                    self.pulse1.voice(self.cpu_clock_hz),
+                    self.pulse2.voice(self.cpu_clock_hz),
+                    self.triangle.voice(self.cpu_clock_hz),
+                    self.noise.voice(),
+                ]);
+            }
+        }
+        if half_frame {
+            self.pulse1.half_frame();
+            self.pulse2.half_frame();
+            self.triangle.half_frame();
+            self.noise.half_frame();
+        }
+    }
+
+    /// Level of the APU's IRQ output to the CPU
+    pub fn irq(&self) -> bool {
+        self.frame_counter.irq_flag || self.dmc.irq_flag
+    }
+
+    /// CPU address the DMC wants to read a sample byte from. The bus performs the
+    /// read, stalls the CPU and hands the byte back through `dmc_dma_complete`.
+    pub fn dmc_dma_request(&self) -> Option<u16> {
+        self.dmc.dma_request()
+    }
+
+    pub fn dmc_dma_complete(&mut self, data: u8) {
+        self.dmc.dma_complete(data);
+    }
+
+    fn end_blip_frame(&mut self) {
+        let clocks = self.blip_clock;
+        self.blip.end_frame(clocks);
+        self.blip_clock = 0;
+        let start = self.audio_buffer.len();
+        self.blip.read_samples(&mut self.audio_buffer);
+        if !self.raw_output {
+            self.filters.process(&mut self.audio_buffer[start..]);
+        }
+
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(e) = recorder.end_frame(clocks, &self.audio_buffer[start..], self.raw_output) {
+                eprintln!("Audio recording stopped: {}", e);
+                self.recorder = None;
+            }
+        }
+
+        match self.output.as_mut() {
+            Some(output) => {
+                // pushing blocks when the device is full, so nothing is ever dropped
+                if self.audio_buffer.len() >= OUTPUT_CHUNK {
+                    output.push(&self.audio_buffer);
+                    self.audio_buffer.clear();
+                }
+            }
+            None => {
+                // nobody is listening and nobody collected the samples: don't grow forever
+                let len = self.audio_buffer.len();
+                if len > self.sample_rate as usize {
+                    self.audio_buffer.drain(0..BUFFER_SIZE.min(len));
+                }
+            }
+        }
+    }
+
+    /// The APU's channels, then those of the cartridge's sound chip
+    pub fn channel_names(&self) -> Vec<&'static str> {
+        let mut names = CHANNEL_NAMES.to_vec();
+        if let Some(expansion) = self.expansion.as_ref() {
+            names.extend_from_slice(expansion.borrow().channel_names());
+        }
+        names
+    }
+
+    pub fn channel_controls(&self) -> &ChannelControls {
+        &self.controls
+    }
+
+    /// Mute, solo and gain of the channels, indexed like `channel_names`
+    pub fn channel_controls_mut(&mut self) -> &mut ChannelControls {
+        &mut self.controls
+    }
+
+    /// Starts writing the output to a WAV file, and every channel to its own file with `stems`
+    pub fn start_recording(&mut self, path: &str, stems: bool) -> Result<(), String> {
+        self.stop_recording()?;
+        let stem_names = if stems { self.channel_names() } else { Vec::new() };
+        self.recorder = Some(Recorder::start(path, self.sample_rate, self.cpu_clock_hz, &stem_names)?);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> Result<(), String> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// Starts logging the register writes to a VGM file
+    pub fn start_vgm_log(&mut self, path: &str) -> Result<(), String> {
+        self.stop_vgm_log()?;
+        self.vgm = Some(VgmWriter::create(path, self.region, self.cycles)?);
+        Ok(())
+    }
+
+    pub fn stop_vgm_log(&mut self) -> Result<(), String> {
+        match self.vgm.take() {
+            Some(vgm) => vgm.finish(self.cycles).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_logging_vgm(&self) -> bool {
+        self.vgm.is_some()
+    }
+
+    /// Starts transcribing the pulse, triangle and noise channels to a MIDI file,
+    /// which is written when the transcription stops
+    pub fn start_midi_export(&mut self, path: &str) -> Result<(), String> {
+        self.stop_midi_export()?;
+        self.midi = Some(MidiTranscriber::new(path, self.cpu_clock_hz, self.cycles));
+        Ok(())
+    }
+
+    pub fn stop_midi_export(&mut self) -> Result<(), String> {
+        match self.midi.take() {
+            Some(midi) => midi.finish(self.cycles),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_exporting_midi(&self) -> bool {
+        self.midi.is_some()
+    }
+
+    /// Start address and length in bytes of the DMC sample, as set by $4012 and $4013
+    pub fn dmc_sample(&self) -> (u16, u16) {
+        (self.dmc.sample_address, self.dmc.sample_length)
+    }
+
+    /// Hands the VGM log sample data from CPU memory, which the bus reads for it
+    pub fn log_vgm_sample_data(&mut self, addr: u16, data: &[u8]) {
+        if let Some(vgm) = self.vgm.as_mut() {
+            if let Err(e) = vgm.write_memory(self.cycles, addr, data) {
+                eprintln!("VGM log stopped: {}", e);
+                self.vgm = None;
+            }
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// (underruns, overruns) of the audio device so far
+    pub fn output_stats(&self) -> Option<(u64, u64)> {
+        self.output.as_ref().map(|output| (output.underruns, output.overruns))
+    }
+
+    pub fn get_audio_buffer(&mut self) -> Vec<f32> {
+        let buffer = self.audio_buffer.clone();
+        self.audio_buffer.clear();
+        buffer
+    }
+} 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_output_sample_rate() {
+        let mut apu = APU::new();
+        let mut samples = 0;
+        // one second of CPU time
+        for cycle in 0..Region::Ntsc.cpu_clock_hz() as u32 {
+            apu.tick();
+            if cycle % 1000 == 0 {
+                samples += apu.get_audio_buffer().len();
+            }
+        }
+        samples += apu.get_audio_buffer().len();
+        // the resampler hands samples out a frame at a time, a partial frame is still pending
+        let pending = (BLIP_FRAME as f64 * DEFAULT_SAMPLE_RATE as f64 / Region::Ntsc.cpu_clock_hz()).ceil() as usize;
+        let expected = DEFAULT_SAMPLE_RATE as usize;
+        assert!(samples >= expected - pending && samples <= expected + 1, "got {}", samples);
+    }
+
+    #[test]
+    fn test_configurable_sample_rate() {
+        let mut apu = APU::new();
+        apu.set_sample_rate(48000);
+        let mut samples = Vec::new();
+        let frames = 200;
+        for _ in 0..BLIP_FRAME * frames {
+            apu.tick();
+            samples.extend(apu.get_audio_buffer());
+        }
+        let expected = (BLIP_FRAME as f64 * frames as f64 * 48000.0 / Region::Ntsc.cpu_clock_hz()) as usize;
+        assert_eq!(samples.len(), expected);
+    }
+
+    #[test]
+    fn test_uncollected_samples_are_dropped() {
+        // fewer samples a second than get dropped at once
+        let mut apu = APU::new();
+        apu.set_sample_rate(800);
+        // a little over a second
+        for _ in 0..BLIP_FRAME * 2000 {
+            apu.tick();
+        }
+        assert!(apu.get_audio_buffer().len() <= BUFFER_SIZE);
+    }
+
+    #[test]
+    fn test_length_counter_table_and_halt() {
+        let mut apu = APU::new();
+        apu.write_register(APU_STATUS, 0x01);
+        apu.write_register(APU_PULSE1_DUTY, 0x3F);
+        apu.write_register(APU_PULSE1_TIMER_HIGH, 0x08); // index 1: 254
+        assert_eq!(apu.pulse1.length_counter, 254);
+        apu.pulse1.half_frame();
+        assert_eq!(apu.pulse1.length_counter, 254, "halted by the loop flag");
+
+        apu.write_register(APU_PULSE1_DUTY, 0x1F);
+        apu.pulse1.half_frame();
+        assert_eq!(apu.pulse1.length_counter, 253);
+
+        apu.write_register(APU_STATUS, 0x00);
+        assert_eq!(apu.read_register(APU_STATUS) & 0x01, 0);
+        apu.write_register(APU_PULSE1_TIMER_HIGH, 0x08);
+        assert_eq!(apu.pulse1.length_counter, 0, "not loaded while disabled");
+    }
+
+    #[test]
+    fn test_sweep_negate_and_muting() {
+        let mut apu = APU::new();
+        for (sweep, timer_low) in [(APU_PULSE1_SWEEP, APU_PULSE1_TIMER_LOW), (APU_PULSE2_SWEEP, APU_PULSE2_TIMER_LOW)] {
+            apu.write_register(sweep, 0x89); // enabled, negate, shift 1
+            apu.write_register(timer_low, 0x64);
+        }
+        assert_eq!(apu.pulse1.sweep_target(), 100 - 50 - 1);
+        assert_eq!(apu.pulse2.sweep_target(), 100 - 50);
+
+        // an upward sweep past $7FF mutes even with the sweep disabled
+        apu.write_register(APU_PULSE1_SWEEP, 0x01);
+        apu.write_register(APU_PULSE1_TIMER_LOW, 0x00);
+        apu.write_register(APU_PULSE1_TIMER_HIGH, 0x06);
+        assert!(apu.pulse1.sweep_muted());
+        apu.write_register(APU_PULSE1_TIMER_HIGH, 0x04);
+        assert!(!apu.pulse1.sweep_muted());
+        apu.write_register(APU_PULSE1_TIMER_HIGH, 0x00);
+        apu.write_register(APU_PULSE1_TIMER_LOW, 0x07);
+        assert!(apu.pulse1.sweep_muted(), "periods below 8 are muted");
+    }
+
+    #[test]
+    fn test_pulse_timer_runs_at_half_cpu_clock() {
+        let mut pulse = PulseChannel::new(false);
+        pulse.set_enabled(true);
+        pulse.write_timer_low(9);
+        pulse.write_timer_high(0x08);
+        // steps on its first APU cycle, then every 2 * (9 + 1) CPU cycles
+        for _ in 0..20 {
+            pulse.tick();
+        }
+        assert_eq!(pulse.duty_step, 1);
+        pulse.tick();
+        assert_eq!(pulse.duty_step, 2);
+    }
+
+    #[test]
+    fn test_frame_irq_is_cleared_by_status_read() {
+        let mut apu = APU::new();
+        for _ in 0..29830 {
+            apu.tick();
+        }
+        assert!(apu.irq());
+        assert_eq!(apu.read_register(APU_STATUS) & 0x40, 0x40);
+        assert!(!apu.irq());
+        assert_eq!(apu.read_register(APU_STATUS) & 0x40, 0);
+    }
+
+    // runs the APU the way the bus does, serving DMC reads from `memory` at $8000
+    fn run_with_memory(apu: &mut APU, memory: &[u8], cycles: u32) -> Vec<u16> {
+        let mut reads = Vec::new();
+        for _ in 0..cycles {
+            apu.tick();
+            if let Some(addr) = apu.dmc_dma_request() {
+                reads.push(addr);
+                apu.dmc_dma_complete(memory[(addr - 0x8000) as usize]);
+            }
+        }
+        reads
+    }
+
+    #[test]
+    fn test_dmc_plays_sample_from_memory() {
+        let mut memory = vec![0x00; 0x8000];
+        memory[0x4000] = 0xFF; // sample at $C000: only upward deltas
+        let mut apu = APU::new();
+        apu.write_register(APU_DMC_FREQ, 0x8F); // IRQ, fastest rate
+        apu.write_register(APU_DMC_RAW, 0x20);
+        apu.write_register(APU_DMC_START, 0x00);
+        apu.write_register(APU_DMC_LENGTH, 0x00); // 1 byte
+        apu.write_register(APU_STATUS, 0x10);
+        assert_eq!(apu.read_register(APU_STATUS) & 0x10, 0x10);
+
+        let reads = run_with_memory(&mut apu, &memory, 54 * 20);
+        assert_eq!(reads, vec![0xC000]);
+        assert_eq!(apu.dmc.output_level, 0x20 + 16);
+        assert_eq!(apu.read_register(APU_STATUS) & 0x90, 0x80, "finished with an IRQ");
+        assert!(apu.irq());
+
+        apu.write_register(APU_STATUS, 0x10);
+        assert!(!apu.irq(), "acknowledged by $4015 writes");
+    }
+
+    #[test]
+    fn test_dmc_address_wraps_and_loops() {
+        let memory = vec![0x00; 0x8000];
+        let mut apu = APU::new();
+        apu.write_register(APU_FRAME_COUNTER, 0x40);
+        apu.write_register(APU_DMC_FREQ, 0x4F); // loop
+        apu.write_register(APU_DMC_START, 0xFF); // $FFC0
+        apu.write_register(APU_DMC_LENGTH, 0x04); // 65 bytes
+        apu.write_register(APU_STATUS, 0x10);
+
+        let reads = run_with_memory(&mut apu, &memory, 54 * 8 * 70);
+        assert_eq!(reads[63], 0xFFFF);
+        assert_eq!(reads[64], 0x8000);
+        assert_eq!(reads[65], 0xFFC0, "restarts at the sample address");
+        assert!(!apu.irq());
+
+        apu.write_register(APU_STATUS, 0x00);
+        assert_eq!(apu.read_register(APU_STATUS) & 0x10, 0);
+        assert!(run_with_memory(&mut apu, &memory, 54 * 8 * 2).len() <= 1);
+    }
+
+    #[test]
+    fn test_record_mix_and_stems() {
+        let path = std::env::temp_dir().join(format!("nes_apu_test_{}.wav", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut apu = APU::new();
+        apu.start_recording(path, true).unwrap();
+        apu.write_register(APU_STATUS, 0x01);
+        apu.write_register(APU_PULSE1_DUTY, 0xBF);
+        apu.write_register(APU_PULSE1_TIMER_LOW, 0xFD);
+        apu.write_register(APU_PULSE1_TIMER_HIGH, 0x08);
+        for _ in 0..BLIP_FRAME * 100 {
+            apu.tick();
+        }
+        apu.stop_recording().unwrap();
+        assert!(!apu.is_recording());
+
+        let samples = |path: &str| {
+            let bytes = std::fs::read(path).unwrap();
+            std::fs::remove_file(path).unwrap();
+            bytes[44..]
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                .collect::<Vec<i16>>()
+        };
+        let mix = samples(path);
+        let pulse1 = samples(&recorder::stem_path(path, "pulse1"));
+        assert_eq!(mix.len(), apu.get_audio_buffer().len());
+        assert_eq!(mix.len(), pulse1.len());
+        assert!(pulse1.iter().any(|s| *s != 0));
+        // the triangle idles at level 15, which is a step at power on
+        let triangle = samples(&recorder::stem_path(path, "triangle"));
+        assert!(triangle[triangle.len() / 2..].iter().all(|s| *s == 0));
+        for name in ["pulse2", "noise", "dmc"] {
+            assert!(samples(&recorder::stem_path(path, name)).iter().all(|s| *s == 0), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_mute_and_solo() {
+        // peak level of the filtered output with pulse 1 playing
+        let peak = |configure: &dyn Fn(&mut ChannelControls)| {
+            let mut apu = APU::new();
+            configure(apu.channel_controls_mut());
+            apu.write_register(APU_STATUS, 0x01);
+            apu.write_register(APU_PULSE1_DUTY, 0xBF);
+            apu.write_register(APU_PULSE1_TIMER_LOW, 0xFD);
+            apu.write_register(APU_PULSE1_TIMER_HIGH, 0x08);
+            for _ in 0..BLIP_FRAME * 40 {
+                apu.tick();
+            }
+            let samples = apu.get_audio_buffer();
+            samples[samples.len() / 2..].iter().fold(0.0f32, |peak, s| peak.max(s.abs()))
+        };
+
+        let full = peak(&|_| {});
+        assert!(full > 0.05, "{}", full);
+        assert!(peak(&|c| c.set_muted(0, true)) < 0.001);
+        assert!(peak(&|c| c.set_solo(Some(2))) < 0.001);
+        assert!((peak(&|c| c.set_solo(Some(0))) - full).abs() < 0.01);
+        let half = peak(&|c| c.set_gain(0, 0.5));
+        assert!(half > full * 0.4 && half < full * 0.6, "{} vs {}", half, full);
+    }
+
+    #[test]
+    fn test_midi_export() {
+        let path = std::env::temp_dir().join(format!("nes_apu_test_{}.mid", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut apu = APU::new();
+        apu.start_midi_export(path).unwrap();
+        // pulse 1 plays A4 at constant volume 12, then stops
+        apu.write_register(APU_STATUS, 0x01);
+        apu.write_register(APU_PULSE1_DUTY, 0xBC);
+        apu.write_register(APU_PULSE1_TIMER_LOW, 0xFD);
+        apu.write_register(APU_PULSE1_TIMER_HIGH, 0x08);
+        for _ in 0..20000 {
+            apu.tick();
+        }
+        apu.write_register(APU_STATUS, 0x00);
+        for _ in 0..20000 {
+            apu.tick();
+        }
+        apu.stop_midi_export().unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let events: Vec<&[u8]> = bytes.windows(3).filter(|w| w[0] & 0xEF == 0x80 && w[1] == 69).collect();
+        assert_eq!(events, vec![&[0x90, 69, 102][..], &[0x80, 69, 0][..]]);
+    }
+}
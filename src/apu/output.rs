@@ -0,0 +1,69 @@
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use std::time::{Duration, Instant};
+
+// Queue depth the output aims for, in samples. Less than this risks the device
+// running dry when a frame takes long to emulate, more adds input-to-sound lag.
+const TARGET_LATENCY: usize = 2048;
+// Past this the emulator is running ahead of the sound card and has to wait.
+const MAX_LATENCY: usize = TARGET_LATENCY * 2;
+// never block the emulation for longer than that, e.g. when the device is stuck
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+/// SDL playback queue fed with the samples the APU produces.
+pub struct AudioOutput {
+    queue: AudioQueue<f32>,
+    started: bool,
+    pub underruns: u64,
+    pub overruns: u64,
+}
+
+impl AudioOutput {
+    pub fn open(sdl_context: &sdl2::Sdl, sample_rate: u32) -> Result<AudioOutput, String> {
+        let audio_subsystem = sdl_context.audio()?;
+        let desired = AudioSpecDesired {
+            freq: Some(sample_rate as i32),
+            channels: Some(1),
+            samples: Some(1024),
+        };
+        let queue = audio_subsystem.open_queue::<f32, _>(None, &desired)?;
+        queue.resume();
+
+        Ok(AudioOutput {
+            queue,
+            started: false,
+            underruns: 0,
+            overruns: 0,
+        })
+    }
+
+    /// Rate the device actually plays at, may differ from the requested one
+    pub fn sample_rate(&self) -> u32 {
+        self.queue.spec().freq as u32
+    }
+
+    fn queued_samples(&self) -> usize {
+        self.queue.size() as usize / std::mem::size_of::<f32>()
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        let queued = self.queued_samples();
+
+        if queued == 0 && self.started {
+            // the device ran dry and played silence; rebuild the cushion so that
+            // a single slow frame doesn't turn into continuous crackling
+            self.underruns += 1;
+            let last = samples.first().copied().unwrap_or(0.0);
+            self.queue.queue(&vec![last; TARGET_LATENCY / 2]);
+        } else if queued + samples.len() > MAX_LATENCY {
+            // producing faster than the device consumes: let it catch up
+            self.overruns += 1;
+            let deadline = Instant::now() + MAX_WAIT;
+            while self.queued_samples() + samples.len() > TARGET_LATENCY && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        self.queue.queue(samples);
+        self.started = true;
+    }
+}
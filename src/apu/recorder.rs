@@ -0,0 +1,96 @@
+// Writes the APU output to WAV files: the final mix, and optionally every
+// channel on its own ("stems"). Stems go through the same mixer curve,
+// resampler and filters as the mix, only with the other channels silent.
+use super::blip::BlipBuffer;
+use super::mixer::FilterChain;
+use super::wav::WavWriter;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+struct Stem {
+    wav: WavWriter<BufWriter<File>>,
+    blip: BlipBuffer,
+    filters: FilterChain,
+    last_level: f32,
+    samples: Vec<f32>,
+}
+
+pub struct Recorder {
+    mix: WavWriter<BufWriter<File>>,
+    stems: Vec<Stem>,
+}
+
+/// "music.wav" -> "music.triangle.wav"
+pub fn stem_path(path: &str, stem: &str) -> String {
+    Path::new(path)
+        .with_extension(format!("{}.wav", stem))
+        .to_string_lossy()
+        .into_owned()
+}
+
+impl Recorder {
+    /// Records the mix to `path`, and a stem for each of `stem_names` next to it
+    pub fn start(path: &str, sample_rate: u32, cpu_clock_hz: f64, stem_names: &[&str]) -> Result<Recorder, String> {
+        let mix = WavWriter::create(path, sample_rate)?;
+        let mut recorder = Recorder { mix, stems: Vec::new() };
+        for name in stem_names.iter() {
+            recorder.stems.push(Stem {
+                wav: WavWriter::create(&stem_path(path, name), sample_rate)?,
+                blip: BlipBuffer::new(cpu_clock_hz, sample_rate as f64),
+                filters: FilterChain::new(sample_rate),
+                last_level: 0.0,
+                samples: Vec::new(),
+            });
+        }
+        Ok(recorder)
+    }
+
+    pub fn has_stems(&self) -> bool {
+        !self.stems.is_empty()
+    }
+
+    /// Mixed level of every channel on its own, `clock` cycles into the resampler frame
+    pub fn add_stem_levels(&mut self, clock: u32, levels: &[f32]) {
+        for (stem, level) in self.stems.iter_mut().zip(levels.iter()) {
+            if *level != stem.last_level {
+                stem.blip.add_delta(clock, level - stem.last_level);
+                stem.last_level = *level;
+            }
+        }
+    }
+
+    /// Writes the samples of one resampler frame of `clocks` cycles
+    pub fn end_frame(&mut self, clocks: u32, mixed: &[f32], raw: bool) -> Result<(), String> {
+        self.mix.write(mixed)?;
+        for stem in self.stems.iter_mut() {
+            stem.blip.end_frame(clocks);
+            stem.samples.clear();
+            stem.blip.read_samples(&mut stem.samples);
+            if !raw {
+                stem.filters.process(&mut stem.samples);
+            }
+            stem.wav.write(&stem.samples)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        self.mix.finish()?;
+        for stem in self.stems {
+            stem.wav.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stem_path() {
+        assert_eq!(stem_path("music.wav", "pulse1"), "music.pulse1.wav");
+        assert_eq!(stem_path("out/music", "dmc"), "out/music.dmc.wav");
+    }
+}
@@ -0,0 +1,176 @@
+// VGM 1.61 log of the APU register writes, for playback in external players.
+// DMC samples go in as "NES APU RAM write" data blocks ahead of their playback.
+// https://vgmrips.net/wiki/VGM_Specification
+use crate::region::Region;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+const HEADER_SIZE: u32 = 0x100;
+const VERSION: u32 = 0x161;
+// VGM time is counted in samples at this rate, whatever the player outputs
+const VGM_SAMPLE_RATE: f64 = 44100.0;
+
+const CMD_NES_APU_WRITE: u8 = 0xB4;
+const CMD_WAIT: u8 = 0x61;
+const CMD_WAIT_NTSC_FRAME: u8 = 0x62;
+const CMD_WAIT_PAL_FRAME: u8 = 0x63;
+// 0x70-0x7F wait 1-16 samples
+const CMD_WAIT_SHORT: u8 = 0x70;
+const CMD_DATA_BLOCK: u8 = 0x67;
+const CMD_END: u8 = 0x66;
+const BLOCK_NES_APU_RAM: u8 = 0xC2;
+
+pub struct VgmWriter<W: Write + Seek> {
+    writer: W,
+    cpu_clock_hz: f64,
+    // APU cycle the log started at
+    start_cycle: u64,
+    // samples of waits written so far
+    samples: u64,
+    bytes: u32,
+    // what the player has in its memory at $8000-$FFFF, to send every sample only once
+    memory: Vec<Option<u8>>,
+}
+
+impl VgmWriter<BufWriter<File>> {
+    pub fn create(path: &str, region: Region, cycle: u64) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("Can't create {}: {}", path, e))?;
+        VgmWriter::new(BufWriter::new(file), region, cycle)
+    }
+}
+
+impl<W: Write + Seek> VgmWriter<W> {
+    /// Writes the header; the sizes in it are filled in by `finish`
+    pub fn new(mut writer: W, region: Region, cycle: u64) -> Result<Self, String> {
+        let mut header = vec![0; HEADER_SIZE as usize];
+        let mut put = |offset: usize, value: u32| header[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        put(0x08, VERSION);
+        put(0x24, region.frame_rate().round() as u32);
+        // relative to the field itself
+        put(0x34, HEADER_SIZE - 0x34);
+        put(0x84, region.cpu_clock_hz().round() as u32);
+        header[0..4].copy_from_slice(b"Vgm ");
+        writer.write_all(&header).map_err(|e| e.to_string())?;
+
+        Ok(VgmWriter {
+            writer,
+            cpu_clock_hz: region.cpu_clock_hz(),
+            start_cycle: cycle,
+            samples: 0,
+            bytes: HEADER_SIZE,
+            memory: vec![None; 0x8000],
+        })
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.bytes += bytes.len() as u32;
+        self.writer.write_all(bytes).map_err(|e| e.to_string())
+    }
+
+    // waits from the last command up to APU cycle `cycle`
+    fn wait_until(&mut self, cycle: u64) -> Result<(), String> {
+        let elapsed = cycle.saturating_sub(self.start_cycle) as f64;
+        let target = (elapsed * VGM_SAMPLE_RATE / self.cpu_clock_hz) as u64;
+        let mut wait = target.saturating_sub(self.samples);
+        self.samples = self.samples.max(target);
+        while wait > 0 {
+            let samples = wait.min(u16::MAX as u64);
+            match samples {
+                735 => self.emit(&[CMD_WAIT_NTSC_FRAME])?,
+                882 => self.emit(&[CMD_WAIT_PAL_FRAME])?,
+                1..=16 => self.emit(&[CMD_WAIT_SHORT + (samples - 1) as u8])?,
+                _ => {
+                    let [lo, hi] = (samples as u16).to_le_bytes();
+                    self.emit(&[CMD_WAIT, lo, hi])?
+                }
+            }
+            wait -= samples;
+        }
+        Ok(())
+    }
+
+    /// A write to $4000-$4017 at APU cycle `cycle`
+    pub fn write_register(&mut self, cycle: u64, addr: u16, value: u8) -> Result<(), String> {
+        self.wait_until(cycle)?;
+        self.emit(&[CMD_NES_APU_WRITE, (addr - 0x4000) as u8, value])
+    }
+
+    /// Sample data at `addr`, wrapping from $FFFF to $8000 the way the DMC reads it.
+    /// Only written when the player doesn't have it yet.
+    pub fn write_memory(&mut self, cycle: u64, addr: u16, data: &[u8]) -> Result<(), String> {
+        let first = (addr as usize).max(0x8000) - 0x8000;
+        let split = data.len().min(0x8000 - first);
+        for (start, bytes) in [(first, &data[..split]), (0, &data[split..])] {
+            let known = &mut self.memory[start..start + bytes.len()];
+            if known.iter().zip(bytes).all(|(known, byte)| *known == Some(*byte)) {
+                continue;
+            }
+            for (known, byte) in known.iter_mut().zip(bytes) {
+                *known = Some(*byte);
+            }
+
+            self.wait_until(cycle)?;
+            self.emit(&[CMD_DATA_BLOCK, CMD_END, BLOCK_NES_APU_RAM])?;
+            self.emit(&(bytes.len() as u32 + 2).to_le_bytes())?;
+            self.emit(&(0x8000 + start as u16).to_le_bytes())?;
+            self.emit(bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Ends the log at APU cycle `cycle` and patches the sizes into the header
+    pub fn finish(mut self, cycle: u64) -> Result<W, String> {
+        self.wait_until(cycle)?;
+        self.emit(&[CMD_END])?;
+        let patch = |writer: &mut W, offset: u64, value: u32| -> std::io::Result<()> {
+            writer.seek(SeekFrom::Start(offset))?;
+            writer.write_all(&value.to_le_bytes())
+        };
+        patch(&mut self.writer, 0x04, self.bytes - 4).map_err(|e| e.to_string())?;
+        patch(&mut self.writer, 0x18, self.samples as u32).map_err(|e| e.to_string())?;
+        self.writer.flush().map_err(|e| e.to_string())?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+    }
+
+    #[test]
+    fn test_vgm_log() {
+        let clock = Region::Ntsc.cpu_clock_hz();
+        let mut vgm = VgmWriter::new(Cursor::new(Vec::new()), Region::Ntsc, 1000).unwrap();
+        vgm.write_register(1000, 0x4000, 0xBF).unwrap();
+        // one NTSC frame of samples later
+        let frame = 1000 + (735.0 * clock / VGM_SAMPLE_RATE).ceil() as u64;
+        vgm.write_register(frame, 0x4015, 0x10).unwrap();
+        vgm.write_memory(frame, 0xFFFF, &[0x11, 0x22]).unwrap();
+        // already sent
+        vgm.write_memory(frame, 0x8000, &[0x22]).unwrap();
+        let bytes = vgm.finish(frame).unwrap().into_inner();
+
+        assert_eq!(&bytes[0..4], b"Vgm ");
+        assert_eq!(u32_at(&bytes, 0x04) as usize, bytes.len() - 4);
+        assert_eq!(u32_at(&bytes, 0x08), 0x161);
+        assert_eq!(u32_at(&bytes, 0x18), 735);
+        assert_eq!(u32_at(&bytes, 0x34), 0xCC);
+        assert_eq!(u32_at(&bytes, 0x84), 1789773);
+        assert_eq!(
+            &bytes[0x100..],
+            &[
+                0xB4, 0x00, 0xBF, //
+                0x62, //
+                0xB4, 0x15, 0x10, //
+                0x67, 0x66, 0xC2, 3, 0, 0, 0, 0xFF, 0xFF, 0x11, //
+                0x67, 0x66, 0xC2, 3, 0, 0, 0, 0x00, 0x80, 0x22, //
+                0x66,
+            ]
+        );
+    }
+}
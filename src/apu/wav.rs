@@ -0,0 +1,86 @@
+// Minimal RIFF/WAVE writer: mono, 16-bit PCM.
+// http://soundfile.sapp.org/doc/WaveFormat/
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+const HEADER_SIZE: u32 = 44;
+
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    samples: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create(path: &str, sample_rate: u32) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("Can't create {}: {}", path, e))?;
+        WavWriter::new(BufWriter::new(file), sample_rate)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// Writes the header; the sizes in it are filled in by `finish`
+    pub fn new(mut writer: W, sample_rate: u32) -> Result<Self, String> {
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        header.extend_from_slice(&1u16.to_le_bytes()); // mono
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&(sample_rate * 2).to_le_bytes()); // bytes per second
+        header.extend_from_slice(&2u16.to_le_bytes()); // bytes per frame
+        header.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        writer.write_all(&header).map_err(|e| e.to_string())?;
+
+        Ok(WavWriter { writer, samples: 0 })
+    }
+
+    pub fn write(&mut self, samples: &[f32]) -> Result<(), String> {
+        let mut bytes = Vec::with_capacity(samples.len() * 2);
+        for sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        self.writer.write_all(&bytes).map_err(|e| e.to_string())?;
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    /// Patches the chunk sizes into the header, returning the underlying writer
+    pub fn finish(mut self) -> Result<W, String> {
+        let data_size = self.samples * 2;
+        let patch = |writer: &mut W, offset: u64, value: u32| -> std::io::Result<()> {
+            writer.seek(SeekFrom::Start(offset))?;
+            writer.write_all(&value.to_le_bytes())
+        };
+        patch(&mut self.writer, 4, HEADER_SIZE - 8 + data_size).map_err(|e| e.to_string())?;
+        patch(&mut self.writer, 40, data_size).map_err(|e| e.to_string())?;
+        self.writer.flush().map_err(|e| e.to_string())?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_wav_layout() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+        wav.write(&[0.0, 1.0, -1.0]).unwrap();
+        wav.write(&[2.0]).unwrap();
+        let bytes = wav.finish().unwrap().into_inner();
+
+        assert_eq!(bytes.len(), 44 + 8);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]), 36 + 8);
+        assert_eq!(u32::from_le_bytes([bytes[24], bytes[25], bytes[26], bytes[27]]), 44100);
+        assert_eq!(u32::from_le_bytes([bytes[40], bytes[41], bytes[42], bytes[43]]), 8);
+        assert_eq!(&bytes[44..], &[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80, 0xFF, 0x7F]);
+    }
+}
@@ -1,9 +1,13 @@
 use crate::cartridge::Rom;
+use crate::mapper::{self, Mapper, SharedMapper};
 use crate::cpu::Mem;
 use crate::ppu::NesPPU;
 use crate::ppu::PPU;
 use crate::joypad::Joypad;
 use crate::apu::APU;
+use crate::region::Region;
+use std::cell::RefCell;
+use std::rc::Rc;
 
 //  _______________ $10000  _______________
 // | PRG-ROM       |       |               |
@@ -36,69 +40,155 @@ const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF;
 const PPU_REGISTERS: u16 = 0x2000;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
+// CPU cycles the CPU is halted for while the DMC fetches a sample byte
+const DMC_DMA_CYCLES: u8 = 4;
+
+type GameloopCallback<'call> = Box<dyn FnMut(&NesPPU, &mut APU, &mut Joypad) + 'call>;
 
 pub struct Bus<'call> {
     cpu_vram: [u8; 2048],
-    prg_rom: Vec<u8>,
-    ppu: NesPPU,
+    mapper: SharedMapper,
+    // None for the NSF player, which has no picture to draw
+    ppu: Option<NesPPU>,
     apu: APU,
 
     cycles: usize,
-    gameloop_callback: Box<dyn FnMut(&NesPPU, &mut Joypad) + 'call>,
+    region: Region,
+    // PPU dots owed to the PPU, in 1/denominator units of the CPU:PPU ratio
+    ppu_dots_remainder: u32,
+    gameloop_callback: GameloopCallback<'call>,
     joypad1: Joypad,
 }
 
 impl<'a> Bus<'a> {
     pub fn new<'call, F>(rom: Rom, gameloop_callback: F) -> Bus<'call>
     where
-        F: FnMut(&NesPPU, &mut Joypad) + 'call,
+        F: FnMut(&NesPPU, &mut APU, &mut Joypad) + 'call,
+    {
+        let mapper = mapper::for_rom(&rom);
+        let region = rom.region.unwrap_or_default();
+        Bus::with_mapper(mapper, region, gameloop_callback)
+    }
+
+    /// Bus for cartridge hardware that doesn't come from an iNES file, e.g. the NSF player
+    pub fn with_mapper<'call, F>(mapper: Box<dyn Mapper>, region: Region, gameloop_callback: F) -> Bus<'call>
+    where
+        F: FnMut(&NesPPU, &mut APU, &mut Joypad) + 'call,
     {
-        let ppu = NesPPU::new(rom.chr_rom, rom.screen_mirroring);
+        let mapper: SharedMapper = Rc::new(RefCell::new(mapper));
+        let mut ppu = NesPPU::with_mapper(mapper.clone());
+        ppu.set_region(region);
+        Bus::build(mapper, Some(ppu), region, Box::from(gameloop_callback))
+    }
+
+    /// Bus with the CPU, APU and cartridge only, for the NSF player. PPU
+    /// registers read as 0 and ignore writes, and vblank never comes.
+    pub fn without_ppu(mapper: Box<dyn Mapper>, region: Region) -> Bus<'static> {
+        Bus::build(Rc::new(RefCell::new(mapper)), None, region, Box::new(|_: &NesPPU, _: &mut APU, _: &mut Joypad| {}))
+    }
+
+    fn build<'call>(
+        mapper: SharedMapper,
+        ppu: Option<NesPPU>,
+        region: Region,
+        gameloop_callback: GameloopCallback<'call>,
+    ) -> Bus<'call> {
         let mut apu = APU::new();
+        apu.set_region(region);
+        if let Some(expansion) = mapper.borrow().expansion_audio() {
+            apu.set_expansion_audio(expansion);
+        }
 
         Bus {
             cpu_vram: [0; 2048],
-            prg_rom: rom.prg_rom,
-            ppu: ppu,
-            apu: apu,
+            mapper,
+            ppu,
+            apu,
             cycles: 0,
-            gameloop_callback: Box::from(gameloop_callback),
+            region,
+            ppu_dots_remainder: 0,
+            gameloop_callback,
             joypad1: Joypad::new()
         }
     }
 
-    pub fn init_audio(&mut self, sdl_context: &sdl2::Sdl) -> Result<(), String> {
-        self.apu.init_audio(sdl_context)
+    pub fn init_audio(&mut self, sdl_context: &sdl2::Sdl, sample_rate: u32) -> Result<(), String> {
+        self.apu.init_audio(sdl_context, sample_rate)
     }
 
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            //mirror if needed
-            addr = addr % 0x4000;
-        }
-        self.prg_rom[addr as usize]
+    pub fn apu_mut(&mut self) -> &mut APU {
+        &mut self.apu
     }
 
-    pub fn tick(&mut self, cycles: u8) {
-        self.cycles += cycles as usize;
+    /// CPU cycles run since power on
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
 
+    pub fn tick(&mut self, mut cycles: u8) {
         // Tick APU for each CPU cycle
-        for _ in 0..cycles {
+        let mut cycle = 0;
+        while cycle < cycles {
+            self.mapper.borrow_mut().tick();
             self.apu.tick();
+            if let Some(addr) = self.apu.dmc_dma_request() {
+                // a sample starting over may have changed since the log last saw it
+                if addr == self.apu.dmc_sample().0 {
+                    self.log_dmc_sample();
+                }
+                let data = self.mem_read(addr);
+                self.apu.dmc_dma_complete(data);
+                cycles += DMC_DMA_CYCLES;
+            }
+            cycle += 1;
         }
+        self.cycles += cycles as usize;
+
+        let ppu = match self.ppu.as_mut() {
+            Some(ppu) => ppu,
+            None => return,
+        };
+        let (dots_per_cycle, denominator) = self.region.ppu_dots_per_cpu_cycle();
+        let dots = self.ppu_dots_remainder + cycles as u32 * dots_per_cycle;
+        self.ppu_dots_remainder = dots % denominator;
 
-        let nmi_before = self.ppu.nmi_interrupt.is_some();
-        self.ppu.tick(cycles *3);
-        let nmi_after = self.ppu.nmi_interrupt.is_some();
+        let nmi_before = ppu.nmi_interrupt.is_some();
+        ppu.tick((dots / denominator) as u8);
+        let nmi_after = ppu.nmi_interrupt.is_some();
         
         if !nmi_before && nmi_after {
-            (self.gameloop_callback)(&self.ppu, &mut self.joypad1);
+            (self.gameloop_callback)(ppu, &mut self.apu, &mut self.joypad1);
         }
     }
     
+    // passes the DMC sample to the VGM log, which needs it ahead of its playback
+    fn log_dmc_sample(&mut self) {
+        if !self.apu.is_logging_vgm() {
+            return;
+        }
+        let (start, length) = self.apu.dmc_sample();
+        let data: Vec<u8> = (0..length as u32)
+            .map(|offset| {
+                // the DMC wraps from $FFFF to $8000
+                let addr = start as u32 + offset;
+                let addr = if addr > 0xFFFF { addr - 0x8000 } else { addr };
+                self.mapper.borrow_mut().read(addr as u16)
+            })
+            .collect();
+        self.apu.log_vgm_sample_data(start, &data);
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
     pub fn poll_nmi_status(&mut self) -> Option<u8> {
-        self.ppu.poll_nmi_interrupt()
+        self.ppu.as_mut().and_then(|ppu| ppu.poll_nmi_interrupt())
+    }
+
+    /// IRQ is level triggered: it stays asserted until the source is acknowledged
+    pub fn irq_pending(&self) -> bool {
+        self.apu.irq() || self.mapper.borrow().irq()
     }
 
     pub fn get_audio_buffer(&mut self) -> Vec<f32> {
@@ -117,9 +207,12 @@ impl Mem for Bus<'_> {
                 // panic!("Attempt to read from write-only PPU address {:x}", addr);
                 0
             }
-            0x2002 => self.ppu.read_status(),
-            0x2004 => self.ppu.read_oam_data(),
-            0x2007 => self.ppu.read_data(),
+            0x2002 | 0x2004 | 0x2007 => match self.ppu.as_mut() {
+                Some(ppu) if addr == 0x2002 => ppu.read_status(),
+                Some(ppu) if addr == 0x2004 => ppu.read_oam_data(),
+                Some(ppu) => ppu.read_data(),
+                None => 0,
+            },
 
             0x4000..=0x4015 | 0x4017 => {
                 self.apu.read_register(addr)
@@ -133,7 +226,7 @@ impl Mem for Bus<'_> {
                 let mirror_down_addr = addr & 0b00100000_00000111;
                 self.mem_read(mirror_down_addr)
             }
-            0x8000..=0xFFFF => self.read_prg_rom(addr),
+            0x4020..=0xFFFF => self.mapper.borrow_mut().read(addr),
 
             _ => {
                 // println!("Ignoring mem access at {:x}", addr);
@@ -148,32 +241,16 @@ impl Mem for Bus<'_> {
                 let mirror_down_addr = addr & 0b11111111111;
                 self.cpu_vram[mirror_down_addr as usize] = data;
             }
-            0x2000 => {
-                self.ppu.write_to_ctrl(data);
-            }
-            0x2001 => {
-                self.ppu.write_to_mask(data);
-            }
-
-            0x2002 => panic!("attempt to write to PPU status register"),
-
-            0x2003 => {
-                self.ppu.write_to_oam_addr(data);
-            }
-            0x2004 => {
-                self.ppu.write_to_oam_data(data);
-            }
-            0x2005 => {
-                self.ppu.write_to_scroll(data);
-            }
-
-            0x2006 => {
-                self.ppu.write_to_ppu_addr(data);
-            }
-            0x2007 => {
-                self.ppu.write_to_data(data);
+            0x2000..=0x2007 => {
+                if let Some(ppu) = self.ppu.as_mut() {
+                    write_ppu_register(ppu, addr, data);
+                }
             }
             0x4000..=0x4013 | 0x4015 | 0x4017 => {
+                // the log has to have the sample before the write that may start it
+                if addr == 0x4015 && data & 0x10 != 0 {
+                    self.log_dmc_sample();
+                }
                 self.apu.write_register(addr, data);
             }
 
@@ -189,7 +266,9 @@ impl Mem for Bus<'_> {
                     buffer[i as usize] = self.mem_read(hi + i);
                 }
 
-                self.ppu.write_oam_dma(&buffer);
+                if let Some(ppu) = self.ppu.as_mut() {
+                    ppu.write_oam_dma(&buffer);
+                }
 
                 // todo: handle this eventually
                 // let add_cycles: u16 = if self.cycles % 2 == 1 { 514 } else { 513 };
@@ -200,9 +279,7 @@ impl Mem for Bus<'_> {
                 let mirror_down_addr = addr & 0b00100000_00000111;
                 self.mem_write(mirror_down_addr, data);
             }
-            0x8000..=0xFFFF => {
-                // panic!("attempt to write to cartridge ROM space");
-            }
+            0x4020..=0xFFFF => self.mapper.borrow_mut().write(addr, data),
 
             _ => {
                 // println!("Ignoring mem write-access at {:x}", addr);
@@ -211,6 +288,38 @@ impl Mem for Bus<'_> {
     }
 }
 
+fn write_ppu_register(ppu: &mut NesPPU, addr: u16, data: u8) {
+    // some boards watch the PPU registers, mirrors come back through here
+    ppu.notify_register_write(addr, data);
+    match addr {
+        0x2000 => {
+            ppu.write_to_ctrl(data);
+        }
+        0x2001 => {
+            ppu.write_to_mask(data);
+        }
+
+        0x2002 => panic!("attempt to write to PPU status register"),
+
+        0x2003 => {
+            ppu.write_to_oam_addr(data);
+        }
+        0x2004 => {
+            ppu.write_to_oam_data(data);
+        }
+        0x2005 => {
+            ppu.write_to_scroll(data);
+        }
+
+        0x2006 => {
+            ppu.write_to_ppu_addr(data);
+        }
+        _ => {
+            ppu.write_to_data(data);
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -218,8 +327,53 @@ mod test {
 
     #[test]
     fn test_mem_read_write_to_ram() {
-        let mut bus = Bus::new(test::test_rom(), |_, _| {});
+        let mut bus = Bus::new(test::test_rom(), |_, _, _| {});
         bus.mem_write(0x01, 0x55);
         assert_eq!(bus.mem_read(0x01), 0x55);
     }
+
+    #[test]
+    fn test_bus_without_ppu() {
+        let mut bus = Bus::without_ppu(mapper::for_rom(&test::test_rom()), Region::Ntsc);
+        bus.mem_write(0x2000, 0x80);
+        bus.mem_write(0x4014, 0x02);
+        bus.tick(255);
+        assert_eq!(bus.mem_read(0x2002), 0);
+        assert_eq!(bus.poll_nmi_status(), None);
+        bus.mem_write(0x4015, 0x01);
+        bus.mem_write(0x4003, 0x08);
+        assert_eq!(bus.mem_read(0x4015) & 0x01, 0x01, "the APU still runs");
+    }
+
+    #[test]
+    fn test_dmc_dma_stalls_cpu() {
+        let mut bus = Bus::new(test::test_rom(), |_, _, _| {});
+        bus.mem_write(0x4013, 0x00);
+        bus.mem_write(0x4015, 0x10);
+        bus.tick(2);
+        assert_eq!(bus.cycles, 2 + DMC_DMA_CYCLES as usize);
+        assert_eq!(bus.mem_read(0x4015) & 0x10, 0, "the only byte was fetched");
+    }
+
+    #[test]
+    fn test_vgm_log_gets_dmc_sample() {
+        let path = std::env::temp_dir().join(format!("nes_bus_test_{}.vgm", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut bus = Bus::new(test::test_rom(), |_, _, _| {});
+        bus.apu_mut().start_vgm_log(path).unwrap();
+        bus.mem_write(0x4012, 0x00);
+        bus.mem_write(0x4013, 0x00);
+        bus.mem_write(0x4015, 0x10);
+        bus.tick(2);
+        bus.apu_mut().stop_vgm_log().unwrap();
+        let vgm = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        // a one byte sample block at $C000, then the write that plays it
+        let sample = bus.mem_read(0xC000);
+        assert_eq!(
+            &vgm[0x100..],
+            &[0xB4, 0x12, 0x00, 0xB4, 0x13, 0x00, 0x67, 0x66, 0xC2, 3, 0, 0, 0, 0x00, 0xC0, sample, 0xB4, 0x15, 0x10, 0x66]
+        );
+    }
 }
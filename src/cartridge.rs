@@ -1,4 +1,8 @@
+use crate::region::Region;
+
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+const UNIF_TAG: [u8; 4] = *b"UNIF";
+const UNIF_HEADER_SIZE: usize = 32;
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
 
@@ -7,27 +11,38 @@ pub enum Mirroring {
     Vertical,
     Horizontal,
     FourScreen,
+    // every nametable address shows the first or the second nametable,
+    // selected by the mapper
+    SingleScreenLower,
+    SingleScreenUpper,
 }
 
 pub struct Rom {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
-    pub mapper: u8,
+    pub mapper: u16,
+    pub submapper: u8,
     pub screen_mirroring: Mirroring,
+    // timing declared by the header, None if it doesn't say
+    pub region: Option<Region>,
 }
 
 impl Rom {
     pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
+        if raw.starts_with(&UNIF_TAG) {
+            return Rom::from_unif(raw);
+        }
         if &raw[0..4] != NES_TAG {
-            return Err("File is not in iNES file format".to_string());
+            return Err("File is not in iNES or UNIF file format".to_string());
         }
 
-        let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
+        let mut mapper = ((raw[7] & 0b1111_0000) | (raw[6] >> 4)) as u16;
 
         let ines_ver = (raw[7] >> 2) & 0b11;
-        if ines_ver != 0 {
-            return Err("NES2.0 format is not supported".to_string());
+        if ines_ver != 0 && ines_ver != 2 {
+            return Err("Unknown iNES header version".to_string());
         }
+        let nes2 = ines_ver == 2;
 
         let four_screen = raw[6] & 0b1000 != 0;
         let vertical_mirroring = raw[6] & 0b1 != 0;
@@ -37,23 +52,159 @@ impl Rom {
             (false, false) => Mirroring::Horizontal,
         };
 
-        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
-        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        let mut submapper = 0;
+        let mut prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
+        let mut chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        let region;
+
+        if nes2 {
+            // https://wiki.nesdev.com/w/index.php/NES_2.0
+            mapper |= ((raw[8] & 0x0f) as u16) << 8;
+            submapper = raw[8] >> 4;
+            prg_rom_size = nes2_rom_size(raw[4], raw[9] & 0x0f, PRG_ROM_PAGE_SIZE)?;
+            chr_rom_size = nes2_rom_size(raw[5], raw[9] >> 4, CHR_ROM_PAGE_SIZE)?;
+            region = match raw[12] & 0b11 {
+                0 => Some(Region::Ntsc),
+                1 => Some(Region::Pal),
+                3 => Some(Region::Dendy),
+                _ => None, // multi-region, runs on any
+            };
+        } else {
+            region = if raw[9] & 1 != 0 { Some(Region::Pal) } else { None };
+        }
 
         let skip_trainer = raw[6] & 0b100 != 0;
 
-        let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
-        let chr_rom_start = prg_rom_start + prg_rom_size;
+        let prg_rom_start: usize = 16 + if skip_trainer { 512 } else { 0 };
+        let chr_rom_start = prg_rom_start.checked_add(prg_rom_size).ok_or("ROM size out of range")?;
+        let chr_rom_end = chr_rom_start.checked_add(chr_rom_size).ok_or("ROM size out of range")?;
+
+        if raw.len() < chr_rom_end {
+            return Err("ROM file is truncated".to_string());
+        }
+
+        Ok(Rom {
+            prg_rom: raw[prg_rom_start..chr_rom_start].to_vec(),
+            chr_rom: raw[chr_rom_start..chr_rom_end].to_vec(),
+            mapper,
+            submapper,
+            screen_mirroring,
+            region,
+        })
+    }
+
+    /// A UNIF image: a 32 byte header, then chunks of a 4 byte ID, a 32-bit
+    /// little endian length and the data. The board is named rather than
+    /// numbered, and PRG and CHR come in up to 16 chunks each.
+    /// https://wiki.nesdev.com/w/index.php/UNIF
+    pub fn from_unif(raw: &[u8]) -> Result<Rom, String> {
+        let mut board = None;
+        let mut prg_chunks: [&[u8]; 16] = [&[]; 16];
+        let mut chr_chunks: [&[u8]; 16] = [&[]; 16];
+        let mut screen_mirroring = Mirroring::Horizontal;
+        let mut region = None;
+
+        let mut pos = UNIF_HEADER_SIZE;
+        while pos + 8 <= raw.len() {
+            let id = &raw[pos..pos + 4];
+            let len = u32::from_le_bytes([raw[pos + 4], raw[pos + 5], raw[pos + 6], raw[pos + 7]]) as usize;
+            let data = raw
+                .get(pos + 8..pos + 8 + len)
+                .ok_or_else(|| format!("UNIF chunk {} is truncated", String::from_utf8_lossy(id)))?;
+            pos += 8 + len;
+
+            match id {
+                b"MAPR" => {
+                    let name = data.split(|&byte| byte == 0).next().unwrap_or_default();
+                    board = Some(String::from_utf8_lossy(name).trim().to_string());
+                }
+                b"MIRR" => {
+                    screen_mirroring = match data.first() {
+                        Some(1) => Mirroring::Vertical,
+                        Some(2) => Mirroring::SingleScreenLower,
+                        Some(3) => Mirroring::SingleScreenUpper,
+                        Some(4) => Mirroring::FourScreen,
+                        // horizontal, or controlled by the mapper
+                        _ => Mirroring::Horizontal,
+                    }
+                }
+                b"TVCI" => {
+                    region = match data.first() {
+                        Some(0) => Some(Region::Ntsc),
+                        Some(1) => Some(Region::Pal),
+                        _ => None,
+                    }
+                }
+                _ => {
+                    // PRG0-PRGF and CHR0-CHRF, hex digit last
+                    let chunk = (id[3] as char).to_digit(16).map(|n| n as usize);
+                    match (&id[0..3], chunk) {
+                        (b"PRG", Some(n)) => prg_chunks[n] = data,
+                        (b"CHR", Some(n)) => chr_chunks[n] = data,
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        let board = board.ok_or("UNIF file has no MAPR chunk")?;
+        let (mapper, submapper) = unif_board(&board).ok_or(format!("Unknown UNIF board {}", board))?;
+        let prg_rom = prg_chunks.concat();
+        if prg_rom.is_empty() {
+            return Err("UNIF file has no PRG chunks".to_string());
+        }
 
         Ok(Rom {
-            prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
-            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
-            mapper: mapper,
-            screen_mirroring: screen_mirroring,
+            prg_rom,
+            chr_rom: chr_chunks.concat(),
+            mapper,
+            submapper,
+            screen_mirroring,
+            region,
         })
     }
 }
 
+// UNIF board names, without the "NES-", "UNL-" etc. prefix but with makers'
+// names like "CAMERICA-", as iNES mapper and NES 2.0 submapper numbers. Only boards with a mapper here are listed, so
+// the rest are turned away rather than run as NROM.
+fn unif_board(name: &str) -> Option<(u16, u8)> {
+    let board = match name.split_once('-') {
+        Some((prefix, board)) if ["NES", "HVC", "UNL", "BTL", "BMC", "AVE"].contains(&prefix) => board,
+        _ => name,
+    };
+    let numbers = match board {
+        "NROM" | "NROM-128" | "NROM-256" | "RROM" | "RROM-128" => (0, 0),
+        "EKROM" | "ELROM" | "ETROM" | "EWROM" => (5, 0),
+        "PNROM" | "PEEOROM" => (9, 0),
+        "FJROM" | "FKROM" => (10, 0),
+        "COLORDREAMS-74*377" => (11, 0),
+        "NINA-001" | "NINA-01" => (34, 1),
+        "BNROM" => (34, 2),
+        "GNROM" | "MHROM" => (66, 0),
+        "JLROM" | "JSROM" | "BTR" => (69, 0),
+        "CAMERICA-BF9093" => (71, 0),
+        "CAMERICA-BF9097" => (71, 1),
+        "NINA-03" | "NINA-06" => (79, 0),
+        _ => return None,
+    };
+    Some(numbers)
+}
+
+// NES 2.0 sizes: LSB from the iNES size byte and MSB nibble from byte 9,
+// or exponent-multiplier notation when the MSB nibble is 0xF
+fn nes2_rom_size(lsb: u8, msb: u8, page_size: usize) -> Result<usize, String> {
+    let size = if msb == 0x0f {
+        // exponents up to 63 are valid in the header, if not in any real ROM
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        1usize.checked_shl(exponent).and_then(|size| size.checked_mul(multiplier))
+    } else {
+        (((msb as usize) << 8) | lsb as usize).checked_mul(page_size)
+    };
+    size.ok_or_else(|| "ROM size out of range".to_string())
+}
+
 pub mod test {
 
     use super::*;
@@ -157,19 +308,84 @@ pub mod test {
     }
 
     #[test]
-    fn test_nes2_is_not_supported() {
+    fn test_nes2_header() {
         let test_rom = create_rom(TestRom {
             header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x8, 00, 00, 00, 00, 00, 00, 00, 00,
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x8, 0x21, 00, 00, 00, 0x01, 00, 00, 00,
             ],
             trainer: None,
             pgp_rom: vec![1; 1 * PRG_ROM_PAGE_SIZE],
             chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
         });
-        let rom = Rom::new(&test_rom);
-        match rom {
-            Result::Ok(_) => assert!(false, "should not load rom"),
-            Result::Err(str) => assert_eq!(str, "NES2.0 format is not supported"),
+        let rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.mapper, 0x103);
+        assert_eq!(rom.submapper, 2);
+        assert_eq!(rom.region, Some(Region::Pal));
+        assert_eq!(rom.prg_rom, vec!(1; PRG_ROM_PAGE_SIZE));
+        assert_eq!(rom.chr_rom, vec!(2; CHR_ROM_PAGE_SIZE));
+    }
+
+    #[test]
+    fn test_unif() {
+        fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+            let mut chunk = id.to_vec();
+            chunk.extend(&(data.len() as u32).to_le_bytes());
+            chunk.extend(data);
+            chunk
         }
+        let mut raw = b"UNIF".to_vec();
+        raw.extend(&[7, 0, 0, 0]);
+        raw.resize(UNIF_HEADER_SIZE, 0);
+        raw.extend(chunk(b"MAPR", b"NES-GNROM\0"));
+        // chunks out of order come together by number
+        raw.extend(chunk(b"PRG1", &[2; 0x4000]));
+        raw.extend(chunk(b"PRG0", &[1; 0x4000]));
+        raw.extend(chunk(b"CHR0", &[3; 0x2000]));
+        raw.extend(chunk(b"MIRR", &[1]));
+        raw.extend(chunk(b"TVCI", &[1]));
+
+        let rom = Rom::new(&raw).unwrap();
+        assert_eq!(rom.mapper, 66);
+        assert_eq!(rom.prg_rom.len(), 0x8000);
+        assert_eq!((rom.prg_rom[0], rom.prg_rom[0x4000]), (1, 2));
+        assert_eq!(rom.chr_rom, vec![3; 0x2000]);
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+        assert_eq!(rom.region, Some(Region::Pal));
+
+        // a board for each mapper, with and without a prefix
+        assert_eq!(unif_board("NES-NROM-256"), Some((0, 0)));
+        assert_eq!(unif_board("NES-ELROM"), Some((5, 0)));
+        assert_eq!(unif_board("NES-PNROM"), Some((9, 0)));
+        assert_eq!(unif_board("HVC-FKROM"), Some((10, 0)));
+        assert_eq!(unif_board("COLORDREAMS-74*377"), Some((11, 0)));
+        assert_eq!(unif_board("AVE-NINA-01"), Some((34, 1)));
+        assert_eq!(unif_board("NES-BNROM"), Some((34, 2)));
+        assert_eq!(unif_board("NES-MHROM"), Some((66, 0)));
+        assert_eq!(unif_board("NES-JLROM"), Some((69, 0)));
+        assert_eq!(unif_board("NES-BTR"), Some((69, 0)));
+        assert_eq!(unif_board("CAMERICA-BF9097"), Some((71, 1)));
+        assert_eq!(unif_board("AVE-NINA-06"), Some((79, 0)));
+        assert_eq!(unif_board("UNL-SOMETHING"), None);
+        // known boards we have no mapper for are just as unknown
+        assert_eq!(unif_board("NES-SLROM"), None);
+
+        // a board we don't know, or a chunk running past the end of the file
+        let mut unknown = raw[..UNIF_HEADER_SIZE].to_vec();
+        unknown.extend(chunk(b"MAPR", b"UNL-SOMETHING\0"));
+        assert!(Rom::new(&unknown).is_err());
+        raw.truncate(raw.len() - 1);
+        assert_eq!(Rom::new(&raw).err(), Some("UNIF chunk TVCI is truncated".to_string()));
+    }
+
+    #[test]
+    fn test_nes2_exponent_size() {
+        assert_eq!(nes2_rom_size(0b0001_0101, 0x0f, PRG_ROM_PAGE_SIZE), Ok(32 * 3));
+        assert_eq!(nes2_rom_size(0x02, 0x01, PRG_ROM_PAGE_SIZE), Ok(0x102 * PRG_ROM_PAGE_SIZE));
+        // 2^63 * 7 doesn't fit
+        assert_eq!(nes2_rom_size(0xFF, 0x0f, PRG_ROM_PAGE_SIZE), Err("ROM size out of range".to_string()));
+        // PRG and CHR each fit, but not together
+        let header = vec![0x4E, 0x45, 0x53, 0x1A, 0xF7, 0xF7, 00, 0x08, 00, 0xFF, 00, 00, 00, 00, 00, 00];
+        assert_eq!(Rom::new(&header).err(), Some("ROM size out of range".to_string()));
     }
 }
@@ -97,8 +97,9 @@ fn page_cross(addr1: u16, addr2: u16) -> bool {
 mod interrupt {
     #[derive(PartialEq, Eq)]
     pub enum InterruptType {
-        NMI,
-        BRK,
+        Nmi,
+        Irq,
+        Brk,
     }
 
     #[derive(PartialEq, Eq)]
@@ -110,14 +111,21 @@ mod interrupt {
     }
 
     pub(super) const NMI: Interrupt = Interrupt {
-        itype: InterruptType::NMI,
+        itype: InterruptType::Nmi,
         vector_addr: 0xfffA,
         b_flag_mask: 0b00100000,
         cpu_cycles: 2,
     };
 
+    pub(super) const IRQ: Interrupt = Interrupt {
+        itype: InterruptType::Irq,
+        vector_addr: 0xfffe,
+        b_flag_mask: 0b00100000,
+        cpu_cycles: 2,
+    };
+
     pub(super) const BRK: Interrupt = Interrupt {
-        itype: InterruptType::BRK,
+        itype: InterruptType::Brk,
         vector_addr: 0xfffe,
         b_flag_mask: 0b00110000,
         cpu_cycles: 1,
@@ -412,7 +420,7 @@ impl<'a> CPU<'a> {
         self.stack_pointer = self.stack_pointer.wrapping_sub(1)
     }
 
-    fn stack_push_u16(&mut self, data: u16) {
+    pub fn stack_push_u16(&mut self, data: u16) {
         let hi = (data >> 8) as u8;
         let lo = (data & 0xff) as u8;
         self.stack_push(hi);
@@ -662,548 +670,557 @@ impl<'a> CPU<'a> {
     where
         F: FnMut(&mut CPU),
     {
-        let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
-
         loop {
             if let Some(_nmi) = self.bus.poll_nmi_status() {
                 self.interrupt(interrupt::NMI);
+            } else if self.bus.irq_pending() && !self.status.contains(CpuFlags::INTERRUPT_DISABLE) {
+                self.interrupt(interrupt::IRQ);
             }
 
             callback(self);
-            let code = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-            let program_counter_state = self.program_counter;
+            if !self.step() {
+                return;
+            }
+        }
+    }
 
-            let opcode = opcodes
-                .get(&code)
-                .expect(&format!("OpCode {:x} is not recognized", code));
+    /// Executes one instruction, interrupts aside. Returns false on BRK.
+    pub fn step(&mut self) -> bool {
+        let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
+        let code = self.mem_read(self.program_counter);
+        self.program_counter += 1;
+        let program_counter_state = self.program_counter;
+
+        let opcode = opcodes
+            .get(&code)
+            .expect(&format!("OpCode {:x} is not recognized", code));
+
+        // if opcode.code == 0x24 {
+        //     panic!(format!("mem 01 = {}", self.mem_read(0x01)));
+        // }
+        match code {
+            0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
+                self.lda(&opcode.mode);
+            }
 
-            // if opcode.code == 0x24 {
-            //     panic!(format!("mem 01 = {}", self.mem_read(0x01)));
+            0xAA => self.tax(),
+            0xe8 => self.inx(),
+            0x00 => return false,
+            // 0x00 => {
+            //     self.program_counter += 1;
+            //     if !self.status.contains(CpuFlags::INTERRUPT_DISABLE) {
+            //         self.interrupt(interrupt::BRK);
+            //     }
             // }
-            match code {
-                0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
-                    self.lda(&opcode.mode);
-                }
-
-                0xAA => self.tax(),
-                0xe8 => self.inx(),
-                0x00 => return,
-                // 0x00 => {
-                //     self.program_counter += 1;
-                //     if !self.status.contains(CpuFlags::INTERRUPT_DISABLE) {
-                //         self.interrupt(interrupt::BRK);
-                //     }
-                // }
     
-                /* CLD */ 0xd8 => self.status.remove(CpuFlags::DECIMAL_MODE),
+            /* CLD */ 0xd8 => self.status.remove(CpuFlags::DECIMAL_MODE),
 
-                /* CLI */ 0x58 => self.status.remove(CpuFlags::INTERRUPT_DISABLE),
+            /* CLI */ 0x58 => self.status.remove(CpuFlags::INTERRUPT_DISABLE),
 
-                /* CLV */ 0xb8 => self.status.remove(CpuFlags::OVERFLOW),
+            /* CLV */ 0xb8 => self.status.remove(CpuFlags::OVERFLOW),
 
-                /* CLC */ 0x18 => self.clear_carry_flag(),
+            /* CLC */ 0x18 => self.clear_carry_flag(),
 
-                /* SEC */ 0x38 => self.set_carry_flag(),
+            /* SEC */ 0x38 => self.set_carry_flag(),
 
-                /* SEI */ 0x78 => self.status.insert(CpuFlags::INTERRUPT_DISABLE),
+            /* SEI */ 0x78 => self.status.insert(CpuFlags::INTERRUPT_DISABLE),
 
-                /* SED */ 0xf8 => self.status.insert(CpuFlags::DECIMAL_MODE),
+            /* SED */ 0xf8 => self.status.insert(CpuFlags::DECIMAL_MODE),
 
-                /* PHA */ 0x48 => self.stack_push(self.register_a),
+            /* PHA */ 0x48 => self.stack_push(self.register_a),
 
-                /* PLA */
-                0x68 => {
-                    self.pla();
-                }
-
-                /* PHP */
-                0x08 => {
-                    self.php();
-                }
+            /* PLA */
+            0x68 => {
+                self.pla();
+            }
 
-                /* PLP */
-                0x28 => {
-                    self.plp();
-                }
+            /* PHP */
+            0x08 => {
+                self.php();
+            }
 
-                /* ADC */
-                0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => {
-                    self.adc(&opcode.mode);
-                }
+            /* PLP */
+            0x28 => {
+                self.plp();
+            }
 
-                /* SBC */
-                0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => {
-                    self.sbc(&opcode.mode);
-                }
+            /* ADC */
+            0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => {
+                self.adc(&opcode.mode);
+            }
 
-                /* AND */
-                0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
-                    self.and(&opcode.mode);
-                }
+            /* SBC */
+            0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => {
+                self.sbc(&opcode.mode);
+            }
 
-                /* EOR */
-                0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
-                    self.eor(&opcode.mode);
-                }
+            /* AND */
+            0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
+                self.and(&opcode.mode);
+            }
 
-                /* ORA */
-                0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
-                    self.ora(&opcode.mode);
-                }
+            /* EOR */
+            0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
+                self.eor(&opcode.mode);
+            }
 
-                /* LSR */ 0x4a => self.lsr_accumulator(),
+            /* ORA */
+            0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
+                self.ora(&opcode.mode);
+            }
 
-                /* LSR */
-                0x46 | 0x56 | 0x4e | 0x5e => {
-                    self.lsr(&opcode.mode);
-                }
+            /* LSR */ 0x4a => self.lsr_accumulator(),
 
-                /*ASL*/ 0x0a => self.asl_accumulator(),
+            /* LSR */
+            0x46 | 0x56 | 0x4e | 0x5e => {
+                self.lsr(&opcode.mode);
+            }
 
-                /* ASL */
-                0x06 | 0x16 | 0x0e | 0x1e => {
-                    self.asl(&opcode.mode);
-                }
+            /*ASL*/ 0x0a => self.asl_accumulator(),
 
-                /*ROL*/ 0x2a => self.rol_accumulator(),
+            /* ASL */
+            0x06 | 0x16 | 0x0e | 0x1e => {
+                self.asl(&opcode.mode);
+            }
 
-                /* ROL */
-                0x26 | 0x36 | 0x2e | 0x3e => {
-                    self.rol(&opcode.mode);
-                }
+            /*ROL*/ 0x2a => self.rol_accumulator(),
 
-                /* ROR */ 0x6a => self.ror_accumulator(),
+            /* ROL */
+            0x26 | 0x36 | 0x2e | 0x3e => {
+                self.rol(&opcode.mode);
+            }
 
-                /* ROR */
-                0x66 | 0x76 | 0x6e | 0x7e => {
-                    self.ror(&opcode.mode);
-                }
+            /* ROR */ 0x6a => self.ror_accumulator(),
 
-                /* INC */
-                0xe6 | 0xf6 | 0xee | 0xfe => {
-                    self.inc(&opcode.mode);
-                }
+            /* ROR */
+            0x66 | 0x76 | 0x6e | 0x7e => {
+                self.ror(&opcode.mode);
+            }
 
-                /* INY */
-                0xc8 => self.iny(),
+            /* INC */
+            0xe6 | 0xf6 | 0xee | 0xfe => {
+                self.inc(&opcode.mode);
+            }
 
-                /* DEC */
-                0xc6 | 0xd6 | 0xce | 0xde => {
-                    self.dec(&opcode.mode);
-                }
+            /* INY */
+            0xc8 => self.iny(),
 
-                /* DEX */
-                0xca => {
-                    self.dex();
-                }
+            /* DEC */
+            0xc6 | 0xd6 | 0xce | 0xde => {
+                self.dec(&opcode.mode);
+            }
 
-                /* DEY */
-                0x88 => {
-                    self.dey();
-                }
+            /* DEX */
+            0xca => {
+                self.dex();
+            }
 
-                /* CMP */
-                0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
-                    self.compare(&opcode.mode, self.register_a);
-                }
+            /* DEY */
+            0x88 => {
+                self.dey();
+            }
 
-                /* CPY */
-                0xc0 | 0xc4 | 0xcc => {
-                    self.compare(&opcode.mode, self.register_y);
-                }
+            /* CMP */
+            0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
+                self.compare(&opcode.mode, self.register_a);
+            }
 
-                /* CPX */
-                0xe0 | 0xe4 | 0xec => self.compare(&opcode.mode, self.register_x),
+            /* CPY */
+            0xc0 | 0xc4 | 0xcc => {
+                self.compare(&opcode.mode, self.register_y);
+            }
 
-                /* JMP Absolute */
-                0x4c => {
-                    let mem_address = self.mem_read_u16(self.program_counter);
-                    self.program_counter = mem_address;
-                }
+            /* CPX */
+            0xe0 | 0xe4 | 0xec => self.compare(&opcode.mode, self.register_x),
 
-                /* JMP Indirect */
-                0x6c => {
-                    let mem_address = self.mem_read_u16(self.program_counter);
-                    // let indirect_ref = self.mem_read_u16(mem_address);
-                    //6502 bug mode with with page boundary:
-                    //  if address $3000 contains $40, $30FF contains $80, and $3100 contains $50,
-                    // the result of JMP ($30FF) will be a transfer of control to $4080 rather than $5080 as you intended
-                    // i.e. the 6502 took the low byte of the address from $30FF and the high byte from $3000
-
-                    let indirect_ref = if mem_address & 0x00FF == 0x00FF {
-                        let lo = self.mem_read(mem_address);
-                        let hi = self.mem_read(mem_address & 0xFF00);
-                        (hi as u16) << 8 | (lo as u16)
-                    } else {
-                        self.mem_read_u16(mem_address)
-                    };
-
-                    self.program_counter = indirect_ref;
-                }
+            /* JMP Absolute */
+            0x4c => {
+                let mem_address = self.mem_read_u16(self.program_counter);
+                self.program_counter = mem_address;
+            }
 
-                /* JSR */
-                0x20 => {
-                    self.stack_push_u16(self.program_counter + 2 - 1);
-                    let target_address = self.mem_read_u16(self.program_counter);
-                    self.program_counter = target_address
-                }
+            /* JMP Indirect */
+            0x6c => {
+                let mem_address = self.mem_read_u16(self.program_counter);
+                // let indirect_ref = self.mem_read_u16(mem_address);
+                //6502 bug mode with with page boundary:
+                //  if address $3000 contains $40, $30FF contains $80, and $3100 contains $50,
+                // the result of JMP ($30FF) will be a transfer of control to $4080 rather than $5080 as you intended
+                // i.e. the 6502 took the low byte of the address from $30FF and the high byte from $3000
+
+                let indirect_ref = if mem_address & 0x00FF == 0x00FF {
+                    let lo = self.mem_read(mem_address);
+                    let hi = self.mem_read(mem_address & 0xFF00);
+                    (hi as u16) << 8 | (lo as u16)
+                } else {
+                    self.mem_read_u16(mem_address)
+                };
+
+                self.program_counter = indirect_ref;
+            }
 
-                /* RTS */
-                0x60 => {
-                    self.program_counter = self.stack_pop_u16() + 1;
-                }
+            /* JSR */
+            0x20 => {
+                self.stack_push_u16(self.program_counter + 2 - 1);
+                let target_address = self.mem_read_u16(self.program_counter);
+                self.program_counter = target_address
+            }
 
-                /* RTI */
-                0x40 => {
-                    self.status.bits = self.stack_pop();
-                    self.status.remove(CpuFlags::BREAK);
-                    self.status.insert(CpuFlags::BREAK2);
+            /* RTS */
+            0x60 => {
+                self.program_counter = self.stack_pop_u16() + 1;
+            }
 
-                    self.program_counter = self.stack_pop_u16();
-                }
+            /* RTI */
+            0x40 => {
+                self.status.bits = self.stack_pop();
+                self.status.remove(CpuFlags::BREAK);
+                self.status.insert(CpuFlags::BREAK2);
 
-                /* BNE */
-                0xd0 => {
-                    self.branch(!self.status.contains(CpuFlags::ZERO));
-                }
+                self.program_counter = self.stack_pop_u16();
+            }
 
-                /* BVS */
-                0x70 => {
-                    self.branch(self.status.contains(CpuFlags::OVERFLOW));
-                }
+            /* BNE */
+            0xd0 => {
+                self.branch(!self.status.contains(CpuFlags::ZERO));
+            }
 
-                /* BVC */
-                0x50 => {
-                    self.branch(!self.status.contains(CpuFlags::OVERFLOW));
-                }
+            /* BVS */
+            0x70 => {
+                self.branch(self.status.contains(CpuFlags::OVERFLOW));
+            }
 
-                /* BPL */
-                0x10 => {
-                    self.branch(!self.status.contains(CpuFlags::NEGATIV));
-                }
+            /* BVC */
+            0x50 => {
+                self.branch(!self.status.contains(CpuFlags::OVERFLOW));
+            }
 
-                /* BMI */
-                0x30 => {
-                    self.branch(self.status.contains(CpuFlags::NEGATIV));
-                }
+            /* BPL */
+            0x10 => {
+                self.branch(!self.status.contains(CpuFlags::NEGATIV));
+            }
 
-                /* BEQ */
-                0xf0 => {
-                    self.branch(self.status.contains(CpuFlags::ZERO));
-                }
+            /* BMI */
+            0x30 => {
+                self.branch(self.status.contains(CpuFlags::NEGATIV));
+            }
 
-                /* BCS */
-                0xb0 => {
-                    self.branch(self.status.contains(CpuFlags::CARRY));
-                }
+            /* BEQ */
+            0xf0 => {
+                self.branch(self.status.contains(CpuFlags::ZERO));
+            }
 
-                /* BCC */
-                0x90 => {
-                    self.branch(!self.status.contains(CpuFlags::CARRY));
-                }
+            /* BCS */
+            0xb0 => {
+                self.branch(self.status.contains(CpuFlags::CARRY));
+            }
 
-                /* BIT */
-                0x24 | 0x2c => {
-                    self.bit(&opcode.mode);
-                }
+            /* BCC */
+            0x90 => {
+                self.branch(!self.status.contains(CpuFlags::CARRY));
+            }
 
-                /* STA */
-                0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
-                    self.sta(&opcode.mode);
-                }
+            /* BIT */
+            0x24 | 0x2c => {
+                self.bit(&opcode.mode);
+            }
 
-                /* STX */
-                0x86 | 0x96 | 0x8e => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    self.mem_write(addr, self.register_x);
-                }
+            /* STA */
+            0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
+                self.sta(&opcode.mode);
+            }
 
-                /* STY */
-                0x84 | 0x94 | 0x8c => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    self.mem_write(addr, self.register_y);
-                }
+            /* STX */
+            0x86 | 0x96 | 0x8e => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                self.mem_write(addr, self.register_x);
+            }
 
-                /* LDX */
-                0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
-                    self.ldx(&opcode.mode);
-                }
+            /* STY */
+            0x84 | 0x94 | 0x8c => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                self.mem_write(addr, self.register_y);
+            }
 
-                /* LDY */
-                0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
-                    self.ldy(&opcode.mode);
-                }
+            /* LDX */
+            0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
+                self.ldx(&opcode.mode);
+            }
 
-                /* NOP */
-                0xea => {
-                    //do nothing
-                }
+            /* LDY */
+            0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
+                self.ldy(&opcode.mode);
+            }
 
-                /* TAY */
-                0xa8 => {
-                    self.register_y = self.register_a;
-                    self.update_zero_and_negative_flags(self.register_y);
-                }
+            /* NOP */
+            0xea => {
+                //do nothing
+            }
 
-                /* TSX */
-                0xba => {
-                    self.register_x = self.stack_pointer;
-                    self.update_zero_and_negative_flags(self.register_x);
-                }
+            /* TAY */
+            0xa8 => {
+                self.register_y = self.register_a;
+                self.update_zero_and_negative_flags(self.register_y);
+            }
 
-                /* TXA */
-                0x8a => {
-                    self.register_a = self.register_x;
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
+            /* TSX */
+            0xba => {
+                self.register_x = self.stack_pointer;
+                self.update_zero_and_negative_flags(self.register_x);
+            }
 
-                /* TXS */
-                0x9a => {
-                    self.stack_pointer = self.register_x;
-                }
+            /* TXA */
+            0x8a => {
+                self.register_a = self.register_x;
+                self.update_zero_and_negative_flags(self.register_a);
+            }
 
-                /* TYA */
-                0x98 => {
-                    self.register_a = self.register_y;
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
+            /* TXS */
+            0x9a => {
+                self.stack_pointer = self.register_x;
+            }
 
-                /* unofficial */
+            /* TYA */
+            0x98 => {
+                self.register_a = self.register_y;
+                self.update_zero_and_negative_flags(self.register_a);
+            }
 
-                /* DCP */
-                0xc7 | 0xd7 | 0xCF | 0xdF | 0xdb | 0xd3 | 0xc3 => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let mut data = self.mem_read(addr);
-                    data = data.wrapping_sub(1);
-                    self.mem_write(addr, data);
-                    // self._update_zero_and_negative_flags(data);
-                    if data <= self.register_a {
-                        self.status.insert(CpuFlags::CARRY);
-                    }
+            /* unofficial */
 
-                    self.update_zero_and_negative_flags(self.register_a.wrapping_sub(data));
+            /* DCP */
+            0xc7 | 0xd7 | 0xCF | 0xdF | 0xdb | 0xd3 | 0xc3 => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let mut data = self.mem_read(addr);
+                data = data.wrapping_sub(1);
+                self.mem_write(addr, data);
+                // self._update_zero_and_negative_flags(data);
+                if data <= self.register_a {
+                    self.status.insert(CpuFlags::CARRY);
                 }
 
-                /* RLA */
-                0x27 | 0x37 | 0x2F | 0x3F | 0x3b | 0x33 | 0x23 => {
-                    let data = self.rol(&opcode.mode);
-                    self.and_with_register_a(data);
-                }
+                self.update_zero_and_negative_flags(self.register_a.wrapping_sub(data));
+            }
 
-                /* SLO */ //todo tests
-                0x07 | 0x17 | 0x0F | 0x1f | 0x1b | 0x03 | 0x13 => {
-                    let data = self.asl(&opcode.mode);
-                    self.or_with_register_a(data);
-                }
+            /* RLA */
+            0x27 | 0x37 | 0x2F | 0x3F | 0x3b | 0x33 | 0x23 => {
+                let data = self.rol(&opcode.mode);
+                self.and_with_register_a(data);
+            }
 
-                /* SRE */ //todo tests
-                0x47 | 0x57 | 0x4F | 0x5f | 0x5b | 0x43 | 0x53 => {
-                    let data = self.lsr(&opcode.mode);
-                    self.xor_with_register_a(data);
-                }
+            /* SLO */ //todo tests
+            0x07 | 0x17 | 0x0F | 0x1f | 0x1b | 0x03 | 0x13 => {
+                let data = self.asl(&opcode.mode);
+                self.or_with_register_a(data);
+            }
 
-                /* SKB */
-                0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 => {
-                    /* 2 byte NOP (immediate ) */
-                    // todo: might be worth doing the read
-                }
+            /* SRE */ //todo tests
+            0x47 | 0x57 | 0x4F | 0x5f | 0x5b | 0x43 | 0x53 => {
+                let data = self.lsr(&opcode.mode);
+                self.xor_with_register_a(data);
+            }
 
-                /* AXS */
-                0xCB => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    let x_and_a = self.register_x & self.register_a;
-                    let result = x_and_a.wrapping_sub(data);
+            /* SKB */
+            0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 => {
+                /* 2 byte NOP (immediate ) */
+                // todo: might be worth doing the read
+            }
 
-                    if data <= x_and_a {
-                        self.status.insert(CpuFlags::CARRY);
-                    }
-                    self.update_zero_and_negative_flags(result);
+            /* AXS */
+            0xCB => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                let x_and_a = self.register_x & self.register_a;
+                let result = x_and_a.wrapping_sub(data);
 
-                    self.register_x = result;
+                if data <= x_and_a {
+                    self.status.insert(CpuFlags::CARRY);
                 }
+                self.update_zero_and_negative_flags(result);
 
-                /* ARR */
-                0x6B => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.and_with_register_a(data);
-                    self.ror_accumulator();
-                    //todo: registers
-                    let result = self.register_a;
-                    let bit_5 = (result >> 5) & 1;
-                    let bit_6 = (result >> 6) & 1;
-
-                    if bit_6 == 1 {
-                        self.status.insert(CpuFlags::CARRY)
-                    } else {
-                        self.status.remove(CpuFlags::CARRY)
-                    }
-
-                    if bit_5 ^ bit_6 == 1 {
-                        self.status.insert(CpuFlags::OVERFLOW);
-                    } else {
-                        self.status.remove(CpuFlags::OVERFLOW);
-                    }
-
-                    self.update_zero_and_negative_flags(result);
-                }
+                self.register_x = result;
+            }
 
-                /* unofficial SBC */
-                0xeb => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.sub_from_register_a(data);
-                }
+            /* ARR */
+            0x6B => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.and_with_register_a(data);
+                self.ror_accumulator();
+                //todo: registers
+                let result = self.register_a;
+                let bit_5 = (result >> 5) & 1;
+                let bit_6 = (result >> 6) & 1;
 
-                /* ANC */
-                0x0b | 0x2b => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.and_with_register_a(data);
-                    if self.status.contains(CpuFlags::NEGATIV) {
-                        self.status.insert(CpuFlags::CARRY);
-                    } else {
-                        self.status.remove(CpuFlags::CARRY);
-                    }
+                if bit_6 == 1 {
+                    self.status.insert(CpuFlags::CARRY)
+                } else {
+                    self.status.remove(CpuFlags::CARRY)
                 }
 
-                /* ALR */
-                0x4b => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.and_with_register_a(data);
-                    self.lsr_accumulator();
+                if bit_5 ^ bit_6 == 1 {
+                    self.status.insert(CpuFlags::OVERFLOW);
+                } else {
+                    self.status.remove(CpuFlags::OVERFLOW);
                 }
 
-                //todo: test for everything below
-
-                /* NOP read */
-                0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x0c | 0x1c
-                | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => {
-                    let (addr, page_cross) = self.get_operand_address(&opcode.mode);
-                    let _data = self.mem_read(addr);
-                    if page_cross {
-                        self.bus.tick(1);
-                    }
-                    /* do nothing */
-                }
+                self.update_zero_and_negative_flags(result);
+            }
 
-                /* RRA */
-                0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => {
-                    let data = self.ror(&opcode.mode);
-                    self.add_to_register_a(data);
-                }
+            /* unofficial SBC */
+            0xeb => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.sub_from_register_a(data);
+            }
 
-                /* ISB */
-                0xe7 | 0xf7 | 0xef | 0xff | 0xfb | 0xe3 | 0xf3 => {
-                    let data = self.inc(&opcode.mode);
-                    self.sub_from_register_a(data);
+            /* ANC */
+            0x0b | 0x2b => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.and_with_register_a(data);
+                if self.status.contains(CpuFlags::NEGATIV) {
+                    self.status.insert(CpuFlags::CARRY);
+                } else {
+                    self.status.remove(CpuFlags::CARRY);
                 }
+            }
 
-                /* NOPs */
-                0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2
-                | 0xf2 => { /* do nothing */ }
+            /* ALR */
+            0x4b => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.and_with_register_a(data);
+                self.lsr_accumulator();
+            }
 
-                0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => { /* do nothing */ }
+            //todo: test for everything below
 
-                /* LAX */
-                0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.set_register_a(data);
-                    self.register_x = self.register_a;
+            /* NOP read */
+            0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x0c | 0x1c
+            | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => {
+                let (addr, page_cross) = self.get_operand_address(&opcode.mode);
+                let _data = self.mem_read(addr);
+                if page_cross {
+                    self.bus.tick(1);
                 }
+                /* do nothing */
+            }
 
-                /* SAX */
-                0x87 | 0x97 | 0x8f | 0x83 => {
-                    let data = self.register_a & self.register_x;
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    self.mem_write(addr, data);
-                }
+            /* RRA */
+            0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => {
+                let data = self.ror(&opcode.mode);
+                self.add_to_register_a(data);
+            }
 
-                /* LXA */
-                0xab => {
-                    self.lda(&opcode.mode);
-                    self.tax();
-                }
+            /* ISB */
+            0xe7 | 0xf7 | 0xef | 0xff | 0xfb | 0xe3 | 0xf3 => {
+                let data = self.inc(&opcode.mode);
+                self.sub_from_register_a(data);
+            }
 
-                /* XAA */
-                0x8b => {
-                    self.register_a = self.register_x;
-                    self.update_zero_and_negative_flags(self.register_a);
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.and_with_register_a(data);
-                }
+            /* NOPs */
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2
+            | 0xf2 => { /* do nothing */ }
 
-                /* LAS */
-                0xbb => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let mut data = self.mem_read(addr);
-                    data = data & self.stack_pointer;
-                    self.register_a = data;
-                    self.register_x = data;
-                    self.stack_pointer = data;
-                    self.update_zero_and_negative_flags(data);
-                }
+            0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => { /* do nothing */ }
 
-                /* TAS */
-                0x9b => {
-                    let data = self.register_a & self.register_x;
-                    self.stack_pointer = data;
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_y as u16;
+            /* LAX */
+            0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.set_register_a(data);
+                self.register_x = self.register_a;
+            }
 
-                    let data = ((mem_address >> 8) as u8 + 1) & self.stack_pointer;
-                    self.mem_write(mem_address, data)
-                }
+            /* SAX */
+            0x87 | 0x97 | 0x8f | 0x83 => {
+                let data = self.register_a & self.register_x;
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                self.mem_write(addr, data);
+            }
 
-                /* AHX  Indirect Y */
-                0x93 => {
-                    let pos: u8 = self.mem_read(self.program_counter);
-                    let mem_address = self.mem_read_u16(pos as u16) + self.register_y as u16;
-                    let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
-                    self.mem_write(mem_address, data)
-                }
+            /* LXA */
+            0xab => {
+                self.lda(&opcode.mode);
+                self.tax();
+            }
 
-                /* AHX Absolute Y*/
-                0x9f => {
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_y as u16;
+            /* XAA */
+            0x8b => {
+                self.register_a = self.register_x;
+                self.update_zero_and_negative_flags(self.register_a);
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.and_with_register_a(data);
+            }
 
-                    let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
-                    self.mem_write(mem_address, data)
-                }
+            /* LAS */
+            0xbb => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let mut data = self.mem_read(addr);
+                data = data & self.stack_pointer;
+                self.register_a = data;
+                self.register_x = data;
+                self.stack_pointer = data;
+                self.update_zero_and_negative_flags(data);
+            }
 
-                /* SHX */
-                0x9e => {
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_y as u16;
+            /* TAS */
+            0x9b => {
+                let data = self.register_a & self.register_x;
+                self.stack_pointer = data;
+                let mem_address =
+                    self.mem_read_u16(self.program_counter) + self.register_y as u16;
 
-                    // todo if cross page boundry {
-                    //     mem_address &= (self.x as u16) << 8;
-                    // }
-                    let data = self.register_x & ((mem_address >> 8) as u8 + 1);
-                    self.mem_write(mem_address, data)
-                }
+                let data = ((mem_address >> 8) as u8 + 1) & self.stack_pointer;
+                self.mem_write(mem_address, data)
+            }
 
-                /* SHY */
-                0x9c => {
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_x as u16;
-                    let data = self.register_y & ((mem_address >> 8) as u8 + 1);
-                    self.mem_write(mem_address, data)
-                }
+            /* AHX  Indirect Y */
+            0x93 => {
+                let pos: u8 = self.mem_read(self.program_counter);
+                let mem_address = self.mem_read_u16(pos as u16) + self.register_y as u16;
+                let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
+                self.mem_write(mem_address, data)
             }
 
-            self.bus.tick(opcode.cycles);
+            /* AHX Absolute Y*/
+            0x9f => {
+                let mem_address =
+                    self.mem_read_u16(self.program_counter) + self.register_y as u16;
 
-            if program_counter_state == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
+                let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
+                self.mem_write(mem_address, data)
             }
+
+            /* SHX */
+            0x9e => {
+                let mem_address =
+                    self.mem_read_u16(self.program_counter) + self.register_y as u16;
+
+                // todo if cross page boundry {
+                //     mem_address &= (self.x as u16) << 8;
+                // }
+                let data = self.register_x & ((mem_address >> 8) as u8 + 1);
+                self.mem_write(mem_address, data)
+            }
+
+            /* SHY */
+            0x9c => {
+                let mem_address =
+                    self.mem_read_u16(self.program_counter) + self.register_x as u16;
+                let data = self.register_y & ((mem_address >> 8) as u8 + 1);
+                self.mem_write(mem_address, data)
+            }
+        }
+
+        self.bus.tick(opcode.cycles);
+
+        if program_counter_state == self.program_counter {
+            self.program_counter += (opcode.len - 1) as u16;
         }
+        true
     }
 }
 
@@ -1214,7 +1231,7 @@ mod test {
 
     #[test]
     fn test_0xa9_lda_immediate_load_data() {
-        let bus = Bus::new(test::test_rom_containing(vec![0xa9, 0x05, 0x00]), |_ppu, _joypad| {});
+        let bus = Bus::new(test::test_rom_containing(vec![0xa9, 0x05, 0x00]), |_ppu, _apu, _joypad| {});
         let mut cpu = CPU::new(bus);
 
         cpu.run();
@@ -1226,7 +1243,7 @@ mod test {
 
     #[test]
     fn test_0xaa_tax_move_a_to_x() {
-        let bus = Bus::new(test::test_rom_containing(vec![0xaa, 0x00]), |_ppu, _joypad| {});
+        let bus = Bus::new(test::test_rom_containing(vec![0xaa, 0x00]), |_ppu, _apu, _joypad| {});
         let mut cpu = CPU::new(bus);
         cpu.register_a = 10;
 
@@ -1237,7 +1254,7 @@ mod test {
 
     #[test]
     fn test_5_ops_working_together() {
-        let bus = Bus::new(test::test_rom_containing(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]), |_ppu, _joypad| {});
+        let bus = Bus::new(test::test_rom_containing(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]), |_ppu, _apu, _joypad| {});
         let mut cpu = CPU::new(bus);
 
         cpu.run();
@@ -1247,7 +1264,7 @@ mod test {
 
     #[test]
     fn test_inx_overflow() {
-        let bus = Bus::new(test::test_rom_containing(vec![0xe8, 0xe8, 0x00]), |_ppu, _joypad| {});
+        let bus = Bus::new(test::test_rom_containing(vec![0xe8, 0xe8, 0x00]), |_ppu, _apu, _joypad| {});
         let mut cpu = CPU::new(bus);
         cpu.register_x = 0xff;
 
@@ -1258,7 +1275,7 @@ mod test {
 
     #[test]
     fn test_lda_from_memory() {
-        let bus = Bus::new(test::test_rom_containing(vec![0xa5, 0x10, 0x00]), |_ppu, _joypad| {});
+        let bus = Bus::new(test::test_rom_containing(vec![0xa5, 0x10, 0x00]), |_ppu, _apu, _joypad| {});
         let mut cpu = CPU::new(bus);
         cpu.mem_write(0x10, 0x55);
 
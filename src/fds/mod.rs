@@ -0,0 +1,217 @@
+// Famicom Disk System disk images. A .fds file holds every 65500 byte disk
+// side as its blocks back to back, optionally behind a 16 byte fwNES header.
+// The drive reads the disk as it is on the magnetic surface, with a gap
+// before every block and a CRC after it, so those are put back on loading.
+// http://wiki.nesdev.com/w/index.php/FDS_file_format
+// http://wiki.nesdev.com/w/index.php/FDS_disk_format
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const FDS_TAG: [u8; 4] = [0x46, 0x44, 0x53, 0x1A];
+const FWNES_HEADER_SIZE: usize = 16;
+const DISK_VERIFICATION: &[u8] = b"*NINTENDO-HVC*";
+pub const SIDE_SIZE: usize = 65500;
+// 28300 bits of gap before the first block, 976 after every block
+const LEADING_GAP: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+// the bit that ends a gap, and the CRC every block is followed by, which the
+// drive doesn't check
+const GAP_END: u8 = 0x80;
+const BLOCK_CRC: [u8; 2] = [0x4D, 0x62];
+// CPU cycles a switched side stays out of the drive, so the BIOS sees it go
+const SWITCH_DELAY: u32 = 1_000_000;
+
+pub fn is_disk_image(raw: &[u8]) -> bool {
+    raw.starts_with(&FDS_TAG) || raw.get(1..1 + DISK_VERIFICATION.len()) == Some(DISK_VERIFICATION)
+}
+
+/// "disk 1 side A" for side 0
+pub fn side_name(side: usize) -> String {
+    format!("disk {} side {}", side / 2 + 1, if side.is_multiple_of(2) { 'A' } else { 'B' })
+}
+
+pub struct Disk {
+    // as the drive sees them, gaps and all
+    sides: Vec<Vec<u8>>,
+    side: usize,
+    inserted: bool,
+    // CPU cycles until `side` goes in, after a switch
+    switch_delay: u32,
+}
+
+/// The disk is in the RAM adapter's drive and the player's hands
+pub type SharedDisk = Rc<RefCell<Disk>>;
+
+impl Disk {
+    /// Disk from a .fds image, side A of the first disk inserted
+    pub fn new(raw: &[u8]) -> Result<Disk, String> {
+        let data = if raw.starts_with(&FDS_TAG) { &raw[FWNES_HEADER_SIZE.min(raw.len())..] } else { raw };
+        if data.is_empty() || data.len() % SIDE_SIZE != 0 {
+            return Err(format!("FDS image should be a multiple of {} bytes, it's {}", SIDE_SIZE, data.len()));
+        }
+        let sides = data.chunks(SIDE_SIZE).map(add_gaps).collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Disk { sides, side: 0, inserted: true, switch_delay: 0 })
+    }
+
+    pub fn sides(&self) -> usize {
+        self.sides.len()
+    }
+
+    /// Side in the drive, `None` while the drive is empty
+    pub fn inserted(&self) -> Option<usize> {
+        if self.inserted {
+            Some(self.side)
+        } else {
+            None
+        }
+    }
+
+    pub fn eject(&mut self) {
+        self.inserted = false;
+        self.switch_delay = 0;
+    }
+
+    /// Puts the last side back in
+    pub fn insert(&mut self) {
+        self.inserted = true;
+        self.switch_delay = 0;
+    }
+
+    /// Takes the disk out and puts the next side in a little later. Returns
+    /// the side going in.
+    pub fn switch_side(&mut self) -> usize {
+        self.side = (self.side + 1) % self.sides.len();
+        self.inserted = false;
+        self.switch_delay = SWITCH_DELAY;
+        self.side
+    }
+
+    /// Advances one CPU cycle
+    pub fn tick(&mut self) {
+        if self.switch_delay > 0 {
+            self.switch_delay -= 1;
+            self.inserted = self.switch_delay == 0;
+        }
+    }
+
+    /// Length of the inserted side as the drive reads it
+    pub fn side_len(&self) -> usize {
+        self.inserted().map_or(0, |side| self.sides[side].len())
+    }
+
+    pub fn read(&self, position: usize) -> u8 {
+        self.inserted().and_then(|side| self.sides[side].get(position).copied()).unwrap_or(0)
+    }
+
+    pub fn write(&mut self, position: usize, data: u8) {
+        if let Some(side) = self.inserted() {
+            if let Some(byte) = self.sides[side].get_mut(position) {
+                *byte = data;
+            }
+        }
+    }
+}
+
+// The blocks of a side with the gaps and CRCs in between. Blocks 1-3 have
+// fixed sizes, a file's data (4) is as long as its header (3) says.
+fn add_gaps(side: &[u8]) -> Result<Vec<u8>, String> {
+    if !side[1..].starts_with(DISK_VERIFICATION) {
+        return Err("FDS disk side doesn't start with a disk info block".to_string());
+    }
+    let mut raw = vec![0; LEADING_GAP];
+    let mut file_size = 0;
+    let mut pos = 0;
+    while pos < side.len() {
+        let len = match side[pos] {
+            1 => 56,
+            2 => 2,
+            3 => 16,
+            4 => 1 + file_size,
+            // unused space after the last file
+            _ => break,
+        };
+        let block = side.get(pos..pos + len).ok_or("FDS disk side is truncated")?;
+        if block[0] == 3 {
+            file_size = u16::from_le_bytes([block[13], block[14]]) as usize;
+        }
+        raw.push(GAP_END);
+        raw.extend_from_slice(block);
+        raw.extend_from_slice(&BLOCK_CRC);
+        raw.extend(std::iter::repeat_n(0, BLOCK_GAP));
+        pos += len;
+    }
+    raw.resize(raw.len().max(LEADING_GAP + SIDE_SIZE), 0);
+    Ok(raw)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    /// A side with one file holding `data`
+    pub fn test_side(data: &[u8]) -> Vec<u8> {
+        let mut side = vec![0x01];
+        side.extend_from_slice(DISK_VERIFICATION);
+        side.resize(56, 0);
+        side.extend_from_slice(&[0x02, 1]);
+        let mut header = vec![0x03, 0, 0];
+        header.extend_from_slice(b"FILE    ");
+        header.extend_from_slice(&0x6000u16.to_le_bytes());
+        header.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        header.push(0);
+        side.extend(header);
+        side.push(0x04);
+        side.extend_from_slice(data);
+        side.resize(SIDE_SIZE, 0);
+        side
+    }
+
+    #[test]
+    fn test_image_with_gaps() {
+        let side = test_side(&[0xAA, 0xBB]);
+        let mut image = FDS_TAG.to_vec();
+        image.push(2);
+        image.resize(FWNES_HEADER_SIZE, 0);
+        image.extend(&side);
+        image.extend(&side);
+        assert!(is_disk_image(&image));
+        assert!(is_disk_image(&side));
+        assert!(!is_disk_image(b"NES\x1A"));
+
+        let disk = Disk::new(&image).unwrap();
+        assert_eq!(disk.sides(), 2);
+        assert_eq!(disk.read(LEADING_GAP - 1), 0);
+        assert_eq!(disk.read(LEADING_GAP), GAP_END);
+        assert_eq!(disk.read(LEADING_GAP + 1), 0x01);
+        // the second block after the first, its CRC and a gap
+        let second = LEADING_GAP + 1 + 56 + 2 + BLOCK_GAP;
+        assert_eq!((disk.read(second), disk.read(second + 1)), (GAP_END, 0x02));
+        let data = second + 1 + 2 + 2 + BLOCK_GAP + 1 + 16 + 2 + BLOCK_GAP;
+        assert_eq!(disk.read(data + 1), 0x04);
+        assert_eq!(disk.read(data + 2), 0xAA);
+
+        // headerless images are plain sides, which have to be whole
+        assert_eq!(Disk::new(&side).unwrap().sides(), 1);
+        assert!(Disk::new(&side[..1000]).is_err());
+    }
+
+    #[test]
+    fn test_switch_side() {
+        let side = test_side(&[]);
+        let mut disk = Disk::new(&[side.clone(), side].concat()).unwrap();
+        assert_eq!(disk.switch_side(), 1);
+        assert_eq!(disk.inserted(), None);
+        for _ in 0..SWITCH_DELAY {
+            disk.tick();
+        }
+        assert_eq!(disk.inserted(), Some(1));
+        assert_eq!(side_name(1), "disk 1 side B");
+
+        disk.eject();
+        assert_eq!(disk.side_len(), 0);
+        disk.insert();
+        assert_eq!(disk.inserted(), Some(1));
+        assert_eq!(disk.switch_side(), 0);
+    }
+}
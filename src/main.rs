@@ -7,21 +7,39 @@ pub mod ppu;
 pub mod render;
 pub mod trace;
 pub mod apu;
+pub mod region;
+pub mod mapper;
+pub mod nsf;
+pub mod fds;
 
+use apu::APU;
 use bus::Bus;
 use cartridge::Rom;
 use cpu::CPU;
+use mapper::Mapper;
+use nsf::player::NsfPlayer;
+use nsf::Nsf;
 use ppu::NesPPU;
+use region::Region;
+use render::display::{DisplayMode, Overscan};
 use render::frame::Frame;
+use render::ntsc::{NtscFilter, NtscPreset};
+use render::palette::{ColorBlindness, NtscParams, Palette};
 use clap::Parser;
 // use trace::trace;
 
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
-use sdl2::pixels::PixelFormatEnum;
+use sdl2::keyboard::Mod;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::{Point, Rect};
+use sdl2::render::WindowCanvas;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::path::Path;
+use std::rc::Rc;
 use std::io::{self, Write};
+use std::time::{Duration, Instant};
 
 #[macro_use]
 extern crate lazy_static;
@@ -45,10 +63,134 @@ struct Args {
     /// Disable audio
     #[arg(short, long)]
     no_audio: bool,
+
+    /// Audio output sample rate in Hz
+    #[arg(long, default_value_t = apu::DEFAULT_SAMPLE_RATE, value_parser = clap::value_parser!(u32).range(8000..=192000))]
+    sample_rate: u32,
+
+    /// Output the unfiltered mixer level, without the console's high-pass and low-pass filters
+    #[arg(long)]
+    raw_audio: bool,
+
+    /// Record the audio output to a WAV file; F9 toggles recording while running
+    #[arg(long, value_name = "FILE")]
+    record_audio: Option<String>,
+
+    /// Also record every APU channel to its own WAV file next to the main one
+    #[arg(long)]
+    record_stems: bool,
+
+    /// Log the APU register writes to a VGM file, for playback in VGM players
+    #[arg(long, value_name = "FILE")]
+    record_vgm: Option<String>,
+
+    /// Transcribe the pulse, triangle and noise channels to a MIDI file
+    #[arg(long, value_name = "FILE")]
+    record_midi: Option<String>,
     
     /// Interactive ROM selection
     #[arg(short, long)]
     interactive: bool,
+
+    /// Palette file to use (.pal, 192 bytes or 1536 bytes with emphasis)
+    #[arg(long, value_name = "FILE", conflicts_with = "ntsc_palette")]
+    palette: Option<String>,
+
+    /// Generate the palette by decoding the NTSC signal
+    #[arg(long)]
+    ntsc_palette: bool,
+
+    /// NTSC palette hue rotation in degrees
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    hue: f32,
+
+    /// NTSC palette saturation
+    #[arg(long, default_value_t = 1.0)]
+    saturation: f32,
+
+    /// NTSC palette contrast
+    #[arg(long, default_value_t = 1.0)]
+    contrast: f32,
+
+    /// NTSC palette brightness
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    brightness: f32,
+
+    /// NTSC palette gamma
+    #[arg(long, default_value_t = 1.0)]
+    gamma: f32,
+
+    /// Adjust the palette for color vision deficiency (protanopia, deuteranopia, tritanopia)
+    #[arg(long, value_name = "TYPE")]
+    colorblind: Option<ColorBlindness>,
+
+    /// NTSC video filter (composite, svideo, rgb)
+    #[arg(long, value_name = "PRESET")]
+    ntsc_filter: Option<NtscPreset>,
+
+    /// Console region (ntsc, pal, dendy). Detected from the ROM header or file name if not set
+    #[arg(long)]
+    region: Option<Region>,
+
+    /// Overscan to crop as top,bottom,left,right pixels, e.g. 8,8,0,0
+    #[arg(long, value_name = "T,B,L,R", default_value = "0,0,0,0")]
+    crop: Overscan,
+
+    /// Pixel aspect (square, 8:7, 4:3)
+    #[arg(long, value_name = "MODE", default_value = "square")]
+    display: DisplayMode,
+
+    /// Window scale factor
+    #[arg(long, default_value_t = 2)]
+    scale: u32,
+
+    /// Open a debug window showing all four nametables
+    #[arg(long)]
+    nametables: bool,
+
+    /// Render an NSF track to a WAV file without opening a window
+    #[arg(long, value_name = "FILE")]
+    nsf_render: Option<String>,
+
+    /// NSF track to start with (1-based); the file's starting track if not set
+    #[arg(long)]
+    track: Option<u8>,
+
+    /// Seconds of an NSF track to render; the track's length from the file, or 3 minutes
+    #[arg(long, value_name = "SECONDS")]
+    duration: Option<f64>,
+
+    /// Famicom Disk System BIOS, needed to run .fds disk images
+    #[arg(long, value_name = "FILE", default_value = "disksys.rom")]
+    fds_bios: String,
+}
+
+// NSF tracks without a length in the file are rendered for this long, in seconds
+const NSF_DEFAULT_DURATION: f64 = 180.0;
+
+fn ntsc_params(args: &Args) -> NtscParams {
+    NtscParams {
+        hue: args.hue,
+        saturation: args.saturation,
+        contrast: args.contrast,
+        brightness: args.brightness,
+        gamma: args.gamma,
+    }
+}
+
+fn load_palette(args: &Args) -> Result<Palette, String> {
+    let mut palette = if let Some(path) = &args.palette {
+        Palette::from_file(path)?
+    } else if args.ntsc_palette {
+        Palette::ntsc(&ntsc_params(args))
+    } else {
+        Palette::system()
+    };
+
+    if let Some(kind) = args.colorblind {
+        palette.daltonize(kind);
+    }
+    Ok(palette)
 }
 
 fn list_available_roms() -> Vec<String> {
@@ -74,7 +216,12 @@ fn print_available_roms() {
     }
     
     for (i, rom) in roms.iter().enumerate() {
-        println!("  {}: {}", i + 1, rom);
+        let cartridge = std::fs::read(rom)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| Rom::new(&bytes))
+            .map(|rom| mapper::describe(&rom))
+            .unwrap_or_else(|e| format!("unreadable: {}", e));
+        println!("  {}: {} - {}", i + 1, rom, cartridge);
     }
 }
 
@@ -123,6 +270,228 @@ fn interactive_rom_selection() -> Option<String> {
     }
 }
 
+// audio channel controlled by the number keys 1-9
+fn channel_key(keycode: Keycode) -> Option<usize> {
+    match keycode {
+        Keycode::Num1 => Some(0),
+        Keycode::Num2 => Some(1),
+        Keycode::Num3 => Some(2),
+        Keycode::Num4 => Some(3),
+        Keycode::Num5 => Some(4),
+        Keycode::Num6 => Some(5),
+        Keycode::Num7 => Some(6),
+        Keycode::Num8 => Some(7),
+        Keycode::Num9 => Some(8),
+        _ => None,
+    }
+}
+
+// mute/solo/gain state of every audio channel, drawn in picture pixels
+fn draw_channel_overlay(canvas: &mut WindowCanvas, apu: &APU, selected: usize) {
+    let controls = apu.channel_controls();
+    let lines: Vec<(String, Color)> = apu
+        .channel_names()
+        .iter()
+        .enumerate()
+        .map(|(channel, name)| {
+            let state = if controls.solo() == Some(channel) {
+                "SOLO"
+            } else if controls.is_muted(channel) {
+                "MUTE"
+            } else {
+                ""
+            };
+            let text = format!(
+                "{}{} {:<8} {:>3}% {}",
+                if channel == selected { ">" } else { " " },
+                channel + 1,
+                name,
+                (controls.gain(channel) * 100.0).round(),
+                state
+            );
+            let color = if controls.effective_gain(channel) == 0.0 { Color::GRAY } else { Color::WHITE };
+            (text, color)
+        })
+        .collect();
+
+    let line_height = render::font::GLYPH_HEIGHT + 2;
+    let width = lines.iter().map(|(text, _)| render::font::text_width(text)).max().unwrap_or(0);
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 160));
+    canvas
+        .fill_rect(Rect::new(2, 2, (width + 4) as u32, (lines.len() as i32 * line_height + 2) as u32))
+        .unwrap();
+
+    for (row, (text, color)) in lines.iter().enumerate() {
+        let mut points = Vec::new();
+        render::font::draw_text(text, 4, 4 + row as i32 * line_height, |x, y| points.push(Point::new(x, y)));
+        canvas.set_draw_color(*color);
+        canvas.draw_points(&points[..]).unwrap();
+    }
+}
+
+// the RAM adapter running `bios_file` with the disk of `image` in its drive
+fn load_disk(image: &[u8], bios_file: &str) -> Result<(Box<dyn Mapper>, fds::SharedDisk), String> {
+    let bios = std::fs::read(bios_file)
+        .map_err(|e| format!("can't read the FDS BIOS {}: {} (pass it with --fds-bios)", bios_file, e))?;
+    let disk = Rc::new(RefCell::new(fds::Disk::new(image)?));
+    let ram_adapter = mapper::fds::Fds::new(bios, disk.clone())?;
+    Ok((Box::new(ram_adapter), disk))
+}
+
+fn is_nsf_file(file: &str) -> bool {
+    let extension = Path::new(file).extension().map(|e| e.to_string_lossy().to_ascii_lowercase());
+    matches!(extension.as_deref(), Some("nsf") | Some("nsfe"))
+}
+
+// "m:ss"
+fn format_time(time: Duration) -> String {
+    let seconds = time.as_secs();
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+// tune title, track and time, drawn in picture pixels
+fn draw_nsf_info(canvas: &mut WindowCanvas, player: &NsfPlayer) {
+    let nsf = player.nsf();
+    let track = player.track();
+    let mut time = format_time(player.elapsed());
+    if let Some(length) = nsf.track_length_ms(track) {
+        time = format!("{} / {}", time, format_time(Duration::from_millis(length as u64)));
+    }
+    let lines = [
+        nsf.title.clone(),
+        nsf.artist.clone(),
+        nsf.copyright.clone(),
+        String::new(),
+        format!("TRACK {}/{}", track, nsf.total_songs),
+        nsf.track_title(track).unwrap_or_default().to_string(),
+        time,
+    ];
+
+    canvas.set_draw_color(Color::BLACK);
+    canvas.clear();
+    let mut points = Vec::new();
+    for (row, line) in lines.iter().enumerate() {
+        render::font::draw_text(line, 16, 16 + row as i32 * (render::font::GLYPH_HEIGHT + 4), |x, y| {
+            points.push(Point::new(x, y))
+        });
+    }
+    canvas.set_draw_color(Color::WHITE);
+    canvas.draw_points(&points[..]).unwrap();
+    canvas.present();
+}
+
+// plays an NSF file: to a WAV file with --nsf-render, in a small player window otherwise
+fn run_nsf(args: &Args, file: &str) -> Result<(), String> {
+    let bytes = std::fs::read(file).map_err(|e| format!("Can't read {}: {}", file, e))?;
+    let nsf = Nsf::new(&bytes)?;
+    let region = args.region.or(nsf.region).unwrap_or_default();
+    println!("Region: {:?}", region);
+    println!("{} - {} ({} tracks)", nsf.title, nsf.artist, nsf.total_songs);
+
+    let first_track = args.track.unwrap_or(nsf.starting_song);
+    let mut player = NsfPlayer::new(nsf, region);
+    player.apu_mut().set_raw_output(args.raw_audio);
+    player.apu_mut().set_sample_rate(args.sample_rate);
+    // from before the init routine, which sets the APU up
+    if let Some(path) = &args.record_vgm {
+        player.apu_mut().start_vgm_log(path)?;
+        println!("Logging APU writes to {}", path);
+    }
+    if let Some(path) = &args.record_midi {
+        player.apu_mut().start_midi_export(path)?;
+        println!("Transcribing to {}", path);
+    }
+
+    if let Some(path) = &args.nsf_render {
+        player.start_track(first_track);
+        let duration = args.duration.unwrap_or_else(|| {
+            let length = player.nsf().track_length_ms(player.track());
+            length.map_or(NSF_DEFAULT_DURATION, |ms| ms as f64 / 1000.0)
+        });
+        player.apu_mut().start_recording(path, args.record_stems)?;
+        player.run(duration);
+        player.apu_mut().stop_recording()?;
+        player.apu_mut().stop_vgm_log()?;
+        player.apu_mut().stop_midi_export()?;
+        println!("Rendered track {} ({:.1}s) to {}", player.track(), duration, path);
+        return Ok(());
+    }
+
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
+    let window = video_subsystem
+        .window(
+            &format!("NES Emulator - {}", file),
+            Frame::WIDTH as u32 * args.scale,
+            Frame::HEIGHT as u32 / 2 * args.scale,
+        )
+        .position_centered()
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+    canvas.set_scale(args.scale as f32, args.scale as f32)?;
+    let mut event_pump = sdl_context.event_pump()?;
+
+    if !args.no_audio {
+        match player.init_audio(&sdl_context, args.sample_rate) {
+            Ok(_) => println!("Audio initialized successfully"),
+            Err(e) => eprintln!("Failed to initialize audio: {}", e),
+        }
+    }
+    if let Some(path) = &args.record_audio {
+        player.apu_mut().start_recording(path, args.record_stems)?;
+        println!("Recording audio to {}", path);
+    }
+
+    player.start_track(first_track);
+    let frame_duration = Duration::from_secs_f64(1.0 / region.frame_rate());
+    let mut next_frame = Instant::now() + frame_duration;
+    loop {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => {
+                    player.apu_mut().stop_recording()?;
+                    player.apu_mut().stop_vgm_log()?;
+                    return player.apu_mut().stop_midi_export();
+                }
+                Event::KeyDown { keycode: Some(Keycode::Right), .. } => {
+                    let next = player.track() % player.nsf().total_songs + 1;
+                    player.start_track(next);
+                }
+                Event::KeyDown { keycode: Some(Keycode::Left), .. } => {
+                    let total = player.nsf().total_songs;
+                    let previous = (player.track() + total - 2) % total + 1;
+                    player.start_track(previous);
+                }
+                _ => {}
+            }
+        }
+
+        // move on once a track with a known length is over
+        let track = player.track();
+        if let Some(length) = player.nsf().track_length_ms(track) {
+            if player.elapsed() >= Duration::from_millis(length as u64) && track < player.nsf().total_songs {
+                player.start_track(track + 1);
+            }
+        }
+
+        player.run(frame_duration.as_secs_f64());
+        draw_nsf_info(&mut canvas, &player);
+
+        let now = Instant::now();
+        if next_frame > now {
+            std::thread::sleep(next_frame - now);
+            next_frame += frame_duration;
+        } else {
+            next_frame = now + frame_duration;
+        }
+    }
+}
+
 fn print_usage() {
     println!("NES Emulator Usage:");
     println!("  cargo run [ROM_FILE]");
@@ -136,11 +505,40 @@ fn print_usage() {
     println!("  Space: Select");
     println!("  Enter: Start");
     println!("  I: Pause/Resume");
+    println!("  F9: Start/stop audio recording");
+    println!("  1-9: Mute audio channel (Shift: solo)");
+    println!("  -/=: Lower/raise the last chosen channel's volume");
+    println!("  F8: Show/hide the audio channel overlay");
     println!("  Escape: Quit");
+    println!();
+    println!("Famicom Disk System:");
+    println!("  cargo run -- game.fds --fds-bios disksys.rom");
+    println!("  F5: Eject/insert the disk");
+    println!("  F6: Switch to the next disk side");
+    println!();
+    println!("NSF music:");
+    println!("  cargo run -- music.nsf --track 2");
+    println!("  Left/Right: Previous/next track");
+    println!("  cargo run -- music.nsf --nsf-render music.wav --duration 90");
+    println!();
+    println!("Palettes:");
+    println!("  cargo run -- Super.nes --palette fceux.pal");
+    println!("  cargo run -- Super.nes --ntsc-palette --hue -5 --saturation 1.2");
 }
 
 fn main() {
-    let args = Args::parse();
+    let args = match Args::try_parse() {
+        Ok(args) => args,
+        Err(e) => {
+            // clap's help or error, then the controls it doesn't know about
+            let _ = e.print();
+            if e.kind() != clap::error::ErrorKind::DisplayVersion {
+                println!();
+                print_usage();
+            }
+            std::process::exit(e.exit_code());
+        }
+    };
     
     if args.list {
         print_available_roms();
@@ -156,7 +554,7 @@ fn main() {
             }
         }
     } else {
-        args.rom_file
+        args.rom_file.clone()
     };
     
     // Check if ROM file exists
@@ -167,32 +565,142 @@ fn main() {
         std::process::exit(1);
     }
     
+    if is_nsf_file(&rom_file) {
+        println!("Loading NSF: {}", rom_file);
+        if let Err(e) = run_nsf(&args, &rom_file) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     println!("Loading ROM: {}", rom_file);
 
+    let system_palette = match load_palette(&args) {
+        Ok(palette) => palette,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
     // init sdl2
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
+    let crop = args.crop;
+    let (window_width, window_height) = args.display.window_size(&crop, args.scale);
     let window = video_subsystem
-        .window(&format!("NES Emulator - {}", rom_file), (256.0 * 4.0) as u32, (240.0 * 2.0) as u32)
+        .window(&format!("NES Emulator - {}", rom_file), window_width, window_height)
         .position_centered()
         .build()
         .unwrap();
 
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+    let main_window_id = window.id();
+    let mut canvas = window.into_canvas().build().unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(2.0, 2.0).unwrap();
+    // draw in picture pixels, whatever the window size and pixel aspect
+    canvas
+        .set_scale(
+            window_width as f32 / crop.width() as f32,
+            window_height as f32 / crop.height() as f32,
+        )
+        .unwrap();
+
+    let mut ntsc_filter = args
+        .ntsc_filter
+        .map(|preset| NtscFilter::new(preset, ntsc_params(&args), system_palette.clone()));
+    let (texture_width, texture_height) = match ntsc_filter {
+        Some(_) => (NtscFilter::WIDTH, NtscFilter::HEIGHT),
+        None => (Frame::WIDTH, Frame::HEIGHT),
+    };
+    // the NTSC filter widens lines, scale the horizontal crop along
+    let visible_area = sdl2::rect::Rect::new(
+        (crop.left * texture_width / Frame::WIDTH) as i32,
+        crop.top as i32,
+        (crop.width() * texture_width / Frame::WIDTH) as u32,
+        crop.height() as u32,
+    );
 
     let creator = canvas.texture_creator();
     let mut texture = creator
-        .create_texture_target(PixelFormatEnum::RGB24, 256 * 2, 240)
+        .create_texture_target(PixelFormatEnum::RGB24, texture_width as u32, texture_height as u32)
         .unwrap();
 
+    let mut nametables_canvas = if args.nametables {
+        let window = video_subsystem
+            .window("Nametables", render::NAMETABLES_WIDTH as u32, render::NAMETABLES_HEIGHT as u32)
+            .build()
+            .unwrap();
+        Some(window.into_canvas().build().unwrap())
+    } else {
+        None
+    };
+    let nametables_creator = nametables_canvas.as_ref().map(|canvas| canvas.texture_creator());
+    let mut nametables_texture = nametables_creator.as_ref().map(|creator| {
+        creator
+            .create_texture_target(
+                PixelFormatEnum::RGB24,
+                render::NAMETABLES_WIDTH as u32,
+                render::NAMETABLES_HEIGHT as u32,
+            )
+            .unwrap()
+    });
+    let mut nametables_frame = Frame::with_size(render::NAMETABLES_WIDTH, render::NAMETABLES_HEIGHT);
+
     //load the game
-    let bytes: Vec<u8> = std::fs::read(&rom_file).unwrap();
-    let rom = Rom::new(&bytes).unwrap();
+    let bytes: Vec<u8> = match std::fs::read(&rom_file) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Error: can't read {}: {}", rom_file, e);
+            std::process::exit(1);
+        }
+    };
+    // a disk goes in the RAM adapter, which has the drive's controls
+    let (cartridge, header_region, description, disk) = if fds::is_disk_image(&bytes) {
+        match load_disk(&bytes, &args.fds_bios) {
+            Ok((fds, disk)) => {
+                let sides = disk.borrow().sides();
+                (fds, Some(Region::Ntsc), format!("Famicom Disk System, {} sides", sides), Some(disk))
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        let rom = match Rom::new(&bytes) {
+            Ok(rom) => rom,
+            Err(e) => {
+                eprintln!("Error: can't load {}: {}", rom_file, e);
+                std::process::exit(1);
+            }
+        };
+        (mapper::for_rom(&rom), rom.region, mapper::describe(&rom), None)
+    };
+
+    // the command line wins over the header, which wins over tags in the file name
+    let region = args
+        .region
+        .or(header_region)
+        .or_else(|| Region::from_file_name(&rom_file))
+        .unwrap_or_default();
+    println!("Region: {:?}", region);
+    println!("Cartridge: {}", description);
 
-    let mut frame = Frame::new();
+    let frame_duration = Duration::from_secs_f64(1.0 / region.frame_rate());
+    let mut next_frame = Instant::now() + frame_duration;
+
+    let mut rgb_buffer = Vec::with_capacity(Frame::WIDTH * Frame::HEIGHT * 3);
     let mut paused = false;
+    // where F9 records to
+    let record_path = args.record_audio.clone().unwrap_or_else(|| {
+        let stem = Path::new(&rom_file).file_stem().map(|s| s.to_string_lossy().into_owned());
+        format!("{}.wav", stem.unwrap_or_else(|| "recording".to_string()))
+    });
+    let record_stems = args.record_stems;
+    let mut show_channels = false;
+    // channel the volume keys apply to
+    let mut selected_channel = 0;
 
     let mut key_map = HashMap::new();
     key_map.insert(Keycode::Down, joypad::JoypadButton::DOWN);
@@ -205,18 +713,33 @@ fn main() {
     key_map.insert(Keycode::S, joypad::JoypadButton::BUTTON_B);
 
     // run the game cycle
-    let mut bus = Bus::new(rom, move |ppu: &NesPPU, joypad: &mut joypad::Joypad| {
+    let mut bus = Bus::with_mapper(cartridge, region, move |ppu: &NesPPU, apu: &mut APU, joypad: &mut joypad::Joypad| {
         if !paused {
-            render::render(ppu, &mut frame);
-            texture.update(None, &frame.data, 256 *2 * 3).unwrap();
+            // the PPU has drawn it a scanline at a time
+            let frame = ppu.frame();
+            match ntsc_filter.as_mut() {
+                Some(filter) => texture.update(None, filter.apply(frame), texture_width * 3).unwrap(),
+                None => {
+                    frame.to_rgb24(&system_palette, &mut rgb_buffer);
+                    texture.update(None, &rgb_buffer, texture_width * 3).unwrap()
+                }
+            }
         }
 
-        canvas.copy(&texture, None, None).unwrap();
+        canvas.copy(&texture, visible_area, None).unwrap();
+
+        if let (Some(nt_canvas), Some(nt_texture)) = (nametables_canvas.as_mut(), nametables_texture.as_mut()) {
+            render::render_nametables(ppu, &mut nametables_frame);
+            nametables_frame.to_rgb24(&system_palette, &mut rgb_buffer);
+            nt_texture.update(None, &rgb_buffer, render::NAMETABLES_WIDTH * 3).unwrap();
+            nt_canvas.copy(nt_texture, None, None).unwrap();
+            nt_canvas.present();
+        }
         
         // Draw pause overlay if paused
         if paused {
             // Create a semi-transparent overlay
-            let overlay_rect = sdl2::rect::Rect::new(0, 0, 256 * 2, 240);
+            let overlay_rect = sdl2::rect::Rect::new(0, 0, crop.width() as u32, crop.height() as u32);
             canvas.set_draw_color(sdl2::pixels::Color::RGBA(0, 0, 0, 128));
             canvas.fill_rect(overlay_rect).unwrap();
             
@@ -259,23 +782,105 @@ fn main() {
             canvas.fill_rect(sdl2::rect::Rect::new(188, 100, 8, 20)).unwrap(); // right vertical
         }
         
+        if show_channels {
+            draw_channel_overlay(&mut canvas, apu, selected_channel);
+        }
+
         canvas.present();
+
+        // pace frames to the console's refresh rate rather than the monitor's
+        let now = Instant::now();
+        if next_frame > now {
+            std::thread::sleep(next_frame - now);
+            next_frame += frame_duration;
+        } else {
+            next_frame = now + frame_duration;
+        }
         
         for event in event_pump.poll_iter() {
             match event {
+                Event::Window { window_id, win_event: sdl2::event::WindowEvent::Close, .. }
+                    if window_id != main_window_id =>
+                {
+                    if let Some(nt_canvas) = nametables_canvas.as_mut() {
+                        nt_canvas.window_mut().hide();
+                    }
+                }
+
                 Event::Quit { .. }
+                | Event::Window { win_event: sdl2::event::WindowEvent::Close, .. }
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
-                } => std::process::exit(0),
+                } => {
+                    if let Err(e) = apu.stop_recording() {
+                        eprintln!("Failed to finish audio recording: {}", e);
+                    }
+                    if let Err(e) = apu.stop_vgm_log() {
+                        eprintln!("Failed to finish the VGM log: {}", e);
+                    }
+                    if let Err(e) = apu.stop_midi_export() {
+                        eprintln!("Failed to write the MIDI file: {}", e);
+                    }
+                    std::process::exit(0)
+                }
 
-                Event::KeyDown { keycode, .. } => {
+                Event::KeyDown { keycode, keymod, .. } => {
                     if let Some(keycode) = keycode {
+                        let channels = apu.channel_names().len();
                         match keycode {
                             Keycode::I => {
                                 paused = !paused;
                                 println!("Game {}!", if paused { "PAUSED" } else { "RESUMED" });
                             }
+                            Keycode::F9 => {
+                                let result = if apu.is_recording() {
+                                    apu.stop_recording().map(|_| println!("Audio saved to {}", record_path))
+                                } else {
+                                    apu.start_recording(&record_path, record_stems)
+                                        .map(|_| println!("Recording audio to {}", record_path))
+                                };
+                                if let Err(e) = result {
+                                    eprintln!("Audio recording: {}", e);
+                                }
+                            }
+                            Keycode::F8 => show_channels = !show_channels,
+                            Keycode::F5 if disk.is_some() => {
+                                let mut disk = disk.as_ref().unwrap().borrow_mut();
+                                match disk.inserted() {
+                                    Some(side) => {
+                                        disk.eject();
+                                        println!("Ejected {}", fds::side_name(side));
+                                    }
+                                    None => {
+                                        disk.insert();
+                                        println!("Inserted {}", fds::side_name(disk.inserted().unwrap()));
+                                    }
+                                }
+                            }
+                            Keycode::F6 if disk.is_some() => {
+                                let side = disk.as_ref().unwrap().borrow_mut().switch_side();
+                                println!("Switching to {}", fds::side_name(side));
+                            }
+                            Keycode::Minus | Keycode::Equals => {
+                                let controls = apu.channel_controls_mut();
+                                let step = if keycode == Keycode::Minus { -0.1 } else { 0.1 };
+                                let gain = (controls.gain(selected_channel) + step).clamp(0.0, 2.0);
+                                controls.set_gain(selected_channel, (gain * 10.0).round() / 10.0);
+                                show_channels = true;
+                            }
+                            _ if channel_key(keycode).is_some_and(|channel| channel < channels) => {
+                                let channel = channel_key(keycode).unwrap();
+                                let controls = apu.channel_controls_mut();
+                                if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                                    let solo = if controls.solo() == Some(channel) { None } else { Some(channel) };
+                                    controls.set_solo(solo);
+                                } else {
+                                    controls.set_muted(channel, !controls.is_muted(channel));
+                                }
+                                selected_channel = channel;
+                                show_channels = true;
+                            }
                             _ => {
                                 if let Some(key) = key_map.get(&keycode) {
                                     joypad.set_button_pressed_status(*key, true);
@@ -295,9 +900,12 @@ fn main() {
         }
     });
 
+    bus.apu_mut().set_raw_output(args.raw_audio);
+    bus.apu_mut().set_sample_rate(args.sample_rate);
+
     // Initialize audio if not disabled
     if !args.no_audio {
-        match bus.init_audio(&sdl_context) {
+        match bus.init_audio(&sdl_context, args.sample_rate) {
             Ok(_) => println!("Audio initialized successfully"),
             Err(e) => eprintln!("Failed to initialize audio: {}", e),
         }
@@ -305,6 +913,26 @@ fn main() {
         println!("Audio disabled");
     }
 
+    // after the audio device is open, which may have picked another sample rate
+    if let Some(path) = &args.record_audio {
+        match bus.apu_mut().start_recording(path, args.record_stems) {
+            Ok(_) => println!("Recording audio to {}", path),
+            Err(e) => eprintln!("Failed to start audio recording: {}", e),
+        }
+    }
+    if let Some(path) = &args.record_vgm {
+        match bus.apu_mut().start_vgm_log(path) {
+            Ok(_) => println!("Logging APU writes to {}", path),
+            Err(e) => eprintln!("Failed to start the VGM log: {}", e),
+        }
+    }
+    if let Some(path) = &args.record_midi {
+        match bus.apu_mut().start_midi_export(path) {
+            Ok(_) => println!("Transcribing to {}", path),
+            Err(e) => eprintln!("Failed to start the MIDI export: {}", e),
+        }
+    }
+
     let mut cpu = CPU::new(bus);
     cpu.reset();
     
@@ -0,0 +1,261 @@
+// Boards built from discrete logic chips rather than a mapper ASIC: a latch
+// or two holding a PRG and a CHR bank, written anywhere in their register
+// range. Where the latch sits on the same data bus as the ROM, the ROM drives
+// the bus during the write too and the latch gets both values ANDed together;
+// games write a value that matches the ROM byte under it to avoid that.
+// http://wiki.nesdev.com/w/index.php/Bus_conflict
+use super::{Chr, Mapper, Prg};
+use crate::cartridge::{Mirroring, Rom};
+
+const PRG_RAM_SIZE: usize = 0x2000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Board {
+    /// Mapper 11: 32KB PRG and 8KB CHR banks at $8000-$FFFF, CCCC LLPP
+    /// http://wiki.nesdev.com/w/index.php/Color_Dreams
+    ColorDreams,
+    /// Mapper 34, without CHR ROM: 32KB PRG bank at $8000-$FFFF
+    /// http://wiki.nesdev.com/w/index.php/BNROM
+    Bnrom,
+    /// Mapper 34 with CHR ROM: 32KB PRG bank at $7FFD and 4KB CHR banks at
+    /// $7FFE and $7FFF, over PRG RAM
+    /// http://wiki.nesdev.com/w/index.php/NINA-001
+    Nina001,
+    /// Mapper 66: 32KB PRG and 8KB CHR banks at $8000-$FFFF, --PP --CC
+    /// http://wiki.nesdev.com/w/index.php/GxROM
+    Gxrom,
+    /// Mapper 71: 16KB PRG bank at $C000-$FFFF and the last fixed, with
+    /// one-screen mirroring at $9000-$9FFF on the Fire Hawk board
+    /// http://wiki.nesdev.com/w/index.php/INES_Mapper_071
+    Camerica,
+    /// Mapper 79: 32KB PRG and 8KB CHR banks at $4100-$5FFF, ---- PCCC
+    /// http://wiki.nesdev.com/w/index.php/NINA-003-006
+    Nina003,
+}
+
+impl Board {
+    /// The board of `rom`, if it's one of these
+    pub fn for_rom(rom: &Rom) -> Option<Board> {
+        match (rom.mapper, rom.submapper) {
+            (11, _) => Some(Board::ColorDreams),
+            (34, 1) => Some(Board::Nina001),
+            (34, 2) => Some(Board::Bnrom),
+            // without a submapper, only NINA-001 has CHR ROM
+            (34, _) if rom.chr_rom.len() > 0x2000 => Some(Board::Nina001),
+            (34, _) => Some(Board::Bnrom),
+            (66, _) => Some(Board::Gxrom),
+            (71, _) => Some(Board::Camerica),
+            (79, _) => Some(Board::Nina003),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Board::ColorDreams => "Color Dreams",
+            Board::Bnrom => "BNROM",
+            Board::Nina001 => "NINA-001",
+            Board::Gxrom => "GxROM",
+            Board::Camerica => "Camerica BF909x",
+            Board::Nina003 => "NINA-003/006",
+        }
+    }
+
+    fn bus_conflicts(&self) -> bool {
+        matches!(self, Board::ColorDreams | Board::Bnrom | Board::Gxrom)
+    }
+}
+
+pub struct Discrete {
+    board: Board,
+    prg: Prg,
+    prg_ram: [u8; PRG_RAM_SIZE],
+    chr: Chr,
+    // 32KB, or 16KB on Camerica boards
+    prg_bank: u8,
+    // 4KB banks at $0000 and $1000
+    chr_banks: [u8; 2],
+    mirroring: Mirroring,
+}
+
+impl Discrete {
+    pub fn new(rom: &Rom, board: Board) -> Self {
+        Discrete {
+            board,
+            prg: Prg::new(rom.prg_rom.clone()),
+            prg_ram: [0; PRG_RAM_SIZE],
+            chr: Chr::new(rom.chr_rom.clone()),
+            prg_bank: 0,
+            chr_banks: [0, 1],
+            mirroring: rom.screen_mirroring.clone(),
+        }
+    }
+
+    fn select_chr_8k(&mut self, bank: u8) {
+        self.chr_banks = [bank * 2, bank * 2 + 1];
+    }
+}
+
+impl Mapper for Discrete {
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if self.board == Board::Nina001 => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xBFFF if self.board == Board::Camerica => {
+                self.prg.read(self.prg_bank as usize, 0x4000, (addr & 0x3FFF) as usize)
+            }
+            0xC000..=0xFFFF if self.board == Board::Camerica => {
+                self.prg.read(self.prg.last_bank(0x4000), 0x4000, (addr & 0x3FFF) as usize)
+            }
+            0x8000..=0xFFFF => self.prg.read(self.prg_bank as usize, 0x8000, (addr & 0x7FFF) as usize),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        let data = if self.board.bus_conflicts() && addr >= 0x8000 {
+            data & self.read(addr)
+        } else {
+            data
+        };
+        match (self.board, addr) {
+            (Board::ColorDreams, 0x8000..=0xFFFF) => {
+                self.prg_bank = data & 0x03;
+                self.select_chr_8k(data >> 4);
+            }
+            (Board::Bnrom, 0x8000..=0xFFFF) => self.prg_bank = data,
+            (Board::Nina001, 0x6000..=0x7FFF) => {
+                self.prg_ram[(addr - 0x6000) as usize] = data;
+                match addr {
+                    0x7FFD => self.prg_bank = data & 0x01,
+                    0x7FFE => self.chr_banks[0] = data & 0x0F,
+                    0x7FFF => self.chr_banks[1] = data & 0x0F,
+                    _ => {}
+                }
+            }
+            (Board::Gxrom, 0x8000..=0xFFFF) => {
+                self.prg_bank = (data >> 4) & 0x03;
+                self.select_chr_8k(data & 0x03);
+            }
+            (Board::Camerica, 0x9000..=0x9FFF) => {
+                self.mirroring = if data & 0x10 == 0 {
+                    Mirroring::SingleScreenLower
+                } else {
+                    Mirroring::SingleScreenUpper
+                };
+            }
+            (Board::Camerica, 0xC000..=0xFFFF) => self.prg_bank = data & 0x0F,
+            // A8 set, in $4100-$5FFF
+            (Board::Nina003, 0x4100..=0x5FFF) if addr & 0x0100 != 0 => {
+                self.prg_bank = (data >> 3) & 0x01;
+                self.select_chr_8k(data & 0x07);
+            }
+            _ => {}
+        }
+    }
+
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        let bank = self.chr_banks[(addr as usize >> 12) & 1];
+        self.chr.read(bank as usize, 0x1000, (addr & 0x0FFF) as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        let bank = self.chr_banks[(addr as usize >> 12) & 1];
+        self.chr.write(bank as usize, 0x1000, (addr & 0x0FFF) as usize, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::mapper::test::numbered_rom;
+
+    // 16KB PRG banks numbered by their first byte, $FF after it so writes
+    // there see no bus conflict
+    fn board(board: Board) -> Discrete {
+        let mut rom = numbered_rom(0, 0x1000, 32);
+        rom.prg_rom = (0..16)
+            .flat_map(|bank| {
+                let mut data = vec![0xFF; 0x4000];
+                data[0] = bank as u8;
+                data
+            })
+            .collect();
+        Discrete::new(&rom, board)
+    }
+
+    #[test]
+    fn test_board_for_rom() {
+        let mut rom = test_rom();
+        rom.mapper = 34;
+        assert_eq!(Board::for_rom(&rom), Some(Board::Bnrom));
+        rom.chr_rom = vec![0; 0x10000];
+        assert_eq!(Board::for_rom(&rom), Some(Board::Nina001));
+        rom.submapper = 2;
+        assert_eq!(Board::for_rom(&rom), Some(Board::Bnrom));
+        rom.mapper = 4;
+        assert_eq!(Board::for_rom(&rom), None);
+    }
+
+    #[test]
+    fn test_latch_layouts() {
+        let mut gxrom = board(Board::Gxrom);
+        gxrom.write(0x8001, 0x32);
+        assert_eq!(gxrom.read(0x8000), 6);
+        assert_eq!(gxrom.read(0xC000), 7);
+        assert_eq!(gxrom.read_chr(0x0000), 4);
+        assert_eq!(gxrom.read_chr(0x1000), 5);
+
+        let mut color_dreams = board(Board::ColorDreams);
+        color_dreams.write(0x8001, 0x32);
+        assert_eq!(color_dreams.read(0x8000), 4);
+        assert_eq!(color_dreams.read_chr(0x0000), 6);
+
+        let mut bnrom = board(Board::Bnrom);
+        bnrom.write(0x8001, 5);
+        assert_eq!(bnrom.read(0x8000), 10);
+
+        let mut nina001 = board(Board::Nina001);
+        nina001.write(0x7FFD, 1);
+        nina001.write(0x7FFE, 9);
+        nina001.write(0x7FFF, 3);
+        assert_eq!(nina001.read(0x8000), 2);
+        assert_eq!(nina001.read_chr(0x0000), 9);
+        assert_eq!(nina001.read_chr(0x1000), 3);
+        // the registers are written to the RAM underneath as well
+        assert_eq!(nina001.read(0x7FFE), 9);
+
+        let mut camerica = board(Board::Camerica);
+        camerica.write(0xC000, 5);
+        assert_eq!(camerica.read(0x8000), 5);
+        assert_eq!(camerica.read(0xC000), 15);
+        camerica.write(0x9000, 0x10);
+        assert_eq!(camerica.mirroring(), Mirroring::SingleScreenUpper);
+
+        let mut nina003 = board(Board::Nina003);
+        nina003.write(0x4000, 0x0B);
+        assert_eq!(nina003.read_chr(0x0000), 0);
+        nina003.write(0x4100, 0x0B);
+        assert_eq!(nina003.read(0x8000), 2);
+        assert_eq!(nina003.read_chr(0x0000), 6);
+    }
+
+    #[test]
+    fn test_bus_conflicts() {
+        // the ROM holds 0 at $8000, which wins over the written bits
+        let mut gxrom = board(Board::Gxrom);
+        gxrom.write(0x8000, 0x11);
+        assert_eq!(gxrom.read_chr(0x0000), 0);
+        gxrom.write(0x8001, 0x11);
+        assert_eq!(gxrom.read_chr(0x0000), 2);
+
+        // Camerica boards have none
+        let mut camerica = board(Board::Camerica);
+        camerica.write(0xC000, 0x03);
+        assert_eq!(camerica.read(0x8000), 3);
+    }
+}
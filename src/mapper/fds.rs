@@ -0,0 +1,619 @@
+// Famicom Disk System RAM adapter: 32KB of PRG RAM at $6000-$DFFF that games
+// load themselves into from disk, the BIOS at $E000-$FFFF, 8KB of CHR RAM, a
+// 16-bit IRQ timer, the disk drive interface and a wavetable sound channel.
+// The drive streams the disk past the head a byte every 150 CPU cycles or so,
+// flagging each one in $4030 (and raising an IRQ if asked to) for the BIOS to
+// pick up from $4031.
+// http://wiki.nesdev.com/w/index.php/Family_Computer_Disk_System
+// http://wiki.nesdev.com/w/index.php/FDS_audio
+use super::{Chr, Mapper};
+use crate::apu::expansion::{ExpansionAudio, SharedExpansionAudio, PULSE_FULL_VOLUME};
+use crate::cartridge::Mirroring;
+use crate::fds::SharedDisk;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const BIOS_SIZE: usize = 0x2000;
+const PRG_RAM_SIZE: usize = 0x8000;
+// CPU cycles from the motor starting to the first byte, and between bytes
+const SPIN_UP_DELAY: u32 = 50000;
+const BYTE_DELAY: u32 = 150;
+// modulation table entries: counter steps, or 0x80 to reset the counter
+const MOD_STEPS: [i8; 8] = [0, 1, 2, 4, -128, -4, -2, -1];
+const MOD_RESET: i8 = -128;
+// $4089 master volume: 2/2, 2/3, 2/4, 2/5 of the full level
+const MASTER_VOLUMES: [u32; 4] = [36, 24, 17, 14];
+
+// The volume and modulation envelopes: a gain that steps up or down every
+// 8 * (speed + 1) * master speed CPU cycles, or a fixed gain when off
+struct FdsEnvelope {
+    speed: u8,
+    gain: u8,
+    increase: bool,
+    off: bool,
+    timer: u32,
+}
+
+impl FdsEnvelope {
+    fn new() -> Self {
+        FdsEnvelope { speed: 0, gain: 0, increase: false, off: true, timer: 0 }
+    }
+
+    fn write(&mut self, data: u8, master_speed: u8) {
+        self.off = data & 0x80 != 0;
+        self.increase = data & 0x40 != 0;
+        self.speed = data & 0x3F;
+        if self.off {
+            self.gain = self.speed;
+        }
+        self.reset_timer(master_speed);
+    }
+
+    fn reset_timer(&mut self, master_speed: u8) {
+        self.timer = 8 * (self.speed as u32 + 1) * master_speed as u32;
+    }
+
+    /// True when the gain steps
+    fn tick(&mut self, master_speed: u8) -> bool {
+        if self.off || master_speed == 0 {
+            return false;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return false;
+        }
+        self.reset_timer(master_speed);
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+        true
+    }
+}
+
+pub struct FdsAudio {
+    wave: [u8; 64],
+    wave_write: bool,
+    wave_position: usize,
+    wave_accumulator: u32,
+    pitch: u16,
+    halt: bool,
+    envelopes_off: bool,
+    master_volume: usize,
+    master_speed: u8,
+    volume: FdsEnvelope,
+    modulation: FdsEnvelope,
+    mod_table: [u8; 64],
+    mod_position: usize,
+    mod_accumulator: u32,
+    mod_pitch: u16,
+    mod_disabled: bool,
+    // 7-bit signed
+    mod_counter: i8,
+    // pitch change the modulator makes to the wave
+    mod_output: i32,
+    level: u8,
+}
+
+impl FdsAudio {
+    pub fn new() -> Self {
+        FdsAudio {
+            wave: [0; 64],
+            wave_write: false,
+            wave_position: 0,
+            wave_accumulator: 0,
+            pitch: 0,
+            halt: true,
+            envelopes_off: false,
+            master_volume: 0,
+            master_speed: 0xE8,
+            volume: FdsEnvelope::new(),
+            modulation: FdsEnvelope::new(),
+            mod_table: [0; 64],
+            mod_position: 0,
+            mod_accumulator: 0,
+            mod_pitch: 0,
+            mod_disabled: true,
+            mod_counter: 0,
+            mod_output: 0,
+            level: 0,
+        }
+    }
+
+    /// CPU read from $4040-$4097
+    pub fn read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4040..=0x407F => Some(self.wave[(addr & 0x3F) as usize] | 0x40),
+            0x4090 => Some(self.volume.gain | 0x40),
+            0x4092 => Some(self.modulation.gain | 0x40),
+            _ => None,
+        }
+    }
+
+    /// CPU write to $4040-$408A
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4040..=0x407F if self.wave_write => self.wave[(addr & 0x3F) as usize] = data & 0x3F,
+            0x4080 => self.volume.write(data, self.master_speed),
+            0x4082 => self.pitch = (self.pitch & 0x0F00) | data as u16,
+            0x4083 => {
+                self.pitch = (self.pitch & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                self.halt = data & 0x80 != 0;
+                self.envelopes_off = data & 0x40 != 0;
+                if self.halt {
+                    self.wave_position = 0;
+                    self.wave_accumulator = 0;
+                }
+                if self.envelopes_off {
+                    self.volume.reset_timer(self.master_speed);
+                    self.modulation.reset_timer(self.master_speed);
+                }
+            }
+            0x4084 => self.modulation.write(data, self.master_speed),
+            0x4085 => {
+                self.set_mod_counter(data as i32);
+                self.update_mod_output();
+            }
+            0x4086 => self.mod_pitch = (self.mod_pitch & 0x0F00) | data as u16,
+            0x4087 => {
+                self.mod_pitch = (self.mod_pitch & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                self.mod_disabled = data & 0x80 != 0;
+                if self.mod_disabled {
+                    self.mod_accumulator = 0;
+                }
+            }
+            // the table takes writes while modulation is off, each entry twice
+            0x4088 if self.mod_disabled => {
+                self.mod_table[self.mod_position] = data & 0x07;
+                self.mod_table[(self.mod_position + 1) & 0x3F] = data & 0x07;
+                self.mod_position = (self.mod_position + 2) & 0x3F;
+            }
+            0x4089 => {
+                self.master_volume = (data & 0x03) as usize;
+                self.wave_write = data & 0x80 != 0;
+            }
+            0x408A => self.master_speed = data,
+            _ => {}
+        }
+    }
+
+    fn set_mod_counter(&mut self, value: i32) {
+        // wraps around in 7 bits
+        self.mod_counter = (((value & 0x7F) ^ 0x40) - 0x40) as i8;
+    }
+
+    // the pitch change for the current counter and gain, as the hardware
+    // works it out
+    // http://wiki.nesdev.com/w/index.php/FDS_audio#Frequency_calculation
+    fn update_mod_output(&mut self) {
+        let mut temp = self.mod_counter as i32 * self.modulation.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if self.mod_counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+        temp *= self.pitch as i32;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        self.mod_output = temp;
+    }
+
+    fn tick_modulator(&mut self) {
+        if self.mod_disabled || self.mod_pitch == 0 {
+            return;
+        }
+        self.mod_accumulator += self.mod_pitch as u32;
+        if self.mod_accumulator > 0xFFFF {
+            self.mod_accumulator &= 0xFFFF;
+            let step = MOD_STEPS[self.mod_table[self.mod_position] as usize];
+            let counter = if step == MOD_RESET { 0 } else { self.mod_counter as i32 + step as i32 };
+            self.set_mod_counter(counter);
+            self.mod_position = (self.mod_position + 1) & 0x3F;
+            self.update_mod_output();
+        }
+    }
+}
+
+impl Default for FdsAudio {
+    fn default() -> Self {
+        FdsAudio::new()
+    }
+}
+
+impl ExpansionAudio for FdsAudio {
+    fn channel_names(&self) -> &'static [&'static str] {
+        &["fds"]
+    }
+
+    fn tick(&mut self) {
+        if !self.halt && !self.envelopes_off {
+            self.volume.tick(self.master_speed);
+            if self.modulation.tick(self.master_speed) {
+                self.update_mod_output();
+            }
+        }
+        self.tick_modulator();
+
+        let level = self.volume.gain.min(32) as u32 * MASTER_VOLUMES[self.master_volume];
+        self.level = (self.wave[self.wave_position] as u32 * level / 1152) as u8;
+        let modulated = self.pitch as i32 + if self.mod_disabled { 0 } else { self.mod_output };
+        if !self.halt && !self.wave_write && modulated > 0 {
+            self.wave_accumulator += modulated as u32;
+            if self.wave_accumulator > 0xFFFF {
+                self.wave_accumulator &= 0xFFFF;
+                self.wave_position = (self.wave_position + 1) & 0x3F;
+            }
+        }
+    }
+
+    fn output(&self, levels: &mut [f32]) {
+        // at full volume the channel is about 2.4 times as loud as an APU pulse
+        levels[0] = self.level as f32 * (2.4 * PULSE_FULL_VOLUME / 63.0);
+    }
+}
+
+// The drive side of the RAM adapter, after Mesen's model of it: the head runs
+// from the start of the side to the end while the motor is on, a byte at a time
+#[derive(Default)]
+struct Drive {
+    motor_on: bool,
+    // $4025 bit 1: hold the head at the start of the side
+    reset_transfer: bool,
+    read_mode: bool,
+    crc_control: bool,
+    // $4025 bit 6: reading looks for the end of a gap, writing sends data
+    ready: bool,
+    irq_enabled: bool,
+    scanning: bool,
+    end_of_head: bool,
+    gap_ended: bool,
+    position: usize,
+    delay: u32,
+    transfer_complete: bool,
+    read_data: u8,
+    write_data: u8,
+    irq: bool,
+}
+
+impl Drive {
+    fn write_control(&mut self, data: u8) {
+        self.motor_on = data & 0x01 != 0;
+        self.reset_transfer = data & 0x02 != 0;
+        self.read_mode = data & 0x04 != 0;
+        self.crc_control = data & 0x10 != 0;
+        self.ready = data & 0x40 != 0;
+        self.irq_enabled = data & 0x80 != 0;
+        self.irq = false;
+    }
+
+    fn tick(&mut self, disk: &mut crate::fds::Disk) {
+        if disk.inserted().is_none() || !self.motor_on {
+            self.end_of_head = true;
+            self.scanning = false;
+            return;
+        }
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = SPIN_UP_DELAY;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let mut irq = self.irq_enabled;
+        if self.read_mode {
+            let data = disk.read(self.position);
+            if !self.ready {
+                self.gap_ended = false;
+            } else if data != 0 && !self.gap_ended {
+                // the bit ending the gap, the block starts with the next byte
+                self.gap_ended = true;
+                irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = data;
+                self.irq |= irq;
+            }
+        } else {
+            // the CRC isn't worked out, nothing checks it
+            let mut data = 0;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                self.irq |= irq;
+                if self.ready {
+                    data = self.write_data;
+                }
+            }
+            disk.write(self.position, data);
+            self.gap_ended = false;
+        }
+
+        self.position += 1;
+        if self.position >= disk.side_len() {
+            self.motor_on = false;
+        } else {
+            self.delay = BYTE_DELAY;
+        }
+    }
+}
+
+pub struct Fds {
+    bios: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Chr,
+    mirroring: Mirroring,
+    disk: SharedDisk,
+    drive: Drive,
+    // $4023
+    disk_registers: bool,
+    sound_registers: bool,
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_enabled: bool,
+    timer_repeat: bool,
+    timer_irq: bool,
+    audio: Rc<RefCell<FdsAudio>>,
+}
+
+impl Fds {
+    /// RAM adapter running `bios` (disksys.rom) with `disk` in the drive
+    pub fn new(bios: Vec<u8>, disk: SharedDisk) -> Result<Self, String> {
+        if bios.len() != BIOS_SIZE {
+            return Err(format!("FDS BIOS should be {} bytes, it's {}", BIOS_SIZE, bios.len()));
+        }
+        Ok(Fds {
+            bios,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            chr: Chr::new(Vec::new()),
+            mirroring: Mirroring::Horizontal,
+            disk,
+            drive: Drive { end_of_head: true, ..Default::default() },
+            disk_registers: false,
+            sound_registers: false,
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_enabled: false,
+            timer_repeat: false,
+            timer_irq: false,
+            audio: Rc::new(RefCell::new(FdsAudio::new())),
+        })
+    }
+
+    fn read_status(&mut self) -> u8 {
+        let status = self.timer_irq as u8
+            | (self.drive.transfer_complete as u8) << 1
+            | (self.drive.end_of_head as u8) << 6;
+        self.timer_irq = false;
+        self.drive.transfer_complete = false;
+        self.drive.irq = false;
+        status
+    }
+
+    fn read_drive_status(&self) -> u8 {
+        let inserted = self.disk.borrow().inserted().is_some();
+        // not inserted, not ready, write protected
+        !inserted as u8 | ((!inserted || !self.drive.scanning) as u8) << 1 | (!inserted as u8) << 2 | 0x40
+    }
+}
+
+impl Mapper for Fds {
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4030 if self.disk_registers => self.read_status(),
+            0x4031 if self.disk_registers => {
+                self.drive.transfer_complete = false;
+                self.drive.irq = false;
+                self.drive.read_data
+            }
+            0x4032 if self.disk_registers => self.read_drive_status(),
+            // battery good
+            0x4033 if self.disk_registers => 0x80,
+            0x4040..=0x4097 if self.sound_registers => self.audio.borrow().read(addr).unwrap_or(0),
+            0x6000..=0xDFFF => self.prg_ram[(addr - 0x6000) as usize],
+            0xE000..=0xFFFF => self.bios[(addr - 0xE000) as usize],
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4020 => self.timer_reload = (self.timer_reload & 0xFF00) | data as u16,
+            0x4021 => self.timer_reload = (self.timer_reload & 0x00FF) | (data as u16) << 8,
+            0x4022 => {
+                self.timer_repeat = data & 0x01 != 0;
+                self.timer_enabled = data & 0x02 != 0 && self.disk_registers;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_registers = data & 0x01 != 0;
+                self.sound_registers = data & 0x02 != 0;
+                if !self.disk_registers {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                    self.drive.irq = false;
+                }
+            }
+            0x4024 if self.disk_registers => {
+                self.drive.write_data = data;
+                self.drive.transfer_complete = false;
+                self.drive.irq = false;
+            }
+            0x4025 if self.disk_registers => {
+                self.drive.write_control(data);
+                self.mirroring = if data & 0x08 == 0 { Mirroring::Vertical } else { Mirroring::Horizontal };
+            }
+            0x4040..=0x408A if self.sound_registers => self.audio.borrow_mut().write(addr, data),
+            0x6000..=0xDFFF => self.prg_ram[(addr - 0x6000) as usize] = data,
+            _ => {}
+        }
+    }
+
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        self.chr.read(0, 0x2000, addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        self.chr.write(0, 0x2000, addr as usize, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+
+    fn tick(&mut self) {
+        if self.timer_enabled {
+            if self.timer_counter == 0 {
+                self.timer_irq = true;
+                self.timer_counter = self.timer_reload;
+                self.timer_enabled = self.timer_repeat;
+            } else {
+                self.timer_counter -= 1;
+            }
+        }
+        let mut disk = self.disk.borrow_mut();
+        disk.tick();
+        self.drive.tick(&mut disk);
+    }
+
+    fn irq(&self) -> bool {
+        self.timer_irq || self.drive.irq
+    }
+
+    fn expansion_audio(&self) -> Option<SharedExpansionAudio> {
+        Some(self.audio.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fds::test::test_side;
+    use crate::fds::Disk;
+
+    fn fds() -> Fds {
+        let disk = Disk::new(&test_side(&[0xAA, 0xBB])).unwrap();
+        Fds::new(vec![0; BIOS_SIZE], Rc::new(RefCell::new(disk))).unwrap()
+    }
+
+    // runs until the drive raises its IRQ, and takes the byte from $4031
+    fn next_byte(fds: &mut Fds) -> u8 {
+        for _ in 0..1_000_000 {
+            fds.tick();
+            if fds.irq() {
+                return fds.read(0x4031);
+            }
+        }
+        panic!("no byte from the drive");
+    }
+
+    #[test]
+    fn test_memory_map() {
+        assert!(Fds::new(vec![0; 0x1000], fds().disk.clone()).is_err());
+        let mut fds = fds();
+        fds.write(0x6000, 1);
+        fds.write(0xDFFF, 2);
+        fds.write(0xE000, 3);
+        assert_eq!((fds.read(0x6000), fds.read(0xDFFF), fds.read(0xE000)), (1, 2, 0));
+        fds.write_chr(0x1234, 4);
+        assert_eq!(fds.read_chr(0x1234), 4);
+
+        // the disk registers are off until $4023 turns them on
+        fds.write(0x4025, 0x08);
+        assert_eq!(fds.mirroring(), Mirroring::Horizontal);
+        fds.write(0x4023, 0x01);
+        fds.write(0x4025, 0x00);
+        assert_eq!(fds.mirroring(), Mirroring::Vertical);
+    }
+
+    #[test]
+    fn test_timer_irq() {
+        let mut fds = fds();
+        fds.write(0x4023, 0x01);
+        fds.write(0x4020, 10);
+        fds.write(0x4021, 0);
+        fds.write(0x4022, 0x03);
+        for _ in 0..10 {
+            fds.tick();
+        }
+        assert!(!fds.irq());
+        fds.tick();
+        assert!(fds.irq());
+        assert_eq!(fds.read(0x4030) & 0x01, 0x01);
+        assert!(!fds.irq());
+
+        // repeating, it goes off every 11 cycles
+        for _ in 0..11 {
+            fds.tick();
+        }
+        assert!(fds.irq());
+    }
+
+    #[test]
+    fn test_reading_the_disk() {
+        let mut fds = fds();
+        fds.write(0x4023, 0x01);
+        // drive not ready until the motor turns
+        assert_eq!(fds.read(0x4032) & 0x03, 0x02);
+
+        // motor on, read mode, look for the end of the gap, IRQ per byte
+        fds.write(0x4025, 0b1110_0101);
+        assert_eq!(next_byte(&mut fds), 0x01);
+        assert_eq!(fds.read(0x4032) & 0x03, 0x00);
+        let verification: Vec<u8> = (0..14).map(|_| next_byte(&mut fds)).collect();
+        assert_eq!(verification, b"*NINTENDO-HVC*");
+
+        // an ejected disk stops the drive
+        fds.disk.borrow_mut().eject();
+        fds.tick();
+        assert_eq!(fds.read(0x4032) & 0x07, 0x07);
+    }
+
+    #[test]
+    fn test_wave_output() {
+        let mut audio = FdsAudio::new();
+        audio.write(0x4089, 0x80);
+        for n in 0..64 {
+            audio.write(0x4040 + n, n as u8);
+        }
+        // full master volume, envelope off at gain 32, a step every 64 cycles
+        audio.write(0x4089, 0x00);
+        audio.write(0x4080, 0x80 | 32);
+        audio.write(0x4082, 0x00);
+        audio.write(0x4083, 0x04);
+        let mut levels = [0.0];
+        for _ in 0..64 * 5 + 1 {
+            audio.tick();
+        }
+        audio.output(&mut levels);
+        assert_eq!(audio.level, 5);
+        assert!(levels[0] > 0.0);
+        assert_eq!(audio.read(0x4090), Some(32 | 0x40));
+
+        // halted, the wave goes back to the start
+        audio.write(0x4083, 0x80);
+        audio.tick();
+        assert_eq!(audio.level, 0);
+    }
+}
@@ -0,0 +1,488 @@
+// Sunsoft FME-7 and 5B, mapper 69. Four 8KB PRG banks (the one at $6000 can
+// be RAM), eight 1KB CHR banks and a 16-bit IRQ counter clocked by the CPU,
+// all set through a command register at $8000 and its parameter at $A000.
+// The 5B adds a YM2149 (AY-3-8910) sound chip: three squares with a shared
+// noise generator and envelope, mixed into the Famicom's audio.
+// http://wiki.nesdev.com/w/index.php/Sunsoft_FME-7
+// http://wiki.nesdev.com/w/index.php/Sunsoft_5B_audio
+use super::{Chr, Mapper, Prg};
+use crate::apu::expansion::{ExpansionAudio, SharedExpansionAudio, PULSE_FULL_VOLUME};
+use crate::cartridge::{Mirroring, Rom};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const PRG_RAM_SIZE: usize = 0x2000;
+// the chip runs at the CPU clock divided by 16
+const AUDIO_DIVIDER: u8 = 16;
+
+lazy_static! {
+    // the volume is logarithmic, 3dB a step
+    static ref VOLUME_TABLE: [f32; 16] = {
+        let mut table = [0.0; 16];
+        for (volume, level) in table.iter_mut().enumerate().skip(1) {
+            *level = 10f32.powf((volume as f32 - 15.0) * 3.0 / 20.0);
+        }
+        table
+    };
+}
+
+struct Envelope {
+    period: u16,
+    timer: u16,
+    // 0-31 through one ramp, two steps to each volume level
+    step: u8,
+    attack: bool,
+    continues: bool,
+    alternate: bool,
+    hold: bool,
+    // the level it stays at once done
+    held: Option<u8>,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Envelope { period: 0, timer: 0, step: 0, attack: false, continues: false, alternate: false, hold: false, held: Some(0) }
+    }
+
+    /// ---- CAaH: Continue, Attack, alternate, Hold. Restarts the envelope.
+    fn write_shape(&mut self, data: u8) {
+        self.continues = data & 0x08 != 0;
+        self.attack = data & 0x04 != 0;
+        self.alternate = data & 0x02 != 0;
+        self.hold = data & 0x01 != 0;
+        self.step = 0;
+        self.timer = 0;
+        self.held = None;
+    }
+
+    fn level(&self) -> u8 {
+        match self.held {
+            Some(level) => level,
+            None if self.attack => self.step / 2,
+            None => (31 - self.step) / 2,
+        }
+    }
+
+    // one tick of the chip's divided clock, 16 of them per step and period
+    fn tick(&mut self) {
+        if self.held.is_some() {
+            return;
+        }
+        self.timer += 1;
+        if self.timer < self.period.max(1) {
+            return;
+        }
+        self.timer = 0;
+        if self.step < 31 {
+            self.step += 1;
+            return;
+        }
+        // end of a ramp
+        if !self.continues {
+            self.held = Some(0);
+        } else if self.hold {
+            let last = self.level();
+            self.held = Some(if self.alternate { 15 - last } else { last });
+        } else {
+            self.step = 0;
+            if self.alternate {
+                self.attack = !self.attack;
+            }
+        }
+    }
+}
+
+struct Square {
+    period: u16,
+    timer: u16,
+    high: bool,
+    volume: u8,
+    use_envelope: bool,
+}
+
+impl Square {
+    fn new() -> Self {
+        Square { period: 0, timer: 0, high: false, volume: 0, use_envelope: false }
+    }
+
+    fn tick(&mut self) {
+        self.timer += 1;
+        if self.timer >= self.period.max(1) {
+            self.timer = 0;
+            self.high = !self.high;
+        }
+    }
+}
+
+pub struct Sunsoft5bAudio {
+    register: u8,
+    squares: [Square; 3],
+    // register 7: tone off in bits 0-2, noise off in bits 3-5, 1 is off
+    mixer: u8,
+    noise_period: u8,
+    noise_timer: u8,
+    noise: u32,
+    envelope: Envelope,
+    divider: u8,
+}
+
+impl Sunsoft5bAudio {
+    pub fn new() -> Self {
+        Sunsoft5bAudio {
+            register: 0,
+            squares: [Square::new(), Square::new(), Square::new()],
+            mixer: 0xFF,
+            noise_period: 0,
+            noise_timer: 0,
+            noise: 1,
+            envelope: Envelope::new(),
+            divider: 0,
+        }
+    }
+
+    /// $C000-$DFFF, which register $E000 writes to
+    pub fn select(&mut self, data: u8) {
+        self.register = data;
+    }
+
+    /// $E000-$FFFF, the selected register
+    pub fn write(&mut self, data: u8) {
+        match self.register {
+            0x00..=0x05 => {
+                let square = &mut self.squares[self.register as usize / 2];
+                if self.register & 1 == 0 {
+                    square.period = (square.period & 0x0F00) | data as u16;
+                } else {
+                    square.period = (square.period & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                }
+            }
+            0x06 => self.noise_period = data & 0x1F,
+            0x07 => self.mixer = data,
+            0x08..=0x0A => {
+                let square = &mut self.squares[self.register as usize - 0x08];
+                square.volume = data & 0x0F;
+                square.use_envelope = data & 0x10 != 0;
+            }
+            0x0B => self.envelope.period = (self.envelope.period & 0xFF00) | data as u16,
+            0x0C => self.envelope.period = (self.envelope.period & 0x00FF) | ((data as u16) << 8),
+            0x0D => self.envelope.write_shape(data),
+            // 0x0E and 0x0F are I/O ports, not connected
+            _ => {}
+        }
+    }
+
+    fn channel_output(&self, channel: usize) -> u8 {
+        let square = &self.squares[channel];
+        let tone = square.high || self.mixer & (1 << channel) != 0;
+        let noise = self.noise & 1 != 0 || self.mixer & (8 << channel) != 0;
+        if !(tone && noise) {
+            return 0;
+        }
+        if square.use_envelope {
+            self.envelope.level()
+        } else {
+            square.volume
+        }
+    }
+}
+
+impl Default for Sunsoft5bAudio {
+    fn default() -> Self {
+        Sunsoft5bAudio::new()
+    }
+}
+
+impl ExpansionAudio for Sunsoft5bAudio {
+    fn channel_names(&self) -> &'static [&'static str] {
+        &["5b square1", "5b square2", "5b square3"]
+    }
+
+    fn tick(&mut self) {
+        self.divider += 1;
+        if self.divider < AUDIO_DIVIDER {
+            return;
+        }
+        self.divider = 0;
+        for square in self.squares.iter_mut() {
+            square.tick();
+        }
+        self.envelope.tick();
+        // the noise runs at half the rate of the squares
+        self.noise_timer += 1;
+        if self.noise_timer >= self.noise_period.max(1) * 2 {
+            self.noise_timer = 0;
+            let feedback = (self.noise ^ (self.noise >> 3)) & 1;
+            self.noise = (self.noise >> 1) | (feedback << 16);
+        }
+    }
+
+    fn output(&self, levels: &mut [f32]) {
+        for (channel, level) in levels.iter_mut().enumerate().take(3) {
+            *level = VOLUME_TABLE[self.channel_output(channel) as usize] * PULSE_FULL_VOLUME;
+        }
+    }
+}
+
+pub struct Fme7 {
+    prg: Prg,
+    prg_ram: [u8; PRG_RAM_SIZE],
+    chr: Chr,
+    command: u8,
+    chr_banks: [u8; 8],
+    // $6000, $8000, $A000, $C000
+    prg_banks: [u8; 4],
+    ram_selected: bool,
+    ram_enabled: bool,
+    mirroring: Mirroring,
+    irq_enabled: bool,
+    counter_enabled: bool,
+    counter: u16,
+    irq: bool,
+    audio: Rc<RefCell<Sunsoft5bAudio>>,
+}
+
+impl Fme7 {
+    pub fn new(rom: &Rom) -> Self {
+        Fme7 {
+            prg: Prg::new(rom.prg_rom.clone()),
+            prg_ram: [0; PRG_RAM_SIZE],
+            chr: Chr::new(rom.chr_rom.clone()),
+            command: 0,
+            chr_banks: [0; 8],
+            prg_banks: [0; 4],
+            ram_selected: false,
+            ram_enabled: false,
+            mirroring: rom.screen_mirroring.clone(),
+            irq_enabled: false,
+            counter_enabled: false,
+            counter: 0,
+            irq: false,
+            audio: Rc::new(RefCell::new(Sunsoft5bAudio::new())),
+        }
+    }
+
+    fn write_parameter(&mut self, data: u8) {
+        match self.command {
+            0x0..=0x7 => self.chr_banks[self.command as usize] = data,
+            0x8 => {
+                self.ram_enabled = data & 0x80 != 0;
+                self.ram_selected = data & 0x40 != 0;
+                self.prg_banks[0] = data & 0x3F;
+            }
+            0x9..=0xB => self.prg_banks[(self.command - 0x8) as usize] = data & 0x3F,
+            0xC => {
+                self.mirroring = match data & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenLower,
+                    _ => Mirroring::SingleScreenUpper,
+                }
+            }
+            0xD => {
+                self.irq_enabled = data & 0x01 != 0;
+                self.counter_enabled = data & 0x80 != 0;
+                self.irq = false;
+            }
+            0xE => self.counter = (self.counter & 0xFF00) | data as u16,
+            _ => self.counter = (self.counter & 0x00FF) | ((data as u16) << 8),
+        }
+    }
+}
+
+impl Mapper for Fme7 {
+    fn read(&mut self, addr: u16) -> u8 {
+        let offset = (addr & 0x1FFF) as usize;
+        match addr {
+            0x6000..=0x7FFF if self.ram_selected && self.ram_enabled => self.prg_ram[offset],
+            // disabled RAM reads as open bus
+            0x6000..=0x7FFF if self.ram_selected => 0,
+            0x6000..=0x7FFF => self.prg.read(self.prg_banks[0] as usize, 0x2000, offset),
+            0x8000..=0xDFFF => self.prg.read(self.prg_banks[(addr as usize - 0x6000) / 0x2000] as usize, 0x2000, offset),
+            0xE000..=0xFFFF => self.prg.read(self.prg.last_bank(0x2000), 0x2000, offset),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.ram_selected && self.ram_enabled => self.prg_ram[(addr & 0x1FFF) as usize] = data,
+            0x8000..=0x9FFF => self.command = data & 0x0F,
+            0xA000..=0xBFFF => self.write_parameter(data),
+            0xC000..=0xDFFF => self.audio.borrow_mut().select(data),
+            0xE000..=0xFFFF => self.audio.borrow_mut().write(data),
+            _ => {}
+        }
+    }
+
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        let bank = self.chr_banks[(addr as usize / 0x400) & 0x07];
+        self.chr.read(bank as usize, 0x400, (addr & 0x3FF) as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        let bank = self.chr_banks[(addr as usize / 0x400) & 0x07];
+        self.chr.write(bank as usize, 0x400, (addr & 0x3FF) as usize, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+
+    fn tick(&mut self) {
+        if !self.counter_enabled {
+            return;
+        }
+        self.counter = self.counter.wrapping_sub(1);
+        if self.counter == 0xFFFF && self.irq_enabled {
+            self.irq = true;
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq
+    }
+
+    fn expansion_audio(&self) -> Option<SharedExpansionAudio> {
+        Some(self.audio.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::numbered_rom;
+
+    fn fme7() -> Fme7 {
+        Fme7::new(&numbered_rom(32, 0x400, 64))
+    }
+
+    fn command(fme7: &mut Fme7, command: u8, parameter: u8) {
+        fme7.write(0x8000, command);
+        fme7.write(0xA000, parameter);
+    }
+
+    #[test]
+    fn test_banking() {
+        let mut fme7 = fme7();
+        command(&mut fme7, 0x9, 4);
+        command(&mut fme7, 0xA, 5);
+        command(&mut fme7, 0xB, 6);
+        assert_eq!(fme7.read(0x8000), 4);
+        assert_eq!(fme7.read(0xA000), 5);
+        assert_eq!(fme7.read(0xC000), 6);
+        assert_eq!(fme7.read(0xE000), 31);
+
+        command(&mut fme7, 0x3, 42);
+        assert_eq!(fme7.read_chr(0x0C00), 42);
+
+        command(&mut fme7, 0xC, 2);
+        assert_eq!(fme7.mirroring(), Mirroring::SingleScreenLower);
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        let mut fme7 = fme7();
+        // ROM bank 7 at $6000
+        command(&mut fme7, 0x8, 7);
+        assert_eq!(fme7.read(0x6000), 7);
+        fme7.write(0x6000, 0x55);
+        assert_eq!(fme7.read(0x6000), 7);
+
+        // RAM, disabled
+        command(&mut fme7, 0x8, 0x40);
+        fme7.write(0x6000, 0x55);
+        assert_eq!(fme7.read(0x6000), 0);
+
+        command(&mut fme7, 0x8, 0xC0);
+        fme7.write(0x6000, 0x55);
+        assert_eq!(fme7.read(0x6000), 0x55);
+    }
+
+    #[test]
+    fn test_irq_counter() {
+        let mut fme7 = fme7();
+        command(&mut fme7, 0xE, 2);
+        command(&mut fme7, 0xF, 0);
+        command(&mut fme7, 0xD, 0x81);
+        fme7.tick();
+        fme7.tick();
+        assert!(!fme7.irq());
+        // fires when it wraps from 0 to $FFFF
+        fme7.tick();
+        assert!(fme7.irq());
+        command(&mut fme7, 0xD, 0x81);
+        assert!(!fme7.irq());
+
+        // counting without the IRQ enabled
+        command(&mut fme7, 0xD, 0x80);
+        for _ in 0..0x10000 {
+            fme7.tick();
+        }
+        assert!(!fme7.irq());
+    }
+
+    #[test]
+    fn test_audio() {
+        let mut fme7 = fme7();
+        let audio = fme7.expansion_audio().unwrap();
+        let mut levels = [0.0; 3];
+        let mut write = |register, data| {
+            fme7.write(0xC000, register);
+            fme7.write(0xE000, data);
+        };
+
+        // square 1 at period 2, full volume, tone only
+        write(0x00, 2);
+        write(0x01, 0);
+        write(0x08, 0x0F);
+        write(0x07, 0b111_110);
+        // toggles every 2 * 16 cycles
+        let mut high = 0;
+        for _ in 0..128 {
+            audio.borrow_mut().tick();
+            audio.borrow().output(&mut levels);
+            if levels[0] > 0.0 {
+                high += 1;
+                assert_eq!(levels[0], PULSE_FULL_VOLUME);
+            }
+            assert_eq!(levels[1], 0.0);
+        }
+        assert_eq!(high, 64);
+
+        // envelope on square 2, a single ramp up then silence
+        write(0x07, 0b111_101);
+        write(0x09, 0x10);
+        write(0x0B, 1);
+        write(0x0C, 0);
+        write(0x0D, 0x04);
+        let mut last = 0.0;
+        for _ in 0..16 * 31 {
+            audio.borrow_mut().tick();
+            audio.borrow().output(&mut levels);
+            // while the tone is high
+            if levels[1] > 0.0 {
+                assert!(levels[1] >= last);
+                last = levels[1];
+            }
+        }
+        assert_eq!(last, PULSE_FULL_VOLUME);
+        for _ in 0..32 {
+            audio.borrow_mut().tick();
+        }
+        audio.borrow().output(&mut levels);
+        assert_eq!(levels[1], 0.0);
+    }
+
+    #[test]
+    fn test_envelope_ramp() {
+        let mut envelope = Envelope::new();
+        envelope.period = 1;
+        envelope.write_shape(0x04);
+        // 32 steps to a ramp, two for each volume level
+        for step in 0..32 {
+            assert_eq!(envelope.level(), step / 2);
+            envelope.tick();
+        }
+        assert_eq!(envelope.level(), 0, "silent once the ramp is done");
+    }
+}
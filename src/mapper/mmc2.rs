@@ -0,0 +1,164 @@
+// Nintendo MMC2 and MMC4, mappers 9 and 10. Each 4KB pattern table has two
+// CHR banks and a latch that picks between them. The latch flips when the PPU
+// fetches tile $FD or $FE of that table, so a game switches banks mid-screen
+// by drawing those tiles. MMC2 (Punch-Out!!) has an 8KB switchable PRG bank
+// and the last three fixed, MMC4 (Fire Emblem) a 16KB one, the last fixed,
+// and PRG RAM.
+// http://wiki.nesdev.com/w/index.php/MMC2
+// http://wiki.nesdev.com/w/index.php/MMC4
+use super::{Chr, Mapper, Prg};
+use crate::cartridge::{Mirroring, Rom};
+
+const PRG_RAM_SIZE: usize = 0x2000;
+
+pub struct Mmc2 {
+    prg: Prg,
+    prg_ram: [u8; PRG_RAM_SIZE],
+    chr: Chr,
+    mmc4: bool,
+    prg_bank: u8,
+    // [pattern table][latch $FD, $FE]
+    chr_banks: [[u8; 2]; 2],
+    // set when the latch holds $FE
+    latches: [bool; 2],
+    mirroring: Mirroring,
+}
+
+impl Mmc2 {
+    /// `mmc4` for mapper 10
+    pub fn new(rom: &Rom, mmc4: bool) -> Self {
+        Mmc2 {
+            prg: Prg::new(rom.prg_rom.clone()),
+            prg_ram: [0; PRG_RAM_SIZE],
+            chr: Chr::new(rom.chr_rom.clone()),
+            mmc4,
+            prg_bank: 0,
+            chr_banks: [[0; 2]; 2],
+            latches: [true; 2],
+            mirroring: rom.screen_mirroring.clone(),
+        }
+    }
+
+    fn chr_bank(&self, addr: u16) -> usize {
+        let table = (addr as usize >> 12) & 1;
+        self.chr_banks[table][self.latches[table] as usize] as usize
+    }
+}
+
+impl Mapper for Mmc2 {
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if self.mmc4 => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xFFFF if self.mmc4 => {
+                let bank = if addr < 0xC000 { self.prg_bank as usize } else { self.prg.last_bank(0x4000) };
+                self.prg.read(bank, 0x4000, (addr & 0x3FFF) as usize)
+            }
+            0x8000..=0x9FFF => self.prg.read(self.prg_bank as usize, 0x2000, (addr & 0x1FFF) as usize),
+            0xA000..=0xFFFF => {
+                // the last three 8KB banks
+                let banks = self.prg.banks(0x2000).max(3);
+                let bank = banks - 3 + (addr as usize - 0xA000) / 0x2000;
+                self.prg.read(bank, 0x2000, (addr & 0x1FFF) as usize)
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.mmc4 => self.prg_ram[(addr - 0x6000) as usize] = data,
+            0xA000..=0xAFFF => self.prg_bank = data & 0x0F,
+            0xB000..=0xEFFF => {
+                let register = (addr as usize - 0xB000) / 0x1000;
+                self.chr_banks[register / 2][register % 2] = data & 0x1F;
+            }
+            0xF000..=0xFFFF => {
+                self.mirroring = if data & 0x01 == 0 { Mirroring::Vertical } else { Mirroring::Horizontal };
+            }
+            _ => {}
+        }
+    }
+
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        self.chr.read(self.chr_bank(addr), 0x1000, (addr & 0x0FFF) as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        self.chr.write(self.chr_bank(addr), 0x1000, (addr & 0x0FFF) as usize, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+
+    // the latch flips after the fetch of the high plane of tile $FD or $FE,
+    // any row of it, except MMC2 only sees the top row in the $0000 table
+    fn pattern_fetch(&mut self, addr: u16) {
+        let table = (addr as usize >> 12) & 1;
+        let any_row = self.mmc4 || table == 1;
+        let row_matches = any_row || addr & 0x07 == 0;
+        match addr & 0x0FF8 {
+            0x0FD8 if row_matches => self.latches[table] = false,
+            0x0FE8 if row_matches => self.latches[table] = true,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::numbered_rom;
+
+    fn board(mmc4: bool) -> Mmc2 {
+        Mmc2::new(&numbered_rom(16, 0x1000, 32), mmc4)
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        let mut mmc2 = board(false);
+        mmc2.write(0xA000, 5);
+        assert_eq!(mmc2.read(0x8000), 5);
+        assert_eq!(mmc2.read(0xA000), 13);
+        assert_eq!(mmc2.read(0xFFFF), 15);
+
+        let mut mmc4 = board(true);
+        mmc4.write(0xA000, 3);
+        assert_eq!(mmc4.read(0x8000), 6);
+        assert_eq!(mmc4.read(0xA000), 7);
+        assert_eq!(mmc4.read(0xC000), 14);
+        mmc4.write(0x6000, 0x42);
+        assert_eq!(mmc4.read(0x6000), 0x42);
+    }
+
+    #[test]
+    fn test_chr_latches() {
+        let mut mmc2 = board(false);
+        mmc2.write(0xB000, 1);
+        mmc2.write(0xC000, 2);
+        mmc2.write(0xD000, 3);
+        mmc2.write(0xE000, 4);
+        // both latches start at $FE
+        assert_eq!(mmc2.read_chr(0x0000), 2);
+        assert_eq!(mmc2.read_chr(0x1000), 4);
+
+        // MMC2 only flips the $0000 latch on the top row of tile $FD
+        mmc2.pattern_fetch(0x0FD9);
+        assert_eq!(mmc2.read_chr(0x0000), 2);
+        mmc2.pattern_fetch(0x0FD8);
+        assert_eq!(mmc2.read_chr(0x0000), 1);
+        assert_eq!(mmc2.read_chr(0x1000), 4);
+
+        // but any row of it in the $1000 table, and the low plane doesn't count
+        mmc2.pattern_fetch(0x1FD3);
+        assert_eq!(mmc2.read_chr(0x1000), 4);
+        mmc2.pattern_fetch(0x1FDB);
+        assert_eq!(mmc2.read_chr(0x1000), 3);
+        mmc2.pattern_fetch(0x1FEF);
+        assert_eq!(mmc2.read_chr(0x1000), 4);
+
+        let mut mmc4 = board(true);
+        mmc4.pattern_fetch(0x0FDD);
+        assert_eq!(mmc4.latches, [false, true]);
+    }
+}
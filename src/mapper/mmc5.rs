@@ -0,0 +1,587 @@
+// Nintendo MMC5 (ExROM), mapper 5. PRG in 32, 16 or 8KB banks of ROM or RAM,
+// CHR in 8 to 1KB banks with a separate set for the background of 8x16 sprite
+// games, 1KB of ExRAM that can be a nametable, per-tile attributes and CHR
+// banks, or plain RAM, a fill-mode nametable, a vertical split screen, a
+// scanline IRQ, an 8x8 multiplier, and two pulses and a PCM channel.
+// http://wiki.nesdev.com/w/index.php/MMC5
+// http://wiki.nesdev.com/w/index.php/MMC5_audio
+use super::{bank_offset, BackgroundFetch, Chr, Mapper, Nametable, Prg, TileRow};
+use crate::apu::expansion::{ExpansionAudio, SharedExpansionAudio, PULSE_FULL_VOLUME};
+use crate::apu::PulseChannel;
+use crate::cartridge::{Mirroring, Rom};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const PRG_RAM_SIZE: usize = 0x10000;
+// the frame sequencer of the pulses runs at a fixed 240Hz
+const FRAME_PERIOD: u16 = 7457;
+// the PCM channel spans about as far as the APU's DMC
+const PCM_STEP: f32 = 159.79 / (22638.0 / 127.0 + 100.0) / 255.0;
+
+pub struct Mmc5Audio {
+    pulses: [PulseChannel; 2],
+    pulse_levels: [u8; 2],
+    pcm: u8,
+    // $5010 bit 0: the PCM takes what the CPU reads from $8000-$BFFF
+    pcm_read_mode: bool,
+    frame_timer: u16,
+}
+
+impl Mmc5Audio {
+    pub fn new() -> Self {
+        Mmc5Audio {
+            pulses: [PulseChannel::without_sweep(), PulseChannel::without_sweep()],
+            pulse_levels: [0; 2],
+            pcm: 0,
+            pcm_read_mode: false,
+            frame_timer: 0,
+        }
+    }
+
+    /// Write to $5000-$5015
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5000..=0x5007 => {
+                let pulse = &mut self.pulses[(addr as usize - 0x5000) / 4];
+                match addr & 0x03 {
+                    0 => pulse.write_duty(data),
+                    // no sweep unit
+                    1 => {}
+                    2 => pulse.write_timer_low(data),
+                    _ => pulse.write_timer_high(data),
+                }
+            }
+            0x5010 => self.pcm_read_mode = data & 0x01 != 0,
+            // writes of 0 are ignored
+            0x5011 if !self.pcm_read_mode && data != 0 => self.pcm = data,
+            0x5015 => {
+                self.pulses[0].set_enabled(data & 0x01 != 0);
+                self.pulses[1].set_enabled(data & 0x02 != 0);
+            }
+            _ => {}
+        }
+    }
+
+    /// Read from $5015
+    pub fn status(&self) -> u8 {
+        self.pulses[0].is_playing() as u8 | (self.pulses[1].is_playing() as u8) << 1
+    }
+
+    /// CPU read from $8000-$BFFF, which is the PCM sample in read mode
+    pub fn prg_read(&mut self, data: u8) {
+        if self.pcm_read_mode && data != 0 {
+            self.pcm = data;
+        }
+    }
+}
+
+impl Default for Mmc5Audio {
+    fn default() -> Self {
+        Mmc5Audio::new()
+    }
+}
+
+impl ExpansionAudio for Mmc5Audio {
+    fn channel_names(&self) -> &'static [&'static str] {
+        &["mmc5 pulse1", "mmc5 pulse2", "mmc5 pcm"]
+    }
+
+    fn tick(&mut self) {
+        for (pulse, level) in self.pulses.iter_mut().zip(self.pulse_levels.iter_mut()) {
+            *level = pulse.tick();
+        }
+        self.frame_timer += 1;
+        if self.frame_timer == FRAME_PERIOD {
+            self.frame_timer = 0;
+            for pulse in self.pulses.iter_mut() {
+                pulse.quarter_frame();
+                pulse.half_frame();
+            }
+        }
+    }
+
+    fn output(&self, levels: &mut [f32]) {
+        // the pulses are as loud as the APU's
+        levels[0] = self.pulse_levels[0] as f32 * (PULSE_FULL_VOLUME / 15.0);
+        levels[1] = self.pulse_levels[1] as f32 * (PULSE_FULL_VOLUME / 15.0);
+        levels[2] = self.pcm as f32 * PCM_STEP;
+    }
+}
+
+pub struct Mmc5 {
+    prg: Prg,
+    prg_ram: Vec<u8>,
+    // $5102 and $5103 have to be 2 and 1 for PRG RAM writes
+    prg_ram_protect: [u8; 2],
+    prg_mode: u8,
+    // $5113-$5117, bit 7 of $5114-$5116 picks ROM over RAM
+    prg_banks: [u8; 5],
+    chr: Chr,
+    chr_mode: u8,
+    // $5120-$5127 for sprites, $5128-$512B for the background of 8x16 sprite games
+    sprite_banks: [u16; 8],
+    background_banks: [u16; 4],
+    // 8x8 sprite games use whichever set was written last for everything
+    background_written_last: bool,
+    chr_upper: u16,
+    exram: [u8; 0x400],
+    exram_mode: u8,
+    // 2 bits per nametable: VRAM page 0 or 1, ExRAM, or fill mode
+    nametable_mapping: u8,
+    fill_tile: u8,
+    fill_attribute: u8,
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: u8,
+    large_sprites: bool,
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    in_frame: bool,
+    scanline: u8,
+    multiplicand: u8,
+    multiplier: u8,
+    audio: Rc<RefCell<Mmc5Audio>>,
+}
+
+impl Mmc5 {
+    pub fn new(rom: &Rom) -> Self {
+        Mmc5 {
+            prg: Prg::new(rom.prg_rom.clone()),
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            prg_ram_protect: [0; 2],
+            prg_mode: 3,
+            prg_banks: [0, 0xFF, 0xFF, 0xFF, 0xFF],
+            chr: Chr::new(rom.chr_rom.clone()),
+            chr_mode: 3,
+            sprite_banks: [0; 8],
+            background_banks: [0; 4],
+            background_written_last: false,
+            chr_upper: 0,
+            exram: [0; 0x400],
+            exram_mode: 0,
+            nametable_mapping: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            large_sprites: false,
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            in_frame: false,
+            scanline: 0,
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+            audio: Rc::new(RefCell::new(Mmc5Audio::new())),
+        }
+    }
+
+    // ROM or RAM, and the 8KB bank of it, at a CPU address of $6000-$FFFF
+    fn prg_bank(&self, addr: u16) -> (bool, usize) {
+        if addr < 0x8000 {
+            return (false, (self.prg_banks[0] & 0x07) as usize);
+        }
+        let slot = (addr as usize - 0x8000) / 0x2000;
+        // register and 8KB banks per register for each mode
+        let (register, size) = match (self.prg_mode, slot) {
+            (0, _) => (4, 4),
+            (1, 0..=1) => (2, 2),
+            (1, _) => (4, 2),
+            (2, 0..=1) => (2, 2),
+            // 8KB banks from $5114 up, mode 2 only for its upper half
+            (_, _) => (slot + 1, 1),
+        };
+        let value = self.prg_banks[register];
+        // $5117 is always ROM
+        let rom = register == 4 || value & 0x80 != 0;
+        let bank = (value & 0x7F) as usize & !(size - 1);
+        (rom, bank + slot % size)
+    }
+
+    fn chr_bank(&self, sprites: bool, addr: u16) -> usize {
+        let slot = (addr as usize / 0x400) & 0x07;
+        // 1KB banks in a bank of the mode's size, and the register of the
+        // group, which is always its last one
+        let per_bank = 8 >> self.chr_mode;
+        let register = (slot / per_bank + 1) * per_bank - 1;
+        let use_sprite_banks = if self.large_sprites { sprites } else { !self.background_written_last };
+        let value = if use_sprite_banks {
+            self.sprite_banks[register]
+        } else {
+            self.background_banks[register % 4]
+        };
+        value as usize * per_bank + slot % per_bank
+    }
+
+    fn write_chr_bank(&mut self, register: usize, data: u8) {
+        let value = self.chr_upper << 8 | data as u16;
+        if register < 8 {
+            self.sprite_banks[register] = value;
+            self.background_written_last = false;
+        } else {
+            self.background_banks[register - 8] = value;
+            self.background_written_last = true;
+        }
+    }
+
+    fn nametable_mode(&self, index: u16) -> u8 {
+        (self.nametable_mapping >> ((index & 0x03) * 2)) & 0x03
+    }
+
+    fn in_split(&self, column: usize) -> bool {
+        if self.split_control & 0x80 == 0 || self.exram_mode > 1 {
+            return false;
+        }
+        let split_column = (self.split_control & 0x1F) as usize;
+        if self.split_control & 0x40 != 0 {
+            column >= split_column
+        } else {
+            column < split_column
+        }
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x5015 => self.audio.borrow().status(),
+            0x5204 => {
+                let status = (self.irq_pending as u8) << 7 | (self.in_frame as u8) << 6;
+                self.irq_pending = false;
+                status
+            }
+            0x5205 => (self.multiplicand as u16 * self.multiplier as u16) as u8,
+            0x5206 => ((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8,
+            // ExRAM is readable as CPU RAM only
+            0x5C00..=0x5FFF if self.exram_mode >= 2 => self.exram[(addr - 0x5C00) as usize],
+            0x6000..=0xFFFF => {
+                let (rom, bank) = self.prg_bank(addr);
+                let offset = (addr & 0x1FFF) as usize;
+                if !rom {
+                    return self.prg_ram[bank_offset(PRG_RAM_SIZE, bank, 0x2000, offset)];
+                }
+                let data = self.prg.read(bank, 0x2000, offset);
+                if let 0x8000..=0xBFFF = addr {
+                    self.audio.borrow_mut().prg_read(data);
+                }
+                data
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5000..=0x5015 => self.audio.borrow_mut().write(addr, data),
+            0x5100 => self.prg_mode = data & 0x03,
+            0x5101 => self.chr_mode = data & 0x03,
+            0x5102 => self.prg_ram_protect[0] = data & 0x03,
+            0x5103 => self.prg_ram_protect[1] = data & 0x03,
+            0x5104 => self.exram_mode = data & 0x03,
+            0x5105 => self.nametable_mapping = data,
+            0x5106 => self.fill_tile = data,
+            // the color in all four quadrants of the attribute byte
+            0x5107 => self.fill_attribute = (data & 0x03) * 0x55,
+            0x5113..=0x5117 => self.prg_banks[(addr - 0x5113) as usize] = data,
+            0x5120..=0x512B => self.write_chr_bank((addr - 0x5120) as usize, data),
+            0x5130 => self.chr_upper = (data & 0x03) as u16,
+            0x5200 => self.split_control = data,
+            0x5201 => self.split_scroll = data,
+            0x5202 => self.split_bank = data,
+            0x5203 => self.irq_compare = data,
+            0x5204 => self.irq_enabled = data & 0x80 != 0,
+            0x5205 => self.multiplicand = data,
+            0x5206 => self.multiplier = data,
+            // read-only in mode 3
+            0x5C00..=0x5FFF if self.exram_mode != 3 => self.exram[(addr - 0x5C00) as usize] = data,
+            0x6000..=0xFFFF => {
+                let (rom, bank) = self.prg_bank(addr);
+                if !rom && self.prg_ram_protect == [2, 1] {
+                    self.prg_ram[bank_offset(PRG_RAM_SIZE, bank, 0x2000, (addr & 0x1FFF) as usize)] = data;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        let bank = self.chr_bank(false, addr);
+        self.chr.read(bank, 0x400, (addr & 0x3FF) as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        let bank = self.chr_bank(false, addr);
+        self.chr.write(bank, 0x400, (addr & 0x3FF) as usize, data);
+    }
+
+    fn read_sprite_chr(&mut self, addr: u16) -> u8 {
+        let bank = self.chr_bank(true, addr);
+        self.chr.read(bank, 0x400, (addr & 0x3FF) as usize)
+    }
+
+    fn background_row(&mut self, fetch: &BackgroundFetch) -> Option<TileRow> {
+        let row = |chr: &Chr, bank: usize, tile: u8, fine_y: u8, palette: u8| {
+            let offset = tile as usize * 16 + fine_y as usize;
+            TileRow { low: chr.read(bank, 0x1000, offset), high: chr.read(bank, 0x1000, offset + 8), palette }
+        };
+
+        if self.in_split(fetch.column) {
+            // the split is a nametable of its own in ExRAM, scrolled on its own
+            let y = (fetch.scanline + self.split_scroll as usize) % 240;
+            let column = fetch.column % 32;
+            let tile = self.exram[y / 8 * 32 + column];
+            let attribute = self.exram[0x3C0 + y / 32 * 8 + column / 4];
+            let shift = (y / 16 % 2) * 4 + (column / 2 % 2) * 2;
+            let palette = (attribute >> shift) & 0x03;
+            return Some(row(&self.chr, self.split_bank as usize, tile, (y % 8) as u8, palette));
+        }
+        if self.exram_mode == 1 {
+            // a 4KB CHR bank and the palette for every tile
+            let extended = self.exram[(fetch.addr & 0x3FF) as usize];
+            let bank = (self.chr_upper as usize) << 6 | (extended & 0x3F) as usize;
+            return Some(row(&self.chr, bank, fetch.tile, fetch.fine_y, extended >> 6));
+        }
+        // the PPU's own fetch, through read_chr and its choice of banks
+        None
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.nametable_mapping {
+            0x44 => Mirroring::Vertical,
+            0x00 => Mirroring::SingleScreenLower,
+            0x55 => Mirroring::SingleScreenUpper,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn nametable(&self, index: u16) -> Option<Nametable> {
+        match self.nametable_mode(index) {
+            page @ 0..=1 => Some(Nametable::Vram(page as u16)),
+            _ => Some(Nametable::Cartridge),
+        }
+    }
+
+    fn read_nametable(&mut self, addr: u16) -> u8 {
+        let offset = (addr & 0x3FF) as usize;
+        match self.nametable_mode((addr & 0x0FFF) / 0x400) {
+            // ExRAM as RAM reads as zeros on the PPU side
+            2 if self.exram_mode <= 1 => self.exram[offset],
+            2 => 0,
+            _ if offset < 0x3C0 => self.fill_tile,
+            _ => self.fill_attribute,
+        }
+    }
+
+    fn write_nametable(&mut self, addr: u16, data: u8) {
+        if self.nametable_mode((addr & 0x0FFF) / 0x400) == 2 && self.exram_mode <= 1 {
+            self.exram[(addr & 0x3FF) as usize] = data;
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x2000 => self.large_sprites = data & 0x20 != 0,
+            // rendering off: the scanline counter stops
+            0x2001 if data & 0x18 == 0 => self.in_frame = false,
+            _ => {}
+        }
+    }
+
+    fn ppu_scanline(&mut self, scanline: u16, rendering: bool) {
+        if !rendering || scanline >= 240 {
+            self.in_frame = false;
+            return;
+        }
+        if !self.in_frame {
+            self.in_frame = true;
+            self.scanline = 0;
+            return;
+        }
+        self.scanline = self.scanline.wrapping_add(1);
+        if self.scanline == self.irq_compare {
+            self.irq_pending = true;
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending && self.irq_enabled
+    }
+
+    fn expansion_audio(&self) -> Option<SharedExpansionAudio> {
+        Some(self.audio.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::numbered_rom;
+
+    fn mmc5() -> Mmc5 {
+        Mmc5::new(&numbered_rom(32, 0x400, 256))
+    }
+
+    fn fetch(addr: u16, tile: u8, column: usize, scanline: usize) -> BackgroundFetch {
+        BackgroundFetch { addr, tile, palette: 0, fine_y: 0, column, scanline }
+    }
+
+    #[test]
+    fn test_prg_modes_and_ram() {
+        let mut mmc5 = mmc5();
+        // mode 3: four 8KB banks, the last one from $5117
+        mmc5.write(0x5114, 0x85);
+        mmc5.write(0x5115, 0x86);
+        mmc5.write(0x5116, 0x87);
+        mmc5.write(0x5117, 0x09);
+        assert_eq!(mmc5.read(0x8000), 5);
+        assert_eq!(mmc5.read(0xA000), 6);
+        assert_eq!(mmc5.read(0xC000), 7);
+        assert_eq!(mmc5.read(0xE000), 9);
+
+        // mode 1: 16KB banks, the low bit of the register ignored
+        mmc5.write(0x5100, 1);
+        assert_eq!(mmc5.read(0x8000), 6);
+        assert_eq!(mmc5.read(0xA000), 7);
+        assert_eq!(mmc5.read(0xC000), 8);
+        assert_eq!(mmc5.read(0xE000), 9);
+
+        // RAM at $C000 in mode 2, writable once unprotected
+        mmc5.write(0x5100, 2);
+        mmc5.write(0x5116, 0x01);
+        mmc5.write(0xC000, 0x55);
+        assert_eq!(mmc5.read(0xC000), 0);
+        mmc5.write(0x5102, 2);
+        mmc5.write(0x5103, 1);
+        mmc5.write(0xC000, 0x55);
+        assert_eq!(mmc5.read(0xC000), 0x55);
+        // the same RAM bank at $6000
+        mmc5.write(0x5113, 0x01);
+        assert_eq!(mmc5.read(0x6000), 0x55);
+    }
+
+    #[test]
+    fn test_chr_sets() {
+        let mut mmc5 = mmc5();
+        mmc5.write(0x5123, 10);
+        mmc5.write(0x512B, 20);
+        // 8x8 sprites: the set written last for everything
+        assert_eq!(mmc5.read_chr(0x0C00), 20);
+        assert_eq!(mmc5.read_sprite_chr(0x0C00), 20);
+
+        // 8x16 sprites: sprites from the first set, background from the second
+        mmc5.ppu_write(0x2000, 0x20);
+        assert_eq!(mmc5.read_sprite_chr(0x0C00), 10);
+        assert_eq!(mmc5.read_chr(0x0C00), 20);
+        assert_eq!(mmc5.read_chr(0x1C00), 20);
+
+        // 4KB mode with the upper bits: bank $105, 1KB banks $414-$417
+        mmc5.write(0x5101, 1);
+        mmc5.write(0x5130, 1);
+        mmc5.write(0x5127, 5);
+        assert_eq!(mmc5.read_sprite_chr(0x1000), (0x414 % 256) as u8);
+        assert_eq!(mmc5.read_sprite_chr(0x1FFF), (0x417 % 256) as u8);
+    }
+
+    #[test]
+    fn test_nametables_and_extended_attributes() {
+        let mut mmc5 = mmc5();
+        // $2000 VRAM page 1, $2400 ExRAM, $2800 fill, $2C00 VRAM page 0
+        mmc5.write(0x5105, 0b00_11_10_01);
+        mmc5.write(0x5106, 0x42);
+        mmc5.write(0x5107, 0x02);
+        assert!(matches!(mmc5.nametable(0), Some(Nametable::Vram(1))));
+        assert!(matches!(mmc5.nametable(3), Some(Nametable::Vram(0))));
+        mmc5.write_nametable(0x2405, 0x77);
+        assert_eq!(mmc5.read_nametable(0x2405), 0x77);
+        assert_eq!(mmc5.read_nametable(0x2800), 0x42);
+        assert_eq!(mmc5.read_nametable(0x2BC0), 0xAA);
+
+        // the PPU fetches the background itself
+        assert_eq!(mmc5.background_row(&fetch(0x2000, 1, 0, 0)), None);
+
+        // extended attributes: bank and palette from ExRAM at the tile's offset
+        mmc5.write(0x5104, 1);
+        mmc5.exram[0x005] = 0b10_000011;
+        let row = mmc5.background_row(&fetch(0x2005, 2, 5, 0)).unwrap();
+        // 4KB bank 3 is 1KB banks 12-15, tile 2 is in the first
+        assert_eq!(row, TileRow { low: 12, high: 12, palette: 2 });
+    }
+
+    #[test]
+    fn test_split_screen() {
+        let mut mmc5 = mmc5();
+        mmc5.write(0x5104, 1);
+        // left of column 4, scrolled down 16 lines, from 4KB bank 2
+        mmc5.write(0x5200, 0x84);
+        mmc5.write(0x5201, 16);
+        mmc5.write(0x5202, 2);
+        mmc5.exram[2 * 32 + 3] = 1;
+        mmc5.exram[0x3C0] = 0b1100_0000;
+        let row = mmc5.background_row(&fetch(0x2000, 0, 3, 0)).unwrap();
+        assert_eq!(row, TileRow { low: 8, high: 8, palette: 3 });
+        // column 4 is right of the split, ExRAM attributes apply
+        assert_eq!(mmc5.background_row(&fetch(0x2004, 0, 4, 0)).unwrap().palette, 0);
+    }
+
+    #[test]
+    fn test_scanline_irq_and_multiplier() {
+        let mut mmc5 = mmc5();
+        mmc5.write(0x5203, 3);
+        mmc5.write(0x5204, 0x80);
+        for scanline in 0..3 {
+            mmc5.ppu_scanline(scanline, true);
+        }
+        assert!(!mmc5.irq());
+        mmc5.ppu_scanline(3, true);
+        assert!(mmc5.irq());
+        assert_eq!(mmc5.read(0x5204), 0xC0);
+        assert!(!mmc5.irq());
+        mmc5.ppu_scanline(240, true);
+        assert_eq!(mmc5.read(0x5204), 0x00);
+
+        mmc5.write(0x5205, 200);
+        mmc5.write(0x5206, 100);
+        assert_eq!(mmc5.read(0x5205), (20000 & 0xFF) as u8);
+        assert_eq!(mmc5.read(0x5206), (20000 >> 8) as u8);
+    }
+
+    #[test]
+    fn test_audio() {
+        let mut mmc5 = mmc5();
+        let audio = mmc5.expansion_audio().unwrap();
+        let mut levels = [0.0; 3];
+
+        mmc5.write(0x5011, 0x80);
+        mmc5.write(0x5011, 0x00);
+        audio.borrow().output(&mut levels);
+        assert_eq!(levels[2], 0x80 as f32 * PCM_STEP);
+
+        // pulse 1: constant volume 15, 50% duty, length loaded once enabled
+        mmc5.write(0x5015, 0x01);
+        mmc5.write(0x5000, 0xBF);
+        mmc5.write(0x5002, 0x10);
+        mmc5.write(0x5003, 0x08);
+        assert_eq!(mmc5.read(0x5015), 0x01);
+        let mut high = false;
+        for _ in 0..0x100 {
+            audio.borrow_mut().tick();
+            audio.borrow().output(&mut levels);
+            high |= levels[0] == PULSE_FULL_VOLUME;
+        }
+        assert!(high);
+        assert_eq!(levels[1], 0.0);
+
+        // a low note, which a sweep unit would have muted
+        mmc5.write(0x5002, 0x00);
+        mmc5.write(0x5003, 0x0E);
+        let mut high = false;
+        for _ in 0..0x2000 {
+            audio.borrow_mut().tick();
+            audio.borrow().output(&mut levels);
+            high |= levels[0] == PULSE_FULL_VOLUME;
+        }
+        assert!(high);
+    }
+}
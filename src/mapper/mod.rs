@@ -0,0 +1,294 @@
+// Cartridge hardware: everything from $4020 to $FFFF as the CPU sees it, the
+// pattern tables at $0000-$1FFF as the PPU sees them, and the nametable
+// mirroring, IRQs and sound chips that some boards add.
+// http://wiki.nesdev.com/w/index.php/Mapper
+pub mod discrete;
+pub mod fds;
+pub mod fme7;
+pub mod mmc2;
+pub mod mmc5;
+pub mod namco163;
+pub mod nrom;
+pub mod nsf;
+pub mod vrc4;
+pub mod vrc6;
+pub mod vrc_irq;
+
+use crate::apu::expansion::SharedExpansionAudio;
+use crate::cartridge::{Mirroring, Rom};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const CHR_RAM_SIZE: usize = 0x2000;
+
+pub trait Mapper {
+    /// CPU read from $4020-$FFFF
+    fn read(&mut self, addr: u16) -> u8;
+    /// CPU write to $4020-$FFFF
+    fn write(&mut self, addr: u16, data: u8);
+
+    /// PPU read from the pattern tables, $0000-$1FFF
+    fn read_chr(&mut self, _addr: u16) -> u8 {
+        0
+    }
+
+    fn write_chr(&mut self, _addr: u16, _data: u8) {}
+
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::Horizontal
+    }
+
+    /// Where the 1KB nametable `index` ($2000, $2400, $2800, $2C00) comes from,
+    /// for boards that map each one themselves. `None` follows `mirroring()`.
+    fn nametable(&self, _index: u16) -> Option<Nametable> {
+        None
+    }
+
+    /// PPU read from a nametable mapped to `Nametable::Cartridge`
+    fn read_nametable(&mut self, _addr: u16) -> u8 {
+        0
+    }
+
+    fn write_nametable(&mut self, _addr: u16, _data: u8) {}
+
+    /// Background pattern fetch for one row of a tile, for boards that
+    /// substitute it. `None` lets the PPU fetch it from the pattern tables.
+    fn background_row(&mut self, _fetch: &BackgroundFetch) -> Option<TileRow> {
+        None
+    }
+
+    /// PPU read from the pattern tables for sprites, where boards with
+    /// separate sprite banks differ from `read_chr`
+    fn read_sprite_chr(&mut self, addr: u16) -> u8 {
+        self.read_chr(addr)
+    }
+
+    /// Pattern table address the PPU has just read, while rendering or through
+    /// $2007, in the order it reads them, for boards that switch banks on it
+    fn pattern_fetch(&mut self, _addr: u16) {}
+
+    /// CPU write to a PPU register ($2000-$2007), for boards that watch them
+    fn ppu_write(&mut self, _addr: u16, _data: u8) {}
+
+    /// Start of a scanline, 0-239 visible, for boards that count them
+    fn ppu_scanline(&mut self, _scanline: u16, _rendering: bool) {}
+
+    /// Advances one CPU cycle, for boards with IRQ counters
+    fn tick(&mut self) {}
+
+    /// Level of the cartridge's IRQ output to the CPU
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /// Sound chip on the cartridge, mixed in by the APU
+    fn expansion_audio(&self) -> Option<SharedExpansionAudio> {
+        None
+    }
+}
+
+pub enum Nametable {
+    /// One of the two 1KB pages of the console's VRAM
+    Vram(u16),
+    /// Memory on the cartridge, through `read_nametable` and `write_nametable`
+    Cartridge,
+}
+
+/// A background tile row the PPU is about to fetch
+pub struct BackgroundFetch {
+    /// Nametable address of the tile, $2000-$2FFF
+    pub addr: u16,
+    /// Tile and palette (0-3) as read from the nametable and attribute table
+    pub tile: u8,
+    pub palette: u8,
+    /// Row in the tile, 0-7
+    pub fine_y: u8,
+    /// Which of the 33 tile fetches of the scanline this is
+    pub column: usize,
+    pub scanline: usize,
+}
+
+/// Pattern bytes of one tile row and the palette it's drawn with
+#[derive(Debug, PartialEq)]
+pub struct TileRow {
+    pub low: u8,
+    pub high: u8,
+    pub palette: u8,
+}
+
+/// The mapper is wired to both the CPU bus and the PPU
+pub type SharedMapper = Rc<RefCell<Box<dyn Mapper>>>;
+
+/// Offset into memory of `len` bytes of byte `offset` in bank `bank` of `size` bytes.
+/// Bank numbers past the end wrap around, like the unused high bank bits of a board.
+pub fn bank_offset(len: usize, bank: usize, size: usize, offset: usize) -> usize {
+    (bank * size) % len.max(size) + offset
+}
+
+/// PRG ROM, read a bank at a time
+pub struct Prg {
+    data: Vec<u8>,
+}
+
+impl Prg {
+    pub fn new(prg_rom: Vec<u8>) -> Self {
+        Prg { data: prg_rom }
+    }
+
+    /// Byte `offset` of bank `bank` of `size` bytes, 0 without any PRG ROM
+    pub fn read(&self, bank: usize, size: usize, offset: usize) -> u8 {
+        if self.data.is_empty() {
+            return 0;
+        }
+        self.data[bank_offset(self.data.len(), bank, size, offset) % self.data.len()]
+    }
+
+    /// Number of banks of `size` bytes, at least one
+    pub fn banks(&self, size: usize) -> usize {
+        (self.data.len() / size).max(1)
+    }
+
+    /// The last bank of `size` bytes, which boards usually fix at $E000 or $C000
+    pub fn last_bank(&self, size: usize) -> usize {
+        self.banks(size) - 1
+    }
+}
+
+/// CHR ROM, or CHR RAM on boards that come without
+pub struct Chr {
+    data: Vec<u8>,
+    writable: bool,
+}
+
+impl Chr {
+    pub fn new(chr_rom: Vec<u8>) -> Self {
+        if chr_rom.is_empty() {
+            Chr { data: vec![0; CHR_RAM_SIZE], writable: true }
+        } else {
+            Chr { data: chr_rom, writable: false }
+        }
+    }
+
+    /// Byte `offset` of bank `bank` of `size` bytes
+    pub fn read(&self, bank: usize, size: usize, offset: usize) -> u8 {
+        self.data[bank_offset(self.data.len(), bank, size, offset) % self.data.len()]
+    }
+
+    pub fn write(&mut self, bank: usize, size: usize, offset: usize, data: u8) {
+        if self.writable {
+            let len = self.data.len();
+            self.data[bank_offset(len, bank, size, offset) % len] = data;
+        }
+    }
+}
+
+/// Mapper hardware for the board of `rom`
+pub fn for_rom(rom: &Rom) -> Box<dyn Mapper> {
+    if let Some(board) = discrete::Board::for_rom(rom) {
+        return Box::new(discrete::Discrete::new(rom, board));
+    }
+    match rom.mapper {
+        5 => Box::new(mmc5::Mmc5::new(rom)),
+        9 => Box::new(mmc2::Mmc2::new(rom, false)),
+        10 => Box::new(mmc2::Mmc2::new(rom, true)),
+        19 => Box::new(namco163::Namco163::new(rom)),
+        21 | 22 | 23 | 25 => Box::new(vrc4::Vrc4::new(rom)),
+        24 => Box::new(vrc6::Vrc6::new(rom, false)),
+        26 => Box::new(vrc6::Vrc6::new(rom, true)),
+        69 => Box::new(fme7::Fme7::new(rom)),
+        // boards without an implementation run as NROM, as they always have
+        _ => Box::new(nrom::Nrom::new(rom.prg_rom.clone(), rom.chr_rom.clone(), rom.screen_mirroring.clone())),
+    }
+}
+
+/// Name of the board `for_rom` runs `rom` on, `None` if it falls back to NROM
+pub fn board_name(rom: &Rom) -> Option<&'static str> {
+    if let Some(board) = discrete::Board::for_rom(rom) {
+        return Some(board.name());
+    }
+    let name = match (rom.mapper, rom.submapper) {
+        (0, _) => "NROM",
+        (5, _) => "MMC5",
+        (9, _) => "MMC2",
+        (10, _) => "MMC4",
+        (19, _) => "Namco 163",
+        (21, 1) => "VRC4a",
+        (21, 2) => "VRC4c",
+        (21, _) => "VRC4a/VRC4c",
+        (22, _) => "VRC2a",
+        (23, 1) => "VRC4f",
+        (23, 2) => "VRC4e",
+        (23, 3) => "VRC2b",
+        (23, _) => "VRC4e/VRC4f",
+        (24, _) => "VRC6a",
+        (25, 1) => "VRC4b",
+        (25, 2) => "VRC4d",
+        (25, 3) => "VRC2c",
+        (25, _) => "VRC4b/VRC4d",
+        (26, _) => "VRC6b",
+        (69, _) => "Sunsoft FME-7",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// "mapper 23.2 (VRC4e)", with the NES 2.0 submapper if there is one
+pub fn describe(rom: &Rom) -> String {
+    let number = if rom.submapper == 0 {
+        rom.mapper.to_string()
+    } else {
+        format!("{}.{}", rom.mapper, rom.submapper)
+    };
+    match board_name(rom) {
+        Some(name) => format!("mapper {} ({})", number, name),
+        None => format!("mapper {} (not supported, runs as NROM)", number),
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    /// `test_rom` with 8KB PRG banks and `chr_size` CHR banks filled with their
+    /// bank number, so a read shows which bank is mapped
+    pub fn numbered_rom(prg_banks: usize, chr_size: usize, chr_banks: usize) -> Rom {
+        let mut rom = test_rom();
+        rom.prg_rom = (0..prg_banks).flat_map(|bank| vec![bank as u8; 0x2000]).collect();
+        rom.chr_rom = (0..chr_banks).flat_map(|bank| vec![bank as u8; chr_size]).collect();
+        rom
+    }
+
+    #[test]
+    fn test_bank_offset_wraps() {
+        assert_eq!(bank_offset(0x8000, 1, 0x2000, 0x10), 0x2010);
+        assert_eq!(bank_offset(0x8000, 5, 0x2000, 0x10), 0x2010);
+
+        let mut chr_ram = Chr::new(Vec::new());
+        chr_ram.write(1, 0x400, 3, 0x55);
+        assert_eq!(chr_ram.read(0, 0x1000, 0x403), 0x55);
+        let mut chr_rom = Chr::new(vec![1; 0x2000]);
+        chr_rom.write(0, 0x400, 0, 0x55);
+        assert_eq!(chr_rom.read(0, 0x400, 0), 1);
+
+        let prg = Prg::new(numbered_rom(6, 0x400, 0).prg_rom);
+        assert_eq!(prg.read(7, 0x2000, 0), 1);
+        assert_eq!(prg.last_bank(0x4000), 2);
+        assert_eq!(Prg::new(Vec::new()).read(3, 0x2000, 0), 0);
+    }
+
+    #[test]
+    fn test_describe() {
+        let mut rom = test_rom();
+        rom.mapper = 0;
+        assert_eq!(describe(&rom), "mapper 0 (NROM)");
+        rom.mapper = 23;
+        rom.submapper = 2;
+        assert_eq!(describe(&rom), "mapper 23.2 (VRC4e)");
+        rom.mapper = 66;
+        rom.submapper = 0;
+        assert_eq!(describe(&rom), "mapper 66 (GxROM)");
+        rom.mapper = 4;
+        assert_eq!(describe(&rom), "mapper 4 (not supported, runs as NROM)");
+    }
+}
@@ -0,0 +1,366 @@
+// Namco 163, mapper 19. Three switchable 8KB PRG banks and a fixed last one,
+// eight 1KB CHR banks, four nametables that can each be console VRAM or a
+// CHR ROM bank, a 15-bit IRQ counter clocked by the CPU, and a wavetable
+// sound chip with up to eight channels.
+// http://wiki.nesdev.com/w/index.php/INES_Mapper_019
+// http://wiki.nesdev.com/w/index.php/Namco_163_audio
+use super::{Chr, Mapper, Nametable, Prg};
+use crate::apu::expansion::{ExpansionAudio, SharedExpansionAudio, PULSE_FULL_VOLUME};
+use crate::cartridge::{Mirroring, Rom};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const PRG_RAM_SIZE: usize = 0x2000;
+// CPU cycles the chip spends on each channel
+const CHANNEL_CYCLES: u8 = 15;
+// a full-volume wave swings about twice as far as an APU pulse
+const LEVEL_STEP: f32 = PULSE_FULL_VOLUME / 120.0;
+// bank numbers from here up select a page of the console's VRAM
+const VRAM_BANKS: u8 = 0xE0;
+
+/// The sound chip: 128 bytes of RAM holding the waveforms and, from $40 up,
+/// the registers of channels 8 (at $78) down to 1 (at $40). The chip updates
+/// one channel every 15 cycles and only outputs that one, so with more
+/// channels on each plays a smaller share of the time, and switching between
+/// them is heard as a whine.
+pub struct Namco163Audio {
+    ram: [u8; 0x80],
+    // address port, bit 7 increments it after each access
+    addr: u8,
+    // the channel being updated and output, 7 is channel 8
+    current: usize,
+    timer: u8,
+    // output of the current channel, -8..7 times the volume
+    output: i16,
+    pub muted: bool,
+}
+
+impl Namco163Audio {
+    pub fn new() -> Self {
+        Namco163Audio { ram: [0; 0x80], addr: 0, current: 7, timer: 0, output: 0, muted: false }
+    }
+
+    /// Write to $F800-$FFFF
+    pub fn write_addr(&mut self, data: u8) {
+        self.addr = data;
+    }
+
+    fn next_addr(&mut self) -> usize {
+        let addr = (self.addr & 0x7F) as usize;
+        if self.addr & 0x80 != 0 {
+            self.addr = 0x80 | (self.addr.wrapping_add(1) & 0x7F);
+        }
+        addr
+    }
+
+    /// Read from $4800-$4FFF
+    pub fn read_data(&mut self) -> u8 {
+        let addr = self.next_addr();
+        self.ram[addr]
+    }
+
+    /// Write to $4800-$4FFF
+    pub fn write_data(&mut self, data: u8) {
+        let addr = self.next_addr();
+        self.ram[addr] = data;
+    }
+
+    fn enabled_channels(&self) -> usize {
+        ((self.ram[0x7F] >> 4) & 0x07) as usize + 1
+    }
+
+    fn sample(&self, addr: u8) -> i16 {
+        let byte = self.ram[(addr >> 1) as usize & 0x7F];
+        let nibble = if addr & 1 == 0 { byte & 0x0F } else { byte >> 4 };
+        nibble as i16 - 8
+    }
+
+    // advances the phase of a channel and returns its output
+    fn update(&mut self, channel: usize) -> i16 {
+        let base = 0x40 + channel * 8;
+        let regs = &self.ram[base..base + 8];
+        let frequency = regs[0] as u32 | (regs[2] as u32) << 8 | ((regs[4] & 0x03) as u32) << 16;
+        let phase = regs[1] as u32 | (regs[3] as u32) << 8 | (regs[5] as u32) << 16;
+        let length = (256 - (regs[4] & 0xFC) as u32) << 16;
+        let offset = regs[6] as u32;
+        let volume = (regs[7] & 0x0F) as i16;
+
+        let phase = (phase + frequency) % length;
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+        self.sample(((phase >> 16) + offset) as u8) * volume
+    }
+}
+
+impl Default for Namco163Audio {
+    fn default() -> Self {
+        Namco163Audio::new()
+    }
+}
+
+impl ExpansionAudio for Namco163Audio {
+    fn channel_names(&self) -> &'static [&'static str] {
+        &["n163 1", "n163 2", "n163 3", "n163 4", "n163 5", "n163 6", "n163 7", "n163 8"]
+    }
+
+    fn tick(&mut self) {
+        self.timer += 1;
+        if self.timer < CHANNEL_CYCLES {
+            return;
+        }
+        self.timer = 0;
+        // channel 8 down to 8 - enabled + 1, round and round
+        let lowest = 8 - self.enabled_channels();
+        self.current = if self.current <= lowest { 7 } else { self.current - 1 };
+        self.output = self.update(self.current);
+    }
+
+    fn output(&self, levels: &mut [f32]) {
+        for level in levels.iter_mut() {
+            *level = 0.0;
+        }
+        if !self.muted {
+            levels[self.current] = self.output as f32 * LEVEL_STEP;
+        }
+    }
+}
+
+pub struct Namco163 {
+    prg: Prg,
+    prg_ram: [u8; PRG_RAM_SIZE],
+    chr: Chr,
+    // $8000, $A000, $C000
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    nametable_banks: [u8; 4],
+    counter: u16,
+    counter_enabled: bool,
+    irq: bool,
+    audio: Rc<RefCell<Namco163Audio>>,
+}
+
+impl Namco163 {
+    pub fn new(rom: &Rom) -> Self {
+        // start out like the header's mirroring
+        let nametable_banks = match rom.screen_mirroring {
+            Mirroring::Vertical => [0xE0, 0xE1, 0xE0, 0xE1],
+            _ => [0xE0, 0xE0, 0xE1, 0xE1],
+        };
+        Namco163 {
+            prg: Prg::new(rom.prg_rom.clone()),
+            prg_ram: [0; PRG_RAM_SIZE],
+            chr: Chr::new(rom.chr_rom.clone()),
+            prg_banks: [0, 1, 2],
+            chr_banks: [0; 8],
+            nametable_banks,
+            counter: 0,
+            counter_enabled: false,
+            irq: false,
+            audio: Rc::new(RefCell::new(Namco163Audio::new())),
+        }
+    }
+}
+
+impl Mapper for Namco163 {
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4800..=0x4FFF => self.audio.borrow_mut().read_data(),
+            0x5000..=0x57FF => self.counter as u8,
+            0x5800..=0x5FFF => (self.counter >> 8) as u8 | (self.counter_enabled as u8) << 7,
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xDFFF => {
+                let bank = self.prg_banks[(addr as usize - 0x8000) / 0x2000] as usize;
+                self.prg.read(bank, 0x2000, (addr & 0x1FFF) as usize)
+            }
+            0xE000..=0xFFFF => self.prg.read(self.prg.last_bank(0x2000), 0x2000, (addr & 0x1FFF) as usize),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4800..=0x4FFF => self.audio.borrow_mut().write_data(data),
+            0x5000..=0x57FF => {
+                self.counter = (self.counter & 0x7F00) | data as u16;
+                self.irq = false;
+            }
+            0x5800..=0x5FFF => {
+                self.counter = (self.counter & 0x00FF) | ((data as u16 & 0x7F) << 8);
+                self.counter_enabled = data & 0x80 != 0;
+                self.irq = false;
+            }
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize] = data,
+            0x8000..=0xBFFF => self.chr_banks[(addr as usize - 0x8000) / 0x800] = data,
+            0xC000..=0xDFFF => self.nametable_banks[(addr as usize - 0xC000) / 0x800] = data,
+            0xE000..=0xE7FF => {
+                self.prg_banks[0] = data & 0x3F;
+                self.audio.borrow_mut().muted = data & 0x40 != 0;
+            }
+            0xE800..=0xEFFF => self.prg_banks[1] = data & 0x3F,
+            0xF000..=0xF7FF => self.prg_banks[2] = data & 0x3F,
+            0xF800..=0xFFFF => self.audio.borrow_mut().write_addr(data),
+            _ => {}
+        }
+    }
+
+    // banks $E0 and up on the pattern tables can also be VRAM, which nothing is
+    // known to use, they read from CHR ROM here
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        let bank = self.chr_banks[(addr as usize / 0x400) & 0x07];
+        self.chr.read(bank as usize, 0x400, (addr & 0x3FF) as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        let bank = self.chr_banks[(addr as usize / 0x400) & 0x07];
+        self.chr.write(bank as usize, 0x400, (addr & 0x3FF) as usize, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.nametable_banks {
+            [a, b, _, _] if a == b => Mirroring::Horizontal,
+            _ => Mirroring::Vertical,
+        }
+    }
+
+    fn nametable(&self, index: u16) -> Option<Nametable> {
+        let bank = self.nametable_banks[index as usize & 0x03];
+        if bank >= VRAM_BANKS {
+            Some(Nametable::Vram(bank as u16 & 1))
+        } else {
+            Some(Nametable::Cartridge)
+        }
+    }
+
+    fn read_nametable(&mut self, addr: u16) -> u8 {
+        let bank = self.nametable_banks[((addr & 0x0FFF) / 0x400) as usize];
+        self.chr.read(bank as usize, 0x400, (addr & 0x3FF) as usize)
+    }
+
+    fn write_nametable(&mut self, addr: u16, data: u8) {
+        let bank = self.nametable_banks[((addr & 0x0FFF) / 0x400) as usize];
+        self.chr.write(bank as usize, 0x400, (addr & 0x3FF) as usize, data);
+    }
+
+    fn tick(&mut self) {
+        if self.counter_enabled && self.counter < 0x7FFF {
+            self.counter += 1;
+            if self.counter == 0x7FFF {
+                self.irq = true;
+            }
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq
+    }
+
+    fn expansion_audio(&self) -> Option<SharedExpansionAudio> {
+        Some(self.audio.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::numbered_rom;
+
+    fn namco163() -> Namco163 {
+        Namco163::new(&numbered_rom(16, 0x400, 128))
+    }
+
+    #[test]
+    fn test_banking() {
+        let mut n163 = namco163();
+        n163.write(0xE000, 3);
+        n163.write(0xE800, 4);
+        n163.write(0xF000, 5);
+        assert_eq!(n163.read(0x8000), 3);
+        assert_eq!(n163.read(0xA000), 4);
+        assert_eq!(n163.read(0xC000), 5);
+        assert_eq!(n163.read(0xE000), 15);
+
+        n163.write(0xB800, 77);
+        assert_eq!(n163.read_chr(0x1C00), 77);
+    }
+
+    #[test]
+    fn test_nametables() {
+        let mut n163 = namco163();
+        // $2000 and $2C00 on VRAM page 1, $2400 on CHR bank 9
+        n163.write(0xC000, 0xE1);
+        n163.write(0xC800, 9);
+        n163.write(0xD800, 0xFF);
+        assert!(matches!(n163.nametable(0), Some(Nametable::Vram(1))));
+        assert!(matches!(n163.nametable(1), Some(Nametable::Cartridge)));
+        assert!(matches!(n163.nametable(3), Some(Nametable::Vram(1))));
+        assert_eq!(n163.read_nametable(0x2400), 9);
+    }
+
+    #[test]
+    fn test_irq_counter() {
+        let mut n163 = namco163();
+        n163.write(0x5000, 0xFD);
+        n163.write(0x5800, 0xFF);
+        n163.tick();
+        assert!(!n163.irq());
+        n163.tick();
+        assert!(n163.irq());
+        assert_eq!(n163.read(0x5000), 0xFF);
+        assert_eq!(n163.read(0x5800), 0xFF);
+        // stops at $7FFF
+        n163.tick();
+        assert_eq!(n163.read(0x5000), 0xFF);
+        n163.write(0x5000, 0);
+        assert!(!n163.irq());
+    }
+
+    fn write_ram(n163: &mut Namco163, addr: u8, data: &[u8]) {
+        n163.write(0xF800, 0x80 | addr);
+        for byte in data {
+            n163.write(0x4800, *byte);
+        }
+    }
+
+    #[test]
+    fn test_audio_ram_and_channels() {
+        let mut n163 = namco163();
+        let audio = n163.expansion_audio().unwrap();
+        // a 4-sample wave at $00: 15, 15, 0, 0
+        write_ram(&mut n163, 0x00, &[0xFF, 0x00]);
+        // channel 8: one sample a step, 4 samples long, volume 15, one channel on
+        write_ram(&mut n163, 0x78, &[0x00, 0x00, 0x00, 0x00, 0xFC, 0x00, 0x00, 0x0F]);
+        n163.write(0xF800, 0x78);
+        assert_eq!(n163.read(0x4800), 0x00);
+
+        let mut levels = [0.0; 8];
+        let mut outputs = Vec::new();
+        // nothing moves at frequency 0
+        for _ in 0..CHANNEL_CYCLES {
+            audio.borrow_mut().tick();
+        }
+        audio.borrow().output(&mut levels);
+        assert_eq!(levels[7], 7.0 * 15.0 * LEVEL_STEP);
+
+        write_ram(&mut n163, 0x78, &[0x00, 0x00, 0x00, 0x00, 0xFD]);
+        for _ in 0..4 {
+            for _ in 0..CHANNEL_CYCLES {
+                audio.borrow_mut().tick();
+            }
+            audio.borrow().output(&mut levels);
+            outputs.push(levels[7]);
+        }
+        let expected: Vec<f32> = [7.0, -8.0, -8.0, 7.0].iter().map(|sample| sample * 15.0 * LEVEL_STEP).collect();
+        assert_eq!(outputs, expected);
+
+        // two channels: 8 and 7 take turns, only one is heard at a time
+        write_ram(&mut n163, 0x7F, &[0x1F]);
+        write_ram(&mut n163, 0x77, &[0x0F]);
+        for _ in 0..CHANNEL_CYCLES {
+            audio.borrow_mut().tick();
+        }
+        audio.borrow().output(&mut levels);
+        assert_eq!(levels[7], 0.0);
+        assert_eq!(levels[6], 7.0 * 15.0 * LEVEL_STEP);
+    }
+}
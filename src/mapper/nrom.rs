@@ -0,0 +1,80 @@
+// Mapper 0: up to 32KB of PRG ROM and 8KB of CHR, no bank switching. 16KB
+// images are mirrored into $C000-$FFFF. Boards with PRG RAM (Family Basic)
+// have it at $6000.
+use super::{Chr, Mapper};
+use crate::cartridge::Mirroring;
+
+const PRG_RAM_SIZE: usize = 0x2000;
+
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    prg_ram: [u8; PRG_RAM_SIZE],
+    chr: Chr,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        Nrom {
+            prg_rom,
+            prg_ram: [0; PRG_RAM_SIZE],
+            chr: Chr::new(chr_rom),
+            mirroring,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xFFFF => {
+                if self.prg_rom.is_empty() {
+                    return 0;
+                }
+                //mirror if needed
+                self.prg_rom[(addr - 0x8000) as usize % self.prg_rom.len()]
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7FFF = addr {
+            self.prg_ram[(addr - 0x6000) as usize] = data;
+        }
+    }
+
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        self.chr.read(0, 0x2000, addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        self.chr.write(0, 0x2000, addr as usize, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_16k_prg_is_mirrored() {
+        let mut prg_rom = vec![0; 0x4000];
+        prg_rom[0x1234] = 0x42;
+        let mut nrom = Nrom::new(prg_rom, Vec::new(), Mirroring::Vertical);
+        assert_eq!(nrom.read(0x9234), 0x42);
+        assert_eq!(nrom.read(0xD234), 0x42);
+
+        nrom.write(0x6001, 0x55);
+        assert_eq!(nrom.read(0x6001), 0x55);
+
+        // no CHR ROM: CHR RAM
+        nrom.write_chr(0x1FFF, 0x66);
+        assert_eq!(nrom.read_chr(0x1FFF), 0x66);
+    }
+}
@@ -0,0 +1,94 @@
+// Memory map of an NSF player: 8KB of RAM at $6000 and the tune's data in
+// eight 4KB banks at $8000-$FFFF, switched by writes to $5FF8-$5FFF.
+// http://wiki.nesdev.com/w/index.php/NSF#Bankswitching
+use super::Mapper;
+
+const BANK_SIZE: usize = 0x1000;
+
+pub struct NsfMapper {
+    data: Vec<u8>,
+    banks: [u8; 8],
+    banked: bool,
+    ram: [u8; 0x2000],
+}
+
+impl NsfMapper {
+    /// `bank_init` all zero means the tune isn't bankswitched and sits at `load_address`
+    pub fn new(data: &[u8], load_address: u16, bank_init: [u8; 8]) -> Self {
+        let banked = bank_init.iter().any(|bank| *bank != 0);
+        // bankswitched data is aligned to banks by the low bits of the load address only
+        let padding = if banked {
+            (load_address & 0x0FFF) as usize
+        } else {
+            load_address.saturating_sub(0x8000) as usize
+        };
+        let size = (padding + data.len()).div_ceil(BANK_SIZE) * BANK_SIZE;
+        let mut image = vec![0; size.max(BANK_SIZE)];
+        image[padding..padding + data.len()].copy_from_slice(data);
+
+        NsfMapper {
+            data: image,
+            banks: if banked { bank_init } else { [0, 1, 2, 3, 4, 5, 6, 7] },
+            banked,
+            ram: [0; 0x2000],
+        }
+    }
+}
+
+impl Mapper for NsfMapper {
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.ram[(addr - 0x6000) as usize],
+            0x8000..=0xFFFF => {
+                let slot = (addr - 0x8000) as usize / BANK_SIZE;
+                let bank_count = self.data.len() / BANK_SIZE;
+                let bank = self.banks[slot] as usize % bank_count;
+                // banks past the end of a non-bankswitched tune read as open bus
+                if !self.banked && self.banks[slot] as usize >= bank_count {
+                    return 0;
+                }
+                self.data[bank * BANK_SIZE + (addr as usize & (BANK_SIZE - 1))]
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5FF8..=0x5FFF if self.banked => self.banks[(addr - 0x5FF8) as usize] = data,
+            0x6000..=0x7FFF => self.ram[(addr - 0x6000) as usize] = data,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unbanked_data_sits_at_load_address() {
+        let mut mapper = NsfMapper::new(&[0xA9, 0x01], 0x8123, [0; 8]);
+        assert_eq!(mapper.read(0x8123), 0xA9);
+        assert_eq!(mapper.read(0x8124), 0x01);
+        assert_eq!(mapper.read(0xC123), 0x00);
+        mapper.write(0x5FF8, 1);
+        assert_eq!(mapper.read(0x8123), 0xA9);
+    }
+
+    #[test]
+    fn test_bankswitching() {
+        // two banks, padded by the low bits of the load address
+        let mut data = vec![0; 0x2000 - 0x100];
+        data[0] = 0x11;
+        data[0x1000 - 0x100] = 0x22;
+        let mut mapper = NsfMapper::new(&data, 0x8100, [0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(mapper.read(0x8100), 0x11);
+        assert_eq!(mapper.read(0x9000), 0x22);
+
+        mapper.write(0x5FFF, 1);
+        assert_eq!(mapper.read(0xF000), 0x22);
+        mapper.write(0x5FF8, 1);
+        assert_eq!(mapper.read(0x8000), 0x22);
+    }
+}
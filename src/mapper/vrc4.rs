@@ -0,0 +1,259 @@
+// Konami VRC2 and VRC4, mappers 21, 22, 23 and 25. Two switchable 8KB PRG
+// banks (VRC4 can swap the first with the fixed $C000 bank), eight 1KB CHR
+// banks set a nibble at a time, mirroring control, and on VRC4 the VRC IRQ
+// counter. Each register group has four registers, selected by two CPU
+// address lines that every board wires differently; the NES 2.0 submapper
+// says which, and without one the lines of all the boards sharing the mapper
+// number are decoded at once.
+// http://wiki.nesdev.com/w/index.php/VRC2_and_VRC4
+use super::vrc_irq::VrcIrq;
+use super::{Chr, Mapper, Prg};
+use crate::cartridge::{Mirroring, Rom};
+
+const PRG_RAM_SIZE: usize = 0x2000;
+
+/// Which CPU address lines select registers 1 and 2 of a group, and what the
+/// board is built around
+pub struct Wiring {
+    pub low_line: u16,
+    pub high_line: u16,
+    pub vrc2: bool,
+    // VRC2a ignores the lowest CHR bank bit
+    pub chr_shift: u8,
+}
+
+impl Wiring {
+    fn new(low_line: u16, high_line: u16, vrc2: bool) -> Self {
+        Wiring { low_line, high_line, vrc2, chr_shift: 0 }
+    }
+
+    /// The board of `mapper`, `submapper`
+    pub fn for_mapper(mapper: u16, submapper: u8) -> Self {
+        match (mapper, submapper) {
+            // VRC4a, VRC4c
+            (21, 1) => Wiring::new(0x02, 0x04, false),
+            (21, 2) => Wiring::new(0x40, 0x80, false),
+            (21, _) => Wiring::new(0x42, 0x84, false),
+            // VRC2a
+            (22, _) => Wiring { chr_shift: 1, ..Wiring::new(0x02, 0x01, true) },
+            // VRC4f, VRC4e, VRC2b
+            (23, 1) => Wiring::new(0x01, 0x02, false),
+            (23, 2) => Wiring::new(0x04, 0x08, false),
+            (23, 3) => Wiring::new(0x01, 0x02, true),
+            (23, _) => Wiring::new(0x05, 0x0A, false),
+            // VRC4b, VRC4d, VRC2c
+            (25, 1) => Wiring::new(0x02, 0x01, false),
+            (25, 2) => Wiring::new(0x08, 0x04, false),
+            (25, 3) => Wiring::new(0x02, 0x01, true),
+            _ => Wiring::new(0x0A, 0x05, false),
+        }
+    }
+
+    /// `addr` as $x000-$x003
+    fn register(&self, addr: u16) -> u16 {
+        let low = (addr & self.low_line != 0) as u16;
+        let high = (addr & self.high_line != 0) as u16;
+        (addr & 0xF000) | high << 1 | low
+    }
+}
+
+pub struct Vrc4 {
+    prg: Prg,
+    prg_ram: [u8; PRG_RAM_SIZE],
+    chr: Chr,
+    wiring: Wiring,
+    prg_banks: [u8; 2],
+    // VRC4: the first bank at $C000 and the second last at $8000
+    prg_swap: bool,
+    chr_banks: [u16; 8],
+    mirroring: Mirroring,
+    irq: VrcIrq,
+}
+
+impl Vrc4 {
+    pub fn new(rom: &Rom) -> Self {
+        Vrc4 {
+            prg: Prg::new(rom.prg_rom.clone()),
+            prg_ram: [0; PRG_RAM_SIZE],
+            chr: Chr::new(rom.chr_rom.clone()),
+            wiring: Wiring::for_mapper(rom.mapper, rom.submapper),
+            prg_banks: [0; 2],
+            prg_swap: false,
+            chr_banks: [0; 8],
+            mirroring: rom.screen_mirroring.clone(),
+            irq: VrcIrq::new(),
+        }
+    }
+
+    // register 0 and 1 of $B000-$E000 are the low and high bits of one bank,
+    // 2 and 3 of the next
+    fn write_chr_bank(&mut self, register: u16, data: u8) {
+        let bank = ((register >> 12) as usize - 0xB) * 2 + (register as usize & 0x02) / 2;
+        let value = (data & 0x0F) as u16;
+        self.chr_banks[bank] = if register & 0x01 == 0 {
+            (self.chr_banks[bank] & 0x1F0) | value
+        } else {
+            let mask = if self.wiring.vrc2 { 0x0F } else { 0x1F };
+            (self.chr_banks[bank] & 0x0F) | ((data & mask) as u16) << 4
+        };
+    }
+
+    fn chr_bank(&self, addr: u16) -> usize {
+        (self.chr_banks[(addr as usize / 0x400) & 0x07] >> self.wiring.chr_shift) as usize
+    }
+}
+
+impl Mapper for Vrc4 {
+    fn read(&mut self, addr: u16) -> u8 {
+        let offset = (addr & 0x1FFF) as usize;
+        let second_last = self.prg.banks(0x2000).max(2) - 2;
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram[offset],
+            0x8000..=0x9FFF if self.prg_swap => self.prg.read(second_last, 0x2000, offset),
+            0x8000..=0x9FFF => self.prg.read(self.prg_banks[0] as usize, 0x2000, offset),
+            0xA000..=0xBFFF => self.prg.read(self.prg_banks[1] as usize, 0x2000, offset),
+            0xC000..=0xDFFF if self.prg_swap => self.prg.read(self.prg_banks[0] as usize, 0x2000, offset),
+            0xC000..=0xDFFF => self.prg.read(second_last, 0x2000, offset),
+            0xE000..=0xFFFF => self.prg.read(second_last + 1, 0x2000, offset),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7FFF = addr {
+            self.prg_ram[(addr - 0x6000) as usize] = data;
+            return;
+        }
+        let register = self.wiring.register(addr);
+        match register {
+            0x8000..=0x8003 => self.prg_banks[0] = data & 0x1F,
+            0x9000..=0x9003 if self.wiring.vrc2 => {
+                self.mirroring = if data & 0x01 == 0 { Mirroring::Vertical } else { Mirroring::Horizontal };
+            }
+            0x9000..=0x9001 => {
+                self.mirroring = match data & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenLower,
+                    _ => Mirroring::SingleScreenUpper,
+                }
+            }
+            0x9002..=0x9003 => self.prg_swap = data & 0x02 != 0,
+            0xA000..=0xA003 => self.prg_banks[1] = data & 0x1F,
+            0xB000..=0xE003 => self.write_chr_bank(register, data),
+            0xF000..=0xF003 if self.wiring.vrc2 => {}
+            0xF000 => self.irq.write_latch_low(data),
+            0xF001 => self.irq.write_latch_high(data),
+            0xF002 => self.irq.write_control(data),
+            0xF003 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        self.chr.read(self.chr_bank(addr), 0x400, (addr & 0x3FF) as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        self.chr.write(self.chr_bank(addr), 0x400, (addr & 0x3FF) as usize, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+
+    fn tick(&mut self) {
+        self.irq.tick();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.irq()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::numbered_rom;
+
+    fn board(mapper: u16, submapper: u8) -> Vrc4 {
+        let mut rom = numbered_rom(16, 0x400, 256);
+        rom.mapper = mapper;
+        rom.submapper = submapper;
+        Vrc4::new(&rom)
+    }
+
+    #[test]
+    fn test_address_lines() {
+        // mapper, submapper, the lines selecting registers 1 and 2
+        let boards = [
+            (21, 1, 0x02, 0x04),
+            (21, 2, 0x40, 0x80),
+            (21, 0, 0x02, 0x04),
+            (21, 0, 0x40, 0x80),
+            (22, 0, 0x02, 0x01),
+            (23, 1, 0x01, 0x02),
+            (23, 2, 0x04, 0x08),
+            (23, 3, 0x01, 0x02),
+            (23, 0, 0x01, 0x02),
+            (23, 0, 0x04, 0x08),
+            (25, 1, 0x02, 0x01),
+            (25, 2, 0x08, 0x04),
+            (25, 3, 0x02, 0x01),
+            (25, 0, 0x02, 0x01),
+            (25, 0, 0x08, 0x04),
+        ];
+        for &(mapper, submapper, low, high) in boards.iter() {
+            let mut vrc4 = board(mapper, submapper);
+            // CHR banks 2 and 3: $C000/$C001 and $C002/$C003
+            vrc4.write(0xC000, 0x02);
+            vrc4.write(0xC000 | low, 0x01);
+            vrc4.write(0xC000 | high, 0x04);
+            vrc4.write(0xC000 | high | low, 0x02);
+            let (bank2, bank3) = if mapper == 22 { (0x12 >> 1, 0x24 >> 1) } else { (0x12, 0x24) };
+            assert_eq!(vrc4.read_chr(0x0800), bank2, "mapper {} submapper {}", mapper, submapper);
+            assert_eq!(vrc4.read_chr(0x0C00), bank3, "mapper {} submapper {}", mapper, submapper);
+
+            // $9000 and $9001 are both mirroring, on VRC2 too
+            vrc4.write(0x9000 | low, 0x01);
+            assert_eq!(vrc4.mirroring(), Mirroring::Horizontal, "mapper {} submapper {}", mapper, submapper);
+        }
+    }
+
+    #[test]
+    fn test_prg_swap_mode() {
+        let mut vrc4 = board(23, 2);
+        vrc4.write(0x8000, 3);
+        vrc4.write(0xA000, 5);
+        assert_eq!(vrc4.read(0x8000), 3);
+        assert_eq!(vrc4.read(0xA000), 5);
+        assert_eq!(vrc4.read(0xC000), 14);
+        assert_eq!(vrc4.read(0xE000), 15);
+
+        vrc4.write(0x9008, 0x02);
+        assert_eq!(vrc4.read(0x8000), 14);
+        assert_eq!(vrc4.read(0xC000), 3);
+
+        // VRC2 has no swap mode, $9002 is mirroring too
+        let mut vrc2 = board(23, 3);
+        vrc2.write(0x8000, 3);
+        vrc2.write(0x9002, 0x02);
+        assert_eq!(vrc2.read(0x8000), 3);
+        assert_eq!(vrc2.mirroring(), Mirroring::Vertical);
+    }
+
+    #[test]
+    fn test_irq_latch_nibbles() {
+        let mut vrc4 = board(21, 1);
+        vrc4.write(0xF000, 0x0D);
+        vrc4.write(0xF002, 0x0F);
+        vrc4.write(0xF004, 0x06);
+        vrc4.tick();
+        vrc4.tick();
+        assert!(!vrc4.irq());
+        vrc4.tick();
+        assert!(vrc4.irq());
+        vrc4.write(0xF006, 0);
+        assert!(!vrc4.irq());
+    }
+}
@@ -0,0 +1,387 @@
+// Konami VRC6, mappers 24 and 26 (the same chip with A0 and A1 swapped).
+// A switchable 16KB and 8KB PRG bank and a fixed last 8KB, eight 1KB CHR
+// banks, the VRC IRQ counter, and two pulse channels and a sawtooth mixed
+// into the Famicom's audio.
+// http://wiki.nesdev.com/w/index.php/VRC6
+// http://wiki.nesdev.com/w/index.php/VRC6_audio
+use super::vrc_irq::VrcIrq;
+use super::{Chr, Mapper, Prg};
+use crate::apu::expansion::{ExpansionAudio, SharedExpansionAudio, PULSE_FULL_VOLUME};
+use crate::cartridge::{Mirroring, Rom};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const PRG_RAM_SIZE: usize = 0x2000;
+
+struct Vrc6Pulse {
+    volume: u8,
+    duty: u8,
+    // ignore the duty, output the volume all the time
+    constant: bool,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+}
+
+impl Vrc6Pulse {
+    fn new() -> Self {
+        Vrc6Pulse { volume: 0, duty: 0, constant: false, enabled: false, period: 0, timer: 0, step: 15 }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.constant = data & 0x80 != 0;
+                self.duty = (data >> 4) & 0x07;
+                self.volume = data & 0x0F;
+            }
+            1 => self.period = (self.period & 0x0F00) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn tick(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 0x0F;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.constant || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+struct Vrc6Sawtooth {
+    rate: u8,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    // the accumulator takes the rate on every second of 14 steps
+    step: u8,
+    accumulator: u8,
+}
+
+impl Vrc6Sawtooth {
+    fn new() -> Self {
+        Vrc6Sawtooth { rate: 0, enabled: false, period: 0, timer: 0, step: 0, accumulator: 0 }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => self.rate = data & 0x3F,
+            1 => self.period = (self.period & 0x0F00) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn tick(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step & 1 == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled {
+            self.accumulator >> 3
+        } else {
+            0
+        }
+    }
+}
+
+pub struct Vrc6Audio {
+    pulses: [Vrc6Pulse; 2],
+    sawtooth: Vrc6Sawtooth,
+    halt: bool,
+    // the periods are shifted right by 4 or 8 bits
+    shift: u8,
+}
+
+impl Vrc6Audio {
+    pub fn new() -> Self {
+        Vrc6Audio { pulses: [Vrc6Pulse::new(), Vrc6Pulse::new()], sawtooth: Vrc6Sawtooth::new(), halt: false, shift: 0 }
+    }
+
+    /// Write to $9000-$B002, `addr` with the address lines already untangled
+    pub fn write(&mut self, addr: u16, data: u8) {
+        let register = addr & 0x0003;
+        match addr & 0xF003 {
+            0x9003 => {
+                self.halt = data & 0x01 != 0;
+                self.shift = if data & 0x04 != 0 {
+                    8
+                } else if data & 0x02 != 0 {
+                    4
+                } else {
+                    0
+                };
+            }
+            0x9000..=0x9002 => self.pulses[0].write(register, data),
+            0xA000..=0xA002 => self.pulses[1].write(register, data),
+            0xB000..=0xB002 => self.sawtooth.write(register, data),
+            _ => {}
+        }
+    }
+}
+
+impl Default for Vrc6Audio {
+    fn default() -> Self {
+        Vrc6Audio::new()
+    }
+}
+
+impl ExpansionAudio for Vrc6Audio {
+    fn channel_names(&self) -> &'static [&'static str] {
+        &["vrc6 pulse1", "vrc6 pulse2", "sawtooth"]
+    }
+
+    fn tick(&mut self) {
+        if self.halt {
+            return;
+        }
+        for pulse in self.pulses.iter_mut() {
+            pulse.tick(self.shift);
+        }
+        self.sawtooth.tick(self.shift);
+    }
+
+    fn output(&self, levels: &mut [f32]) {
+        // a pulse at volume 15 is about as loud as an APU pulse at 15
+        let step = PULSE_FULL_VOLUME / 15.0;
+        levels[0] = self.pulses[0].output() as f32 * step;
+        levels[1] = self.pulses[1].output() as f32 * step;
+        levels[2] = self.sawtooth.output() as f32 * step;
+    }
+}
+
+pub struct Vrc6 {
+    prg: Prg,
+    prg_ram: [u8; PRG_RAM_SIZE],
+    chr: Chr,
+    swap_lines: bool,
+    prg_16k: u8,
+    prg_8k: u8,
+    chr_banks: [u8; 8],
+    mirroring: Mirroring,
+    irq: VrcIrq,
+    audio: Rc<RefCell<Vrc6Audio>>,
+}
+
+impl Vrc6 {
+    /// `swap_lines` for mapper 26, which has A0 and A1 wired the other way around
+    pub fn new(rom: &Rom, swap_lines: bool) -> Self {
+        Vrc6 {
+            prg: Prg::new(rom.prg_rom.clone()),
+            prg_ram: [0; PRG_RAM_SIZE],
+            chr: Chr::new(rom.chr_rom.clone()),
+            swap_lines,
+            prg_16k: 0,
+            prg_8k: 0,
+            chr_banks: [0; 8],
+            mirroring: rom.screen_mirroring.clone(),
+            irq: VrcIrq::new(),
+            audio: Rc::new(RefCell::new(Vrc6Audio::new())),
+        }
+    }
+}
+
+impl Mapper for Vrc6 {
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xBFFF => self.prg.read(self.prg_16k as usize, 0x4000, (addr & 0x3FFF) as usize),
+            0xC000..=0xDFFF => self.prg.read(self.prg_8k as usize, 0x2000, (addr & 0x1FFF) as usize),
+            0xE000..=0xFFFF => self.prg.read(self.prg.last_bank(0x2000), 0x2000, (addr & 0x1FFF) as usize),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7FFF = addr {
+            self.prg_ram[(addr - 0x6000) as usize] = data;
+            return;
+        }
+        let mut addr = addr & 0xF003;
+        if self.swap_lines {
+            addr = (addr & 0xF000) | ((addr & 0x01) << 1) | ((addr & 0x02) >> 1);
+        }
+        match addr {
+            0x8000..=0x8003 => self.prg_16k = data & 0x0F,
+            0x9000..=0xB002 => self.audio.borrow_mut().write(addr, data),
+            0xB003 => {
+                self.mirroring = match (data >> 2) & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenLower,
+                    _ => Mirroring::SingleScreenUpper,
+                }
+            }
+            0xC000..=0xC003 => self.prg_8k = data & 0x1F,
+            0xD000..=0xD003 => self.chr_banks[(addr & 0x03) as usize] = data,
+            0xE000..=0xE003 => self.chr_banks[4 + (addr & 0x03) as usize] = data,
+            0xF000 => self.irq.write_latch(data),
+            0xF001 => self.irq.write_control(data),
+            0xF002 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        let bank = self.chr_banks[(addr as usize / 0x400) & 0x07];
+        self.chr.read(bank as usize, 0x400, (addr & 0x3FF) as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        let bank = self.chr_banks[(addr as usize / 0x400) & 0x07];
+        self.chr.write(bank as usize, 0x400, (addr & 0x3FF) as usize, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+
+    fn tick(&mut self) {
+        self.irq.tick();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.irq()
+    }
+
+    fn expansion_audio(&self) -> Option<SharedExpansionAudio> {
+        Some(self.audio.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::numbered_rom;
+
+    #[test]
+    fn test_banking() {
+        let mut vrc6 = Vrc6::new(&numbered_rom(16, 0x400, 32), false);
+        vrc6.write(0x8000, 3);
+        vrc6.write(0xC000, 9);
+        assert_eq!(vrc6.read(0x8000), 6);
+        assert_eq!(vrc6.read(0xA000), 7);
+        assert_eq!(vrc6.read(0xC000), 9);
+        assert_eq!(vrc6.read(0xE000), 15);
+
+        vrc6.write(0xD001, 20);
+        vrc6.write(0xE003, 31);
+        assert_eq!(vrc6.read_chr(0x0400), 20);
+        assert_eq!(vrc6.read_chr(0x1FFF), 31);
+
+        vrc6.write(0xB003, 0x04);
+        assert_eq!(vrc6.mirroring(), Mirroring::Horizontal);
+        vrc6.write(0xB003, 0x0C);
+        assert_eq!(vrc6.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn test_mapper_26_swaps_address_lines() {
+        let mut vrc6 = Vrc6::new(&numbered_rom(16, 0x400, 32), true);
+        // $D001 on mapper 26 is $D002 on the chip
+        vrc6.write(0xD001, 5);
+        assert_eq!(vrc6.read_chr(0x0800), 5);
+        // $B003 is the same either way
+        vrc6.write(0xB003, 0x08);
+        assert_eq!(vrc6.mirroring(), Mirroring::SingleScreenLower);
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut vrc6 = Vrc6::new(&numbered_rom(16, 0x400, 32), false);
+        vrc6.write(0xF000, 0xFE);
+        // cycle mode, enabled
+        vrc6.write(0xF001, 0x06);
+        vrc6.tick();
+        assert!(!vrc6.irq());
+        vrc6.tick();
+        assert!(vrc6.irq());
+        vrc6.write(0xF002, 0);
+        assert!(!vrc6.irq());
+    }
+
+    #[test]
+    fn test_audio() {
+        let vrc6 = Vrc6::new(&numbered_rom(16, 0x400, 32), false);
+        let audio = vrc6.expansion_audio().unwrap();
+        let mut vrc6 = vrc6;
+        let mut levels = [0.0; 3];
+
+        // pulse 1: volume 10, duty 7 of 16, period 1
+        vrc6.write(0x9000, 0x7A);
+        vrc6.write(0x9001, 1);
+        vrc6.write(0x9002, 0x80);
+        let mut high = 0;
+        for _ in 0..32 {
+            audio.borrow_mut().tick();
+            audio.borrow().output(&mut levels);
+            if levels[0] > 0.0 {
+                high += 1;
+                assert_eq!(levels[0], 10.0 * (PULSE_FULL_VOLUME / 15.0));
+            }
+        }
+        assert_eq!(high, 16);
+
+        // sawtooth: rate 42 climbs to 6 * 42 >> 3 = 31 before it resets
+        vrc6.write(0xB000, 42);
+        vrc6.write(0xB001, 0);
+        vrc6.write(0xB002, 0x80);
+        let mut peak = 0.0f32;
+        for _ in 0..14 {
+            audio.borrow_mut().tick();
+            audio.borrow().output(&mut levels);
+            peak = peak.max(levels[2]);
+        }
+        assert_eq!(peak, 31.0 * (PULSE_FULL_VOLUME / 15.0));
+        assert_eq!(levels[2], 0.0);
+
+        // halted: nothing moves
+        vrc6.write(0x9003, 0x01);
+        audio.borrow_mut().tick();
+        audio.borrow().output(&mut levels);
+        let before = levels;
+        audio.borrow_mut().tick();
+        audio.borrow().output(&mut levels);
+        assert_eq!(levels, before);
+    }
+}
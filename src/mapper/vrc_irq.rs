@@ -0,0 +1,120 @@
+// IRQ counter of the Konami VRC4, VRC6 and VRC7. An 8-bit counter counts up
+// to $FF and reloads from the latch, raising the IRQ. In scanline mode a
+// prescaler clocks it every 113.667 CPU cycles (341 / 3), in cycle mode it's
+// clocked every CPU cycle.
+// http://wiki.nesdev.com/w/index.php/VRC_IRQ
+const PRESCALER_PERIOD: i16 = 341;
+
+#[derive(Default)]
+pub struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    enable_after_ack: bool,
+    cycle_mode: bool,
+    pending: bool,
+}
+
+impl VrcIrq {
+    pub fn new() -> Self {
+        VrcIrq { prescaler: PRESCALER_PERIOD, ..Default::default() }
+    }
+
+    pub fn write_latch(&mut self, data: u8) {
+        self.latch = data;
+    }
+
+    // low nibble and high nibble of the latch, as VRC4 writes it
+    pub fn write_latch_low(&mut self, data: u8) {
+        self.latch = (self.latch & 0xF0) | (data & 0x0F);
+    }
+
+    pub fn write_latch_high(&mut self, data: u8) {
+        self.latch = (self.latch & 0x0F) | (data << 4);
+    }
+
+    /// ---M EAE-: cycle Mode, Enable, enable After acknowledge
+    pub fn write_control(&mut self, data: u8) {
+        self.enable_after_ack = data & 0x01 != 0;
+        self.enabled = data & 0x02 != 0;
+        self.cycle_mode = data & 0x04 != 0;
+        self.pending = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = PRESCALER_PERIOD;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    /// Advances one CPU cycle
+    pub fn tick(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.cycle_mode {
+            self.clock();
+            return;
+        }
+        self.prescaler -= 3;
+        if self.prescaler <= 0 {
+            self.prescaler += PRESCALER_PERIOD;
+            self.clock();
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        self.pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cycle_and_scanline_modes() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch(0xFD);
+        irq.write_control(0x06);
+        irq.tick();
+        irq.tick();
+        assert!(!irq.irq());
+        irq.tick();
+        assert!(irq.irq());
+
+        // without enable-after-ack the counter stops
+        irq.acknowledge();
+        assert!(!irq.irq());
+        for _ in 0..1000 {
+            irq.tick();
+        }
+        assert!(!irq.irq());
+
+        // scanline mode: one clock every 341 / 3 cycles
+        irq.write_latch(0xFE);
+        irq.write_control(0x03);
+        for _ in 0..227 {
+            irq.tick();
+        }
+        assert!(!irq.irq());
+        irq.tick();
+        assert!(irq.irq());
+        irq.acknowledge();
+        irq.tick();
+        assert!(!irq.irq());
+    }
+}
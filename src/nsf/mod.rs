@@ -0,0 +1,305 @@
+// NES Sound Format: the music code and data of a game, ripped out so that it
+// plays without the game around it.
+// http://wiki.nesdev.com/w/index.php/NSF
+// http://wiki.nesdev.com/w/index.php/NSFe
+pub mod player;
+
+use crate::region::Region;
+
+const NSF_TAG: [u8; 5] = [0x4E, 0x45, 0x53, 0x4D, 0x1A];
+const NSFE_TAG: [u8; 4] = [0x4E, 0x53, 0x46, 0x45];
+const NSF_HEADER_SIZE: usize = 0x80;
+// play rate of tunes that don't specify one, in microseconds
+const NTSC_PLAY_PERIOD: u32 = 16639;
+const PAL_PLAY_PERIOD: u32 = 19997;
+
+pub struct Nsf {
+    pub total_songs: u8,
+    // 1-based
+    pub starting_song: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    // play routine periods in microseconds
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+    // None for tunes that run on both
+    pub region: Option<Region>,
+    pub bank_init: [u8; 8],
+    // expansion sound chips, a bit per chip: VRC6, VRC7, FDS, MMC5, Namco 163, Sunsoft 5B
+    pub expansion: u8,
+    pub data: Vec<u8>,
+    // NSFe only: per-track names and lengths in milliseconds
+    pub track_titles: Vec<String>,
+    pub track_lengths: Vec<Option<u32>>,
+}
+
+fn string_field(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+impl Nsf {
+    pub fn new(raw: &[u8]) -> Result<Nsf, String> {
+        if raw.starts_with(&NSF_TAG) {
+            Nsf::parse_nsf(raw)
+        } else if raw.starts_with(&NSFE_TAG) {
+            Nsf::parse_nsfe(raw)
+        } else {
+            Err("File is not in NSF or NSFe format".to_string())
+        }
+    }
+
+    fn parse_nsf(raw: &[u8]) -> Result<Nsf, String> {
+        if raw.len() < NSF_HEADER_SIZE {
+            return Err("NSF file is truncated".to_string());
+        }
+
+        let mut bank_init = [0; 8];
+        bank_init.copy_from_slice(&raw[0x70..0x78]);
+        let region = match raw[0x7A] & 0b11 {
+            0 => Some(Region::Ntsc),
+            1 => Some(Region::Pal),
+            _ => None,
+        };
+        // NSF2 may declare the length of the program data, metadata follows it
+        let mut data_end = raw.len();
+        if raw[5] >= 2 {
+            let length = raw[0x7D] as usize | (raw[0x7E] as usize) << 8 | (raw[0x7F] as usize) << 16;
+            if length != 0 {
+                data_end = (NSF_HEADER_SIZE + length).min(raw.len());
+            }
+        }
+
+        let total_songs = raw[6].max(1);
+        Ok(Nsf {
+            total_songs,
+            starting_song: raw[7].clamp(1, total_songs),
+            load_address: u16_at(raw, 0x08),
+            init_address: u16_at(raw, 0x0A),
+            play_address: u16_at(raw, 0x0C),
+            title: string_field(&raw[0x0E..0x2E]),
+            artist: string_field(&raw[0x2E..0x4E]),
+            copyright: string_field(&raw[0x4E..0x6E]),
+            ntsc_speed: u16_at(raw, 0x6E),
+            pal_speed: u16_at(raw, 0x78),
+            region,
+            bank_init,
+            expansion: raw[0x7B],
+            data: raw[NSF_HEADER_SIZE..data_end].to_vec(),
+            track_titles: Vec::new(),
+            track_lengths: Vec::new(),
+        })
+    }
+
+    fn parse_nsfe(raw: &[u8]) -> Result<Nsf, String> {
+        let mut nsf = Nsf {
+            total_songs: 1,
+            starting_song: 1,
+            load_address: 0,
+            init_address: 0,
+            play_address: 0,
+            title: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            ntsc_speed: 0,
+            pal_speed: 0,
+            region: Some(Region::Ntsc),
+            bank_init: [0; 8],
+            expansion: 0,
+            data: Vec::new(),
+            track_titles: Vec::new(),
+            track_lengths: Vec::new(),
+        };
+        let mut has_info = false;
+
+        // chunks: length (4 bytes), id (4 bytes), data
+        let mut pos = NSFE_TAG.len();
+        while pos + 8 <= raw.len() {
+            let length = u32::from_le_bytes([raw[pos], raw[pos + 1], raw[pos + 2], raw[pos + 3]]) as usize;
+            let id = &raw[pos + 4..pos + 8];
+            let start = pos + 8;
+            if start + length > raw.len() {
+                return Err("NSFe chunk runs past the end of the file".to_string());
+            }
+            let chunk = &raw[start..start + length];
+            pos = start + length;
+
+            match id {
+                b"INFO" => {
+                    if chunk.len() < 8 {
+                        return Err("NSFe INFO chunk is too short".to_string());
+                    }
+                    nsf.load_address = u16_at(chunk, 0);
+                    nsf.init_address = u16_at(chunk, 2);
+                    nsf.play_address = u16_at(chunk, 4);
+                    nsf.region = match chunk[6] & 0b11 {
+                        0 => Some(Region::Ntsc),
+                        1 => Some(Region::Pal),
+                        _ => None,
+                    };
+                    nsf.expansion = chunk[7];
+                    if let Some(total) = chunk.get(8) {
+                        nsf.total_songs = (*total).max(1);
+                    }
+                    // 0-based in NSFe
+                    if let Some(start) = chunk.get(9) {
+                        nsf.starting_song = start.saturating_add(1).min(nsf.total_songs);
+                    }
+                    has_info = true;
+                }
+                b"DATA" => nsf.data = chunk.to_vec(),
+                b"BANK" => {
+                    for (bank, value) in nsf.bank_init.iter_mut().zip(chunk.iter()) {
+                        *bank = *value;
+                    }
+                }
+                b"RATE" => {
+                    if chunk.len() >= 2 {
+                        nsf.ntsc_speed = u16_at(chunk, 0);
+                    }
+                    if chunk.len() >= 4 {
+                        nsf.pal_speed = u16_at(chunk, 2);
+                    }
+                }
+                b"auth" => {
+                    let mut strings = chunk.split(|b| *b == 0).map(string_field);
+                    nsf.title = strings.next().unwrap_or_default();
+                    nsf.artist = strings.next().unwrap_or_default();
+                    nsf.copyright = strings.next().unwrap_or_default();
+                }
+                b"tlbl" => {
+                    nsf.track_titles = chunk.split(|b| *b == 0).map(string_field).collect();
+                }
+                b"time" => {
+                    nsf.track_lengths = chunk
+                        .chunks_exact(4)
+                        .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                        .map(|ms| if ms < 0 { None } else { Some(ms as u32) })
+                        .collect();
+                }
+                b"NEND" => break,
+                _ => {
+                    // chunks with an upper case first letter are required to be understood
+                    if id[0].is_ascii_uppercase() {
+                        return Err(format!("Unsupported NSFe chunk {}", String::from_utf8_lossy(id)));
+                    }
+                }
+            }
+        }
+
+        if !has_info || nsf.data.is_empty() {
+            return Err("NSFe file has no INFO or DATA chunk".to_string());
+        }
+        Ok(nsf)
+    }
+
+    pub fn is_bankswitched(&self) -> bool {
+        self.bank_init.iter().any(|bank| *bank != 0)
+    }
+
+    /// Microseconds between two calls of the play routine
+    pub fn play_period_us(&self, region: Region) -> u32 {
+        let (speed, default) = match region {
+            Region::Pal | Region::Dendy => (self.pal_speed, PAL_PLAY_PERIOD),
+            Region::Ntsc => (self.ntsc_speed, NTSC_PLAY_PERIOD),
+        };
+        if speed == 0 {
+            default
+        } else {
+            speed as u32
+        }
+    }
+
+    /// Title of a 1-based track, if the file names it
+    pub fn track_title(&self, track: u8) -> Option<&str> {
+        self.track_titles
+            .get(track as usize - 1)
+            .map(|title| title.as_str())
+            .filter(|title| !title.is_empty())
+    }
+
+    pub fn track_length_ms(&self, track: u8) -> Option<u32> {
+        self.track_lengths.get(track as usize - 1).copied().flatten()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    /// NSF with `code` loaded at $8000, init at $8000 and play at `play`
+    pub fn test_nsf(code: &[u8], play: u16, songs: u8) -> Vec<u8> {
+        let mut raw = vec![0; NSF_HEADER_SIZE];
+        raw[0..5].copy_from_slice(&NSF_TAG);
+        raw[5] = 1;
+        raw[6] = songs;
+        raw[7] = 1;
+        raw[0x08..0x0A].copy_from_slice(&0x8000u16.to_le_bytes());
+        raw[0x0A..0x0C].copy_from_slice(&0x8000u16.to_le_bytes());
+        raw[0x0C..0x0E].copy_from_slice(&play.to_le_bytes());
+        raw[0x0E..0x12].copy_from_slice(b"Test");
+        raw[0x6E..0x70].copy_from_slice(&16639u16.to_le_bytes());
+        raw.extend_from_slice(code);
+        raw
+    }
+
+    #[test]
+    fn test_nsf_header() {
+        let nsf = Nsf::new(&test_nsf(&[0x60], 0x8010, 3)).unwrap();
+        assert_eq!(nsf.total_songs, 3);
+        assert_eq!(nsf.starting_song, 1);
+        assert_eq!(nsf.play_address, 0x8010);
+        assert_eq!(nsf.title, "Test");
+        assert_eq!(nsf.region, Some(Region::Ntsc));
+        assert!(!nsf.is_bankswitched());
+        assert_eq!(nsf.data, vec![0x60]);
+        assert_eq!(nsf.play_period_us(Region::Ntsc), 16639);
+        assert_eq!(nsf.play_period_us(Region::Pal), PAL_PLAY_PERIOD);
+    }
+
+    #[test]
+    fn test_nsfe_chunks() {
+        let chunk = |id: &[u8], data: &[u8]| {
+            let mut bytes = (data.len() as u32).to_le_bytes().to_vec();
+            bytes.extend_from_slice(id);
+            bytes.extend_from_slice(data);
+            bytes
+        };
+        let mut raw = NSFE_TAG.to_vec();
+        raw.extend(chunk(b"INFO", &[0x00, 0x80, 0x00, 0x80, 0x03, 0x80, 0x01, 0x00, 0x02, 0x01]));
+        raw.extend(chunk(b"DATA", &[0x60, 0x60, 0x60, 0x60]));
+        raw.extend(chunk(b"auth", b"Song\0Composer\0Company\0Ripper\0"));
+        raw.extend(chunk(b"tlbl", b"Intro\0Level 1\0"));
+        raw.extend(chunk(b"time", &[0x10, 0x27, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]));
+        raw.extend(chunk(b"NEND", &[]));
+
+        let nsf = Nsf::new(&raw).unwrap();
+        assert_eq!(nsf.play_address, 0x8003);
+        assert_eq!(nsf.region, Some(Region::Pal));
+        assert_eq!(nsf.total_songs, 2);
+        assert_eq!(nsf.starting_song, 2);
+        assert_eq!(nsf.artist, "Composer");
+        assert_eq!(nsf.track_title(2), Some("Level 1"));
+        assert_eq!(nsf.track_length_ms(1), Some(10000));
+        assert_eq!(nsf.track_length_ms(2), None);
+
+        // a starting song past the last, even 0xFF, is the last
+        let mut last = NSFE_TAG.to_vec();
+        last.extend(chunk(b"INFO", &[0x00, 0x80, 0x00, 0x80, 0x03, 0x80, 0x00, 0x00, 0x02, 0xFF]));
+        last.extend(chunk(b"DATA", &[0x60]));
+        last.extend(chunk(b"NEND", &[]));
+        assert_eq!(Nsf::new(&last).unwrap().starting_song, 2);
+
+        let mut unknown = NSFE_TAG.to_vec();
+        unknown.extend(chunk(b"VRC7", &[0]));
+        assert!(Nsf::new(&unknown).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+// Plays an NSF on the CPU and APU: init once per track, then play at the
+// rate the tune asks for. The routines are called the way a player ROM
+// would JSR into them, with a return address that stops the CPU.
+// http://wiki.nesdev.com/w/index.php/NSF#Initializing_a_tune
+use super::Nsf;
+use crate::apu::APU;
+use crate::bus::Bus;
+use crate::cpu::{Mem, CPU};
+use crate::mapper::nsf::NsfMapper;
+use crate::region::Region;
+use std::time::Duration;
+
+// unmapped address the routines return to, the CPU stops when it gets there
+const RETURN_ADDRESS: u16 = 0x5FF6;
+// longest a routine may run before it's considered hung, in seconds
+const INIT_TIMEOUT: f64 = 1.0;
+
+pub struct NsfPlayer {
+    nsf: Nsf,
+    cpu: CPU<'static>,
+    region: Region,
+    // 1-based, 0 before the first track starts
+    track: u8,
+    // CPU cycles between two calls of the play routine
+    play_period: f64,
+    next_play: f64,
+    track_start: usize,
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: Nsf, region: Region) -> NsfPlayer {
+        let mapper = NsfMapper::new(&nsf.data, nsf.load_address, nsf.bank_init);
+        let bus = Bus::without_ppu(Box::new(mapper), region);
+        let play_period = nsf.play_period_us(region) as f64 * region.cpu_clock_hz() / 1_000_000.0;
+
+        NsfPlayer {
+            nsf,
+            cpu: CPU::new(bus),
+            region,
+            track: 0,
+            play_period,
+            next_play: 0.0,
+            track_start: 0,
+        }
+    }
+
+    pub fn nsf(&self) -> &Nsf {
+        &self.nsf
+    }
+
+    pub fn track(&self) -> u8 {
+        self.track
+    }
+
+    pub fn apu_mut(&mut self) -> &mut APU {
+        self.cpu.bus.apu_mut()
+    }
+
+    pub fn init_audio(&mut self, sdl_context: &sdl2::Sdl, sample_rate: u32) -> Result<(), String> {
+        self.cpu.bus.init_audio(sdl_context, sample_rate)
+    }
+
+    /// Resets the console state and runs the init routine of a 1-based track
+    pub fn start_track(&mut self, track: u8) {
+        let track = track.clamp(1, self.nsf.total_songs);
+        for addr in 0x0000..0x0800 {
+            self.cpu.mem_write(addr, 0);
+        }
+        for addr in 0x6000..0x8000 {
+            self.cpu.mem_write(addr, 0);
+        }
+        for addr in 0x4000..=0x4013 {
+            self.cpu.mem_write(addr, 0);
+        }
+        self.cpu.mem_write(0x4015, 0x00);
+        self.cpu.mem_write(0x4015, 0x0F);
+        // 4-step mode without the frame IRQ
+        self.cpu.mem_write(0x4017, 0x40);
+        if self.nsf.is_bankswitched() {
+            for (slot, bank) in self.nsf.bank_init.iter().enumerate() {
+                self.cpu.mem_write(0x5FF8 + slot as u16, *bank);
+            }
+        }
+
+        self.track = track;
+        self.cpu.register_a = track - 1;
+        self.cpu.register_x = if self.region == Region::Ntsc { 0 } else { 1 };
+        self.cpu.register_y = 0;
+        let timeout = (INIT_TIMEOUT * self.region.cpu_clock_hz()) as usize;
+        if !self.call(self.nsf.init_address, timeout) {
+            eprintln!("NSF init routine of track {} did not return", track);
+        }
+
+        self.track_start = self.cpu.bus.cycles();
+        self.next_play = self.track_start as f64;
+    }
+
+    /// Runs the tune for `seconds` of console time
+    pub fn run(&mut self, seconds: f64) {
+        let end = self.cpu.bus.cycles() as f64 + seconds * self.region.cpu_clock_hz();
+        while (self.cpu.bus.cycles() as f64) < end {
+            if self.cpu.bus.cycles() as f64 >= self.next_play {
+                self.next_play += self.play_period;
+                // a play routine running past its period just delays the next call
+                self.call(self.nsf.play_address, self.play_period as usize * 4);
+            } else {
+                self.cpu.bus.tick(1);
+            }
+        }
+    }
+
+    /// Console time since the track started
+    pub fn elapsed(&self) -> Duration {
+        let cycles = self.cpu.bus.cycles() - self.track_start;
+        Duration::from_secs_f64(cycles as f64 / self.region.cpu_clock_hz())
+    }
+
+    // JSR to `addr` and run until it returns, BRKs or takes longer than `max_cycles`
+    fn call(&mut self, addr: u16, max_cycles: usize) -> bool {
+        self.cpu.stack_pointer = 0xFD;
+        self.cpu.stack_push_u16(RETURN_ADDRESS - 1);
+        self.cpu.program_counter = addr;
+        let deadline = self.cpu.bus.cycles() + max_cycles;
+        while self.cpu.program_counter != RETURN_ADDRESS {
+            if self.cpu.bus.cycles() >= deadline || !self.cpu.step() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nsf::test::test_nsf;
+
+    #[test]
+    fn test_init_and_play_routines() {
+        let code = [
+            // init at $8000: STA $00, RTS
+            0x85, 0x00, 0x60, //
+            // play at $8003: INC $01, RTS
+            0xE6, 0x01, 0x60,
+        ];
+        let nsf = Nsf::new(&test_nsf(&code, 0x8003, 4)).unwrap();
+        let mut player = NsfPlayer::new(nsf, Region::Ntsc);
+        player.start_track(3);
+        assert_eq!(player.cpu.mem_read(0x00), 2);
+        assert_eq!(player.cpu.mem_read(0x01), 0);
+
+        // 16639us between calls: 60 calls in a second, the first one right away
+        player.run(1.0);
+        assert_eq!(player.cpu.mem_read(0x01), 61);
+        assert!((player.elapsed().as_secs_f64() - 1.0).abs() < 0.001);
+
+        player.start_track(1);
+        assert_eq!(player.cpu.mem_read(0x00), 0);
+        assert_eq!(player.cpu.mem_read(0x01), 0);
+    }
+
+    #[test]
+    fn test_hung_routine_times_out() {
+        // init at $8000: JMP $8000
+        let nsf = Nsf::new(&test_nsf(&[0x4C, 0x00, 0x80], 0x8000, 1)).unwrap();
+        let mut player = NsfPlayer::new(nsf, Region::Ntsc);
+        assert!(!player.call(0x8000, 1000));
+        assert!(player.cpu.bus.cycles() >= 1000);
+    }
+}
@@ -1,4 +1,10 @@
 use crate::cartridge::Mirroring;
+use crate::mapper::nrom::Nrom;
+use crate::mapper::{BackgroundFetch, Nametable, SharedMapper, TileRow};
+use crate::region::Region;
+use crate::render::{self, frame::Frame};
+use std::cell::RefCell;
+use std::rc::Rc;
 use registers::addr::AddrRegister;
 use registers::control::ControlRegister;
 use registers::mask::MaskRegister;
@@ -8,14 +14,15 @@ use registers::status::StatusRegister;
 pub mod registers;
 
 pub struct NesPPU {
-    pub chr_rom: Vec<u8>,
-    pub mirroring: Mirroring,
+    // pattern tables and nametable mirroring come from the cartridge
+    mapper: SharedMapper,
     pub ctrl: ControlRegister,
     pub mask: MaskRegister,
     pub status: StatusRegister,
     pub scroll: ScrollRegister,
     pub addr: AddrRegister,
-    pub vram: [u8; 2048],
+    // 2KB in the console, the rest on four-screen boards
+    pub vram: [u8; 4096],
 
     pub oam_addr: u8,
     pub oam_data: [u8; 256],
@@ -26,6 +33,9 @@ pub struct NesPPU {
     pub scanline: u16,
     cycles: usize,
     pub nmi_interrupt: Option<u8>,
+    region: Region,
+    // the picture, drawn a scanline at a time
+    frame: Frame,
 }
 
 pub trait PPU {
@@ -47,17 +57,22 @@ impl NesPPU {
         NesPPU::new(vec![0; 2048], Mirroring::Horizontal)
     }
 
+    /// PPU on a plain NROM board
     pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        let nrom = Nrom::new(Vec::new(), chr_rom, mirroring);
+        NesPPU::with_mapper(Rc::new(RefCell::new(Box::new(nrom))))
+    }
+
+    pub fn with_mapper(mapper: SharedMapper) -> Self {
         NesPPU {
-            chr_rom: chr_rom,
-            mirroring: mirroring,
+            mapper,
             ctrl: ControlRegister::new(),
             mask: MaskRegister::new(),
             status: StatusRegister::new(),
             oam_addr: 0,
             scroll: ScrollRegister::new(),
             addr: AddrRegister::new(),
-            vram: [0; 2048],
+            vram: [0; 4096],
             oam_data: [0; 64 * 4],
             palette_table: [0; 32],
             internal_data_buf: 0,
@@ -65,7 +80,71 @@ impl NesPPU {
             cycles: 0,
             scanline: 0,
             nmi_interrupt: None,
+            region: Region::Ntsc,
+            frame: Frame::new(),
+        }
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        self.mapper.borrow().mirroring()
+    }
+
+    /// Pattern table read, through the cartridge's CHR banking
+    pub fn read_chr(&self, addr: u16) -> u8 {
+        self.mapper.borrow_mut().read_chr(addr)
+    }
+
+    /// The 16 bytes of the tile at pattern table address `addr`
+    pub fn chr_tile(&self, addr: u16) -> [u8; 16] {
+        let mut tile = [0; 16];
+        for (n, byte) in tile.iter_mut().enumerate() {
+            *byte = self.read_chr(addr + n as u16);
         }
+        tile
+    }
+
+    // pattern table read as the PPU makes it, which the cartridge gets to see
+    fn fetch_chr(&self, addr: u16) -> u8 {
+        let mut mapper = self.mapper.borrow_mut();
+        let data = mapper.read_chr(addr);
+        mapper.pattern_fetch(addr);
+        data
+    }
+
+    /// Both planes of the sprite tile row at pattern table address `addr`,
+    /// fetched as while rendering
+    pub fn sprite_row(&self, addr: u16) -> (u8, u8) {
+        let mut mapper = self.mapper.borrow_mut();
+        let low = mapper.read_sprite_chr(addr);
+        mapper.pattern_fetch(addr);
+        let high = mapper.read_sprite_chr(addr + 8);
+        mapper.pattern_fetch(addr + 8);
+        (low, high)
+    }
+
+    /// Row `fine_y` of the background tile at nametable address `addr`, fetched
+    /// as tile `column` of `scanline`. The cartridge may substitute any of it.
+    pub fn background_row(&self, addr: u16, fine_y: u8, column: usize, scanline: usize) -> TileRow {
+        let tile = self.read_nametable(addr);
+        let attribute_addr = 0x23C0 | (addr & 0x0C00) | ((addr >> 4) & 0x38) | ((addr >> 2) & 0x07);
+        let shift = ((addr >> 4) & 0x04) | (addr & 0x02);
+        let palette = (self.read_nametable(attribute_addr) >> shift) & 0x03;
+
+        let fetch = BackgroundFetch { addr, tile, palette, fine_y, column, scanline };
+        let substitute = self.mapper.borrow_mut().background_row(&fetch);
+        substitute.unwrap_or_else(|| {
+            let pattern = self.ctrl.bknd_pattern_addr() + tile as u16 * 16 + fine_y as u16;
+            TileRow { low: self.fetch_chr(pattern), high: self.fetch_chr(pattern + 8), palette }
+        })
+    }
+
+    /// CPU write to $2000-$2007, seen by the cartridge too
+    pub fn notify_register_write(&mut self, addr: u16, value: u8) {
+        self.mapper.borrow_mut().ppu_write(addr, value);
     }
 
     // Horizontal:
@@ -79,7 +158,12 @@ impl NesPPU {
         let mirrored_vram = addr & 0b10111111111111; // mirror down 0x3000-0x3eff to 0x2000 - 0x2eff
         let vram_index = mirrored_vram - 0x2000; // to vram vector
         let name_table = vram_index / 0x400;
-        match (&self.mirroring, name_table) {
+        if let Some(Nametable::Vram(page)) = self.mapper.borrow().nametable(name_table) {
+            return ((page & 1) * 0x400) | (vram_index & 0x3FF);
+        }
+        match (&self.mirroring(), name_table) {
+            (Mirroring::SingleScreenLower, _) => vram_index & 0x3FF,
+            (Mirroring::SingleScreenUpper, _) => 0x400 | (vram_index & 0x3FF),
             (Mirroring::Vertical, 2) | (Mirroring::Vertical, 3) => vram_index - 0x800,
             (Mirroring::Horizontal, 2) => vram_index - 0x400,
             (Mirroring::Horizontal, 1) => vram_index - 0x400,
@@ -88,6 +172,33 @@ impl NesPPU {
         }
     }
 
+    /// Nametable read at $2000-$2FFF, from VRAM or the cartridge
+    pub fn read_nametable(&self, addr: u16) -> u8 {
+        let source = self.mapper.borrow().nametable((addr & 0x0FFF) / 0x400);
+        if let Some(Nametable::Cartridge) = source {
+            return self.mapper.borrow_mut().read_nametable(addr);
+        }
+        self.vram[self.mirror_vram_addr(addr) as usize]
+    }
+
+    fn write_nametable(&mut self, addr: u16, value: u8) {
+        let source = self.mapper.borrow().nametable((addr & 0x0FFF) / 0x400);
+        if let Some(Nametable::Cartridge) = source {
+            self.mapper.borrow_mut().write_nametable(addr, value);
+        } else {
+            self.vram[self.mirror_vram_addr(addr) as usize] = value;
+        }
+    }
+
+    /// The 1KB nametable `index`, 0-3 for $2000, $2400, $2800 and $2C00
+    pub fn nametable(&self, index: u16) -> [u8; 0x400] {
+        let mut table = [0; 0x400];
+        for (n, byte) in table.iter_mut().enumerate() {
+            *byte = self.read_nametable(0x2000 + index * 0x400 + n as u16);
+        }
+        table
+    }
+
     fn increment_vram_addr(&mut self) {
         self.addr.increment(self.ctrl.vram_addr_increment());
     }
@@ -101,8 +212,9 @@ impl NesPPU {
 
             self.cycles = self.cycles - 341;
             self.scanline += 1;
+            let rendering = self.mask.show_background() || self.mask.show_sprites();
 
-            if self.scanline == 241 {
+            if self.scanline == self.region.vblank_scanline() {
                 self.status.set_vblank_status(true);
                 self.status.set_sprite_zero_hit(false);
                 if self.ctrl.generate_vblank_nmi() {
@@ -110,17 +222,33 @@ impl NesPPU {
                 }
             }
 
-            if self.scanline >= 262 {
+            let new_frame = self.scanline >= self.region.scanlines_per_frame();
+            if new_frame {
                 self.scanline = 0;
                 self.nmi_interrupt = None;
                 self.status.set_sprite_zero_hit(false);
                 self.status.reset_vblank_status();
-                return true;
             }
+            self.mapper.borrow_mut().ppu_scanline(self.scanline, rendering);
+            if (self.scanline as usize) < Frame::HEIGHT {
+                self.render_scanline(self.scanline as usize);
+            }
+            return new_frame;
         }
         return false;
     }
 
+    /// The picture so far, complete once vblank starts
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    fn render_scanline(&mut self, y: usize) {
+        let mut frame = std::mem::replace(&mut self.frame, Frame::with_size(0, 0));
+        render::render_scanline(self, &mut frame, y);
+        self.frame = frame;
+    }
+
     pub fn poll_nmi_interrupt(&mut self) -> Option<u8> {
         self.nmi_interrupt.take()
     }
@@ -178,10 +306,9 @@ impl PPU for NesPPU {
     fn write_to_data(&mut self, value: u8) {
         let addr = self.addr.get();
         match addr {
-            0..=0x1fff => println!("attempt to write to chr rom space {}", addr),
-            0x2000..=0x2fff => {
-                self.vram[self.mirror_vram_addr(addr) as usize] = value;
-            }
+            // CHR RAM, if the cartridge has it
+            0..=0x1fff => self.mapper.borrow_mut().write_chr(addr, value),
+            0x2000..=0x2fff => self.write_nametable(addr, value),
             0x3000..=0x3eff => unimplemented!("addr {} shouldn't be used in reallity", addr),
 
             //Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
@@ -205,12 +332,12 @@ impl PPU for NesPPU {
         match addr {
             0..=0x1fff => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.chr_rom[addr as usize];
+                self.internal_data_buf = self.fetch_chr(addr);
                 result
             }
             0x2000..=0x2fff => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+                self.internal_data_buf = self.read_nametable(addr);
                 result
             }
             0x3000..=0x3eff => unimplemented!("addr {} shouldn't be used in reallity", addr),
@@ -353,6 +480,111 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x77); //read from B
     }
 
+    #[test]
+    fn test_vram_four_screen() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::FourScreen);
+
+        ppu.write_to_ppu_addr(0x2C);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66);
+
+        ppu.write_to_ppu_addr(0x2C);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.read_data(); //load into buffer
+        assert_eq!(ppu.read_data(), 0x66);
+        // none of the other three nametables share it
+        for index in 0..3 {
+            assert_eq!(ppu.nametable(index)[0x05], 0);
+        }
+    }
+
+    #[test]
+    fn test_cartridge_nametables() {
+        use crate::mapper::{namco163::Namco163, Mapper};
+        let mut rom = crate::cartridge::test::test_rom();
+        rom.chr_rom = Vec::new();
+        let mut mapper = Namco163::new(&rom);
+        // $2000 on VRAM page 1, $2400 on CHR RAM
+        mapper.write(0xC000, 0xE1);
+        mapper.write(0xC800, 0x01);
+        // and the same bank on the pattern tables at $0400
+        mapper.write(0x8800, 0x01);
+        let mut ppu = NesPPU::with_mapper(Rc::new(RefCell::new(Box::new(mapper))));
+
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66);
+        assert_eq!(ppu.vram[0x0405], 0x66);
+
+        ppu.write_to_ppu_addr(0x24);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x77);
+        assert_eq!(ppu.read_chr(0x0405), 0x77);
+        assert_eq!(ppu.nametable(1)[0x05], 0x77);
+        assert!(ppu.vram.iter().all(|byte| *byte != 0x77));
+    }
+
+    #[test]
+    fn test_background_row() {
+        let mut chr = vec![0; 0x2000];
+        // tile 3 of the $1000 table, row 2
+        chr[0x1000 + 3 * 16 + 2] = 0xF0;
+        chr[0x1000 + 3 * 16 + 10] = 0x0F;
+        let mut ppu = NesPPU::new(chr, Mirroring::Horizontal);
+        ppu.write_to_ctrl(0b1_0000);
+        // tile (5, 2) of $2000, in the bottom left quadrant of attribute byte 1
+        ppu.vram[2 * 32 + 5] = 3;
+        ppu.vram[0x3C1] = 0b00_11_00_00;
+
+        let row = ppu.background_row(0x2000 + 2 * 32 + 5, 2, 5, 18);
+        assert_eq!(row, TileRow { low: 0xF0, high: 0x0F, palette: 3 });
+    }
+
+    #[test]
+    fn test_pattern_fetches_switch_chr() {
+        use crate::mapper::{mmc2::Mmc2, Mapper};
+        let mut rom = crate::cartridge::test::test_rom();
+        // 4KB CHR banks numbered by their bytes
+        rom.chr_rom = (0..4).flat_map(|bank| vec![bank as u8; 0x1000]).collect();
+        let mut mapper = Mmc2::new(&rom, false);
+        mapper.write(0xD000, 2);
+        mapper.write(0xE000, 3);
+        let mut ppu = NesPPU::with_mapper(Rc::new(RefCell::new(Box::new(mapper))));
+        ppu.write_to_ctrl(0b1_0000);
+        ppu.vram[0] = 0xFD;
+
+        // tile $FD is still drawn from the $FE bank, the next one isn't
+        assert_eq!(ppu.background_row(0x2000, 0, 0, 0).low, 3);
+        assert_eq!(ppu.background_row(0x2001, 0, 1, 0).low, 2);
+        // the nametable viewer reads the pattern tables without switching
+        ppu.vram[1] = 0xFE;
+        ppu.chr_tile(0x1FE0);
+        assert_eq!(ppu.read_chr(0x1000), 2);
+        assert_eq!(ppu.sprite_row(0x1FE0), (2, 2));
+        assert_eq!(ppu.read_chr(0x1000), 3);
+    }
+
+    #[test]
+    fn test_no_pattern_fetches_with_rendering_off() {
+        use crate::mapper::{mmc2::Mmc2, Mapper};
+        let mut rom = crate::cartridge::test::test_rom();
+        rom.chr_rom = (0..4).flat_map(|bank| vec![bank as u8; 0x1000]).collect();
+        let mut mapper = Mmc2::new(&rom, false);
+        mapper.write(0xD000, 2);
+        mapper.write(0xE000, 3);
+        let mut ppu = NesPPU::with_mapper(Rc::new(RefCell::new(Box::new(mapper))));
+        ppu.write_to_ctrl(0b1_1000);
+        ppu.vram[0] = 0xFD;
+        ppu.oam_data[1] = 0xFD;
+
+        // a whole frame over the $FD tiles leaves the $FE bank in
+        while !ppu.tick(1) {}
+        assert_eq!(ppu.read_chr(0x1000), 3);
+        ppu.write_to_mask(0b1_1000);
+        while !ppu.tick(1) {}
+        assert_eq!(ppu.read_chr(0x1000), 2);
+    }
+
     #[test]
     fn test_read_status_resets_latch() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -399,6 +631,54 @@ pub mod test {
         assert_eq!(ppu.status.snapshot() >> 7, 0);
     }
 
+    #[test]
+    fn test_pal_frame_length() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.set_region(Region::Pal);
+        ppu.write_to_ctrl(0b1000_0000);
+
+        let mut dots = 0;
+        while !ppu.tick(1) {
+            dots += 1;
+            if ppu.scanline == 241 {
+                assert!(ppu.status.is_in_vblank());
+            }
+        }
+        assert_eq!(dots + 1, 341 * 312);
+    }
+
+    #[test]
+    fn test_scanlines_drawn_as_reached() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_mask(0b1000);
+        ppu.palette_table[0] = 0x01;
+        while ppu.scanline < 100 {
+            ppu.tick(1);
+        }
+        // a mid-frame palette write only shows from the next line down
+        ppu.palette_table[0] = 0x02;
+        while !ppu.tick(1) {}
+        assert_eq!(ppu.frame().pixel(0, 99), 0x01);
+        assert_eq!(ppu.frame().pixel(0, 101), 0x02);
+    }
+
+    #[test]
+    fn test_eight_sprites_per_scanline() {
+        let mut chr = vec![0; 0x2000];
+        chr[0] = 0xFF;
+        let mut ppu = NesPPU::new(chr, Mirroring::Horizontal);
+        ppu.write_to_mask(0b1_0000);
+        ppu.palette_table[0x11] = 0x05;
+        // nine sprites side by side on line 10, tile 0 row 0 is solid
+        for i in 0..9 {
+            ppu.oam_data[i * 4] = 10;
+            ppu.oam_data[i * 4 + 3] = i as u8 * 8;
+        }
+        while !ppu.tick(1) {}
+        assert_eq!(ppu.frame().pixel(7 * 8, 10), 0x05);
+        assert_eq!(ppu.frame().pixel(8 * 8, 10), 0);
+    }
+
     #[test]
     fn test_oam_read_write() {
         let mut ppu = NesPPU::new_empty_rom();
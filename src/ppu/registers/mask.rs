@@ -70,6 +70,11 @@ impl MaskRegister {
         result
     }
 
+    // emphasis bits in .pal file order: bit 0 - red, bit 1 - green, bit 2 - blue
+    pub fn emphasis(&self) -> u8 {
+        self.bits >> 5
+    }
+
     pub fn update(&mut self, data: u8) {
         self.bits = data;
     }
@@ -0,0 +1,143 @@
+// Timing differences between console regions.
+// http://wiki.nesdev.com/w/index.php/Cycle_reference_chart
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    // Famiclone with PAL video timing but an NTSC-like CPU:PPU ratio
+    Dendy,
+}
+
+const NTSC_NOISE_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const PAL_NOISE_PERIODS: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+const NTSC_DMC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+const PAL_DMC_RATES: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+const NTSC_FRAME_STEPS: [u16; 5] = [7457, 14913, 22371, 29829, 37281];
+const PAL_FRAME_STEPS: [u16; 5] = [8313, 16627, 24939, 33253, 41565];
+
+impl std::str::FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ntsc" => Ok(Region::Ntsc),
+            "pal" => Ok(Region::Pal),
+            "dendy" => Ok(Region::Dendy),
+            _ => Err(format!("unknown region '{}'", s)),
+        }
+    }
+}
+
+impl Region {
+    /// Guesses the region from GoodNES / No-Intro style tags, e.g. "Game (E).nes".
+    /// Only parenthesized tags name regions, square brackets hold status flags
+    /// like [a] for alternate and [f] for fixed.
+    pub fn from_file_name(file_name: &str) -> Option<Region> {
+        let name = file_name.to_ascii_lowercase();
+        let tags = name.split('(').skip(1).filter_map(|tag| tag.split(')').next());
+
+        for tag in tags {
+            for part in tag.split(',').map(|p| p.trim()) {
+                match part {
+                    "dendy" => return Some(Region::Dendy),
+                    "e" | "europe" | "pal" | "a" | "australia" | "g" | "germany" | "f" | "france"
+                    | "i" | "italy" | "s" | "spain" | "sw" | "sweden" => return Some(Region::Pal),
+                    "u" | "usa" | "j" | "japan" | "ntsc" => return Some(Region::Ntsc),
+                    _ => {}
+                }
+            }
+        }
+        None
+    }
+
+    pub fn scanlines_per_frame(&self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    pub fn vblank_scanline(&self) -> u16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            // Dendy keeps NTSC-length vblank, starting 50 lines later
+            Region::Dendy => 291,
+        }
+    }
+
+    /// PPU dots per CPU cycle as a (numerator, denominator) pair
+    pub fn ppu_dots_per_cpu_cycle(&self) -> (u32, u32) {
+        match self {
+            Region::Ntsc | Region::Dendy => (3, 1),
+            Region::Pal => (16, 5),
+        }
+    }
+
+    pub fn cpu_clock_hz(&self) -> f64 {
+        match self {
+            Region::Ntsc => 1_789_773.0,
+            Region::Pal => 1_662_607.0,
+            Region::Dendy => 1_773_448.0,
+        }
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        match self {
+            Region::Ntsc => 60.0988,
+            Region::Pal | Region::Dendy => 50.0070,
+        }
+    }
+
+    pub fn noise_periods(&self) -> &'static [u16; 16] {
+        match self {
+            Region::Ntsc | Region::Dendy => &NTSC_NOISE_PERIODS,
+            Region::Pal => &PAL_NOISE_PERIODS,
+        }
+    }
+
+    pub fn dmc_rates(&self) -> &'static [u16; 16] {
+        match self {
+            Region::Ntsc | Region::Dendy => &NTSC_DMC_RATES,
+            Region::Pal => &PAL_DMC_RATES,
+        }
+    }
+
+    /// CPU cycles at which the APU frame counter clocks its steps. The 4-step
+    /// sequence ends with the fourth, the 5-step one with the fifth.
+    pub fn apu_frame_steps(&self) -> &'static [u16; 5] {
+        match self {
+            Region::Ntsc | Region::Dendy => &NTSC_FRAME_STEPS,
+            Region::Pal => &PAL_FRAME_STEPS,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_region_from_file_name() {
+        assert_eq!(Region::from_file_name("Super Mario Bros. (E).nes"), Some(Region::Pal));
+        assert_eq!(Region::from_file_name("Tetris (Europe).nes"), Some(Region::Pal));
+        assert_eq!(Region::from_file_name("Kirby (USA, Europe).nes"), Some(Region::Ntsc));
+        assert_eq!(Region::from_file_name("Contra (Dendy) [!].nes"), Some(Region::Dendy));
+        assert_eq!(Region::from_file_name("Zelda (J) [!].nes"), Some(Region::Ntsc));
+        assert_eq!(Region::from_file_name("pacman.nes"), None);
+        assert_eq!(Region::from_file_name("Game (W) [a].nes"), None);
+        assert_eq!(Region::from_file_name("Game (W) [f].nes"), None);
+        assert_eq!(Region::from_file_name("Game (U) [f1].nes"), Some(Region::Ntsc));
+        assert_eq!(Region::from_file_name("Exed Exes (Es).nes"), None);
+    }
+}
@@ -0,0 +1,109 @@
+use super::frame::Frame;
+
+/// Rows and columns hidden at the edges of the picture, like a TV bezel would.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Overscan {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+}
+
+impl std::str::FromStr for Overscan {
+    type Err = String;
+
+    // "top,bottom,left,right", e.g. "8,8,0,0"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<usize>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid overscan '{}': {}", s, e))?;
+        if values.len() != 4 {
+            return Err(format!("overscan should be top,bottom,left,right, got '{}'", s));
+        }
+        let overscan = Overscan {
+            top: values[0],
+            bottom: values[1],
+            left: values[2],
+            right: values[3],
+        };
+        if overscan.width() == 0 || overscan.height() == 0 {
+            return Err(format!("overscan '{}' crops the whole picture", s));
+        }
+        Ok(overscan)
+    }
+}
+
+impl Overscan {
+    pub fn width(&self) -> usize {
+        Frame::WIDTH.saturating_sub(self.left + self.right)
+    }
+
+    pub fn height(&self) -> usize {
+        Frame::HEIGHT.saturating_sub(self.top + self.bottom)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DisplayMode {
+    #[default]
+    SquarePixels,
+    // pixel aspect ratio of the NTSC PPU output on a 4:3 TV
+    Ntsc8x7,
+    // stretch whatever is visible to fill a 4:3 screen
+    FourByThree,
+}
+
+impl std::str::FromStr for DisplayMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "square" | "1:1" => Ok(DisplayMode::SquarePixels),
+            "8:7" | "par" => Ok(DisplayMode::Ntsc8x7),
+            "4:3" => Ok(DisplayMode::FourByThree),
+            _ => Err(format!("unknown display mode '{}'", s)),
+        }
+    }
+}
+
+impl DisplayMode {
+    /// Width of a picture pixel relative to its height
+    pub fn pixel_aspect(&self, overscan: &Overscan) -> f64 {
+        match self {
+            DisplayMode::SquarePixels => 1.0,
+            DisplayMode::Ntsc8x7 => 8.0 / 7.0,
+            DisplayMode::FourByThree => (4.0 / 3.0) * overscan.height() as f64 / overscan.width() as f64,
+        }
+    }
+
+    /// Window size for the visible part of the picture at the given integer scale
+    pub fn window_size(&self, overscan: &Overscan, scale: u32) -> (u32, u32) {
+        let height = overscan.height() as u32 * scale;
+        let width = (overscan.width() as f64 * scale as f64 * self.pixel_aspect(overscan)).round() as u32;
+        (width, height)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_overscan() {
+        let overscan: Overscan = "8,8,0,0".parse().unwrap();
+        assert_eq!(overscan.width(), 256);
+        assert_eq!(overscan.height(), 224);
+        assert!("8,8".parse::<Overscan>().is_err());
+        assert!("0,240,0,0".parse::<Overscan>().is_err());
+    }
+
+    #[test]
+    fn test_window_size() {
+        let overscan: Overscan = "8,8,0,0".parse().unwrap();
+        assert_eq!(DisplayMode::SquarePixels.window_size(&overscan, 2), (512, 448));
+        assert_eq!(DisplayMode::Ntsc8x7.window_size(&overscan, 2), (585, 448));
+        assert_eq!(DisplayMode::FourByThree.window_size(&overscan, 3), (896, 672));
+    }
+}
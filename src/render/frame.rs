@@ -1,23 +1,97 @@
+use super::palette::ColorPalette;
+
+// What the PPU outputs per pixel: 6-bit palette index in bits 0-5 and the
+// PPUMASK emphasis bits (red, green, blue) in bits 6-8. Turning that into
+// colors is left to whoever consumes the frame, see the to_* conversions.
 pub struct Frame {
-    pub data: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u16>,
 }
 
 impl Frame {
-    const WIDTH: usize = 256 * 2;
-    const HIGHT: usize = 240 ;
+    pub const WIDTH: usize = 256;
+    pub const HEIGHT: usize = 240;
 
     pub fn new() -> Self {
+        Frame::with_size(Frame::WIDTH, Frame::HEIGHT)
+    }
+
+    // for debug views that are bigger than the picture the PPU outputs
+    pub fn with_size(width: usize, height: usize) -> Self {
         Frame {
-            data: vec![0; (Frame::WIDTH) * (Frame::HIGHT) * 3],
+            width,
+            height,
+            pixels: vec![0; width * height],
         }
     }
 
-    pub fn set_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
-        let base = y * 3 * Frame::WIDTH + x * 3;
-        if base + 2 < self.data.len() {
-            self.data[base] = rgb.0;
-            self.data[base + 1] = rgb.1;
-            self.data[base + 2] = rgb.2;
+    pub fn set_pixel(&mut self, x: usize, y: usize, pixel: u16) {
+        if x < self.width && y < self.height {
+            self.pixels[y * self.width + x] = pixel;
         }
     }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u16 {
+        self.pixels[y * self.width + x]
+    }
+
+    /// 3 bytes per pixel: R, G, B
+    pub fn to_rgb24(&self, palette: &dyn ColorPalette, out: &mut Vec<u8>) {
+        out.clear();
+        for &pixel in self.pixels.iter() {
+            let (r, g, b) = palette.rgb(pixel);
+            out.extend_from_slice(&[r, g, b]);
+        }
+    }
+
+    /// 4 bytes per pixel: R, G, B, A (always opaque)
+    pub fn to_rgba8888(&self, palette: &dyn ColorPalette, out: &mut Vec<u8>) {
+        out.clear();
+        for &pixel in self.pixels.iter() {
+            let (r, g, b) = palette.rgb(pixel);
+            out.extend_from_slice(&[r, g, b, 0xff]);
+        }
+    }
+
+    /// 1 byte per pixel: luma of the palette color (BT.601 weights)
+    pub fn to_greyscale(&self, palette: &dyn ColorPalette, out: &mut Vec<u8>) {
+        out.clear();
+        for &pixel in self.pixels.iter() {
+            let (r, g, b) = palette.rgb(pixel);
+            let luma = (299 * r as u32 + 587 * g as u32 + 114 * b as u32) / 1000;
+            out.push(luma as u8);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct TestPalette;
+
+    impl ColorPalette for TestPalette {
+        fn rgb(&self, pixel: u16) -> (u8, u8, u8) {
+            ((pixel & 0x3f) as u8, (pixel >> 6) as u8, 100)
+        }
+    }
+
+    #[test]
+    fn test_conversions() {
+        let mut frame = Frame::with_size(2, 1);
+        frame.set_pixel(0, 0, 0x01);
+        frame.set_pixel(1, 0, 0b111 << 6 | 0x3f);
+        frame.set_pixel(2, 0, 0x05); // out of bounds, ignored
+
+        let mut out = Vec::new();
+        frame.to_rgb24(&TestPalette, &mut out);
+        assert_eq!(out, vec![1, 0, 100, 0x3f, 7, 100]);
+
+        frame.to_rgba8888(&TestPalette, &mut out);
+        assert_eq!(out, vec![1, 0, 100, 0xff, 0x3f, 7, 100, 0xff]);
+
+        frame.to_greyscale(&TestPalette, &mut out);
+        assert_eq!(out, vec![11, 34]);
+    }
 }
@@ -1,8 +1,10 @@
+pub mod display;
+pub mod font;
 pub mod frame;
+pub mod ntsc;
 pub mod palette;
 
 use crate::ppu::NesPPU;
-use crate::cartridge::Mirroring;
 use frame::Frame;
 
 fn bg_pallette(ppu: &NesPPU, attribute_table: &[u8], tile_column: usize, tile_row: usize) -> [u8; 4] {
@@ -37,6 +39,12 @@ fn sprite_palette(ppu: &NesPPU, pallete_idx: u8) -> [u8; 4] {
     ]
 }
 
+// palette index and emphasis bits as they leave the PPU, see Frame
+fn pixel_value(ppu: &NesPPU, color: u8) -> u16 {
+    let color = if ppu.mask.is_grayscale() { color & 0x30 } else { color & 0x3f };
+    (ppu.mask.emphasis() as u16) << 6 | color as u16
+}
+
 struct Rect {
     x1: usize,
     y1: usize,
@@ -55,7 +63,7 @@ impl Rect {
     }
 }
 
-fn render_name_table(ppu: &NesPPU, frame: &mut Frame, name_table: &[u8], 
+fn render_name_table(ppu: &NesPPU, frame: &mut Frame, name_table: &[u8],
     view_port: Rect, shift_x: isize, shift_y: isize) {
     let bank = ppu.ctrl.bknd_pattern_addr();
 
@@ -65,7 +73,7 @@ fn render_name_table(ppu: &NesPPU, frame: &mut Frame, name_table: &[u8],
         let tile_column = i % 32;
         let tile_row = i / 32;
         let tile_idx = name_table[i] as u16;
-        let tile = &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+        let tile = ppu.chr_tile(bank + tile_idx * 16);
         let palette = bg_pallette(ppu, attribute_table, tile_column, tile_row);
 
         for y in 0..=7 {
@@ -76,114 +84,130 @@ fn render_name_table(ppu: &NesPPU, frame: &mut Frame, name_table: &[u8],
                 let value = (1 & lower) << 1 | (1 & upper);
                 upper = upper >> 1;
                 lower = lower >> 1;
-                let rgb = match value {
-                    0 => palette::SYSTEM_PALLETE[ppu.palette_table[0] as usize],
-                    1 => palette::SYSTEM_PALLETE[palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[palette[3] as usize],
+                let color = match value {
+                    0 => ppu.palette_table[0],
+                    1 => palette[1],
+                    2 => palette[2],
+                    3 => palette[3],
                     _ => panic!("can't be"),
                 };
+                let pixel = pixel_value(ppu, color);
                 let pixel_x = tile_column * 8 + x;
                 let pixel_y = tile_row * 8 + y;
 
                 if pixel_x >= view_port.x1 && pixel_x < view_port.x2 && pixel_y >= view_port.y1 && pixel_y < view_port.y2 {
-                    frame.set_pixel((shift_x + pixel_x as isize) as usize, (shift_y + pixel_y as isize) as usize, rgb);
+                    frame.set_pixel((shift_x + pixel_x as isize) as usize, (shift_y + pixel_y as isize) as usize, pixel);
                 }
             }
         }
     }
 }
 
-pub fn render(ppu: &NesPPU, frame: &mut Frame) {
-    let scroll_x = (ppu.scroll.scroll_x) as usize;
-    let scroll_y = (ppu.scroll.scroll_y) as usize;
+pub const NAMETABLES_WIDTH: usize = 256 * 2;
+pub const NAMETABLES_HEIGHT: usize = 240 * 2;
+
+/// Debug view of all four nametables as the PPU addresses them,
+/// $2000 and $2400 on top, $2800 and $2C00 below.
+/// `frame` should be NAMETABLES_WIDTH x NAMETABLES_HEIGHT.
+pub fn render_nametables(ppu: &NesPPU, frame: &mut Frame) {
+    for n in 0..4usize {
+        render_name_table(ppu, frame,
+            &ppu.nametable(n as u16),
+            Rect::new(0, 0, 256, 240),
+            (n % 2 * 256) as isize, (n / 2 * 240) as isize
+        );
+    }
+}
 
-    let (main_nametable, second_nametable) = match (&ppu.mirroring, ppu.ctrl.nametable_addr()) {
-        (Mirroring::Vertical, 0x2000) | (Mirroring::Vertical, 0x2800) | (Mirroring::Horizontal, 0x2000) | (Mirroring::Horizontal, 0x2400) => {
-            (&ppu.vram[0..0x400], &ppu.vram[0x400..0x800])
+// The background of scanline `y`, 33 tile rows as the PPU fetches them, so
+// the cartridge can substitute any of them
+fn render_background_line(ppu: &NesPPU, frame: &mut Frame, y: usize) {
+    let scroll_x = ppu.scroll.scroll_x as usize;
+    let scroll_y = ppu.scroll.scroll_y as usize;
+    let base = (ppu.ctrl.nametable_addr() - 0x2000) / 0x400;
+
+    // scrolled past the bottom: the nametable below
+    let world_y = (y + scroll_y) % 480;
+    let (below, row) = if world_y >= 240 { (2, world_y - 240) } else { (0, world_y) };
+    for column in 0..33 {
+        let world_x = (scroll_x & !7) + column * 8;
+        let (right, tile_x) = if world_x >= 256 { (1, world_x - 256) } else { (0, world_x) };
+        let nametable = base ^ right ^ below;
+        let addr = 0x2000 + nametable * 0x400 + (row / 8 * 32 + tile_x / 8) as u16;
+        let tile = ppu.background_row(addr, (row % 8) as u8, column, y);
+
+        for x in 0..8 {
+            let pixel_x = match (column * 8 + x).checked_sub(scroll_x & 7) {
+                Some(pixel_x) if pixel_x < 256 => pixel_x,
+                _ => continue,
+            };
+            let value = (tile.low >> (7 - x)) & 1 | ((tile.high >> (7 - x)) & 1) << 1;
+            let color = match value {
+                0 => ppu.palette_table[0],
+                _ => ppu.palette_table[(tile.palette * 4 + value) as usize],
+            };
+            frame.set_pixel(pixel_x, y, pixel_value(ppu, color));
         }
-        (Mirroring::Vertical, 0x2400) | (Mirroring::Vertical, 0x2C00) | (Mirroring::Horizontal, 0x2800) | (Mirroring::Horizontal, 0x2C00) => {
-            ( &ppu.vram[0x400..0x800], &ppu.vram[0..0x400])
-        }
-        (_,_) => {
-            panic!("Not supported mirroring type {:?}", ppu.mirroring);
-        }
-    };
-
-    render_name_table(ppu, frame, 
-        main_nametable, 
-        Rect::new(scroll_x, scroll_y, 256, 240 ),
-        -(scroll_x as isize), -(scroll_y as isize)
-    );
-    if scroll_x > 0 {
-        render_name_table(ppu, frame, 
-            second_nametable, 
-            Rect::new(0, 0, scroll_x, 240),
-            (256 - scroll_x) as isize, 0
-        );
-    } else if scroll_y > 0 {
-        render_name_table(ppu, frame, 
-            second_nametable, 
-            Rect::new(0, 0, 256, scroll_y),
-            0, (240 - scroll_y) as isize
-        );
     }
+}
 
-    for i in (0..ppu.oam_data.len()).step_by(4).rev() {
-        let tile_idx = ppu.oam_data[i + 1] as u16;
-        let tile_x = ppu.oam_data[i + 3] as usize;
+// The sprites on scanline `y`. Their rows are fetched in OAM order, as the
+// PPU does after the background, then drawn so lower entries end up on top.
+fn render_sprite_line(ppu: &NesPPU, frame: &mut Frame, y: usize) {
+    let height = ppu.ctrl.sprite_size() as usize;
+    let mut rows = Vec::new();
+    for i in (0..ppu.oam_data.len()).step_by(4) {
         let tile_y = ppu.oam_data[i] as usize;
-
-        let flip_vertical = if ppu.oam_data[i + 2] >> 7 & 1 == 1 {
-            true
-        } else {
-            false
-        };
-        let flip_horizontal = if ppu.oam_data[i + 2] >> 6 & 1 == 1 {
-            true
+        if y < tile_y || y >= tile_y + height {
+            continue;
+        }
+        let tile_idx = ppu.oam_data[i + 1] as u16;
+        let flip_vertical = ppu.oam_data[i + 2] >> 7 & 1 == 1;
+        let row = if flip_vertical { height - 1 - (y - tile_y) } else { y - tile_y } as u16;
+        // 8x16 sprites are two tiles from the table picked by bit 0 of the tile
+        // number, the bottom one first when flipped
+        let addr = if height == 16 {
+            (tile_idx & 1) * 0x1000 + ((tile_idx & 0xFE) + row / 8) * 16 + row % 8
         } else {
-            false
+            ppu.ctrl.sprt_pattern_addr() + tile_idx * 16 + row
         };
-        let pallette_idx = ppu.oam_data[i + 2] & 0b11;
-        let sprite_palette = sprite_palette(ppu, pallette_idx);
-        let bank: u16 = ppu.ctrl.sprt_pattern_addr();
-
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+        rows.push((i, ppu.sprite_row(addr)));
+        // the PPU only has room for 8 sprites a line, the rest aren't fetched
+        if rows.len() == 8 {
+            break;
+        }
+    }
 
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
-            'ololo: for x in (0..=7).rev() {
-                let value = (1 & lower) << 1 | (1 & upper);
-                upper = upper >> 1;
-                lower = lower >> 1;
-                let rgb = match value {
-                    0 => continue 'ololo, // skip coloring the pixel
-                    1 => palette::SYSTEM_PALLETE[sprite_palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[sprite_palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[sprite_palette[3] as usize],
-                    _ => panic!("can't be"),
-                };
-                match (flip_horizontal, flip_vertical) {
-                    (false, false) => {
-                        frame.set_pixel(tile_x + x , tile_y + y, rgb);
-                        // frame.set_pixel(tile_x + x, tile_y + y +250, rgb);
-                    },
-                    (true, false) => {
-                        frame.set_pixel(tile_x + 7 - x , tile_y + y , rgb);
-                        // frame.set_pixel(tile_x + 7 - x , tile_y + y + 250, rgb);
-                    }
-                    (false, true) => {
-                        frame.set_pixel(tile_x + x  , tile_y + 7 - y, rgb);
-                        // frame.set_pixel(tile_x + x, tile_y + 7 - y + 250, rgb);
-                    }
-                    (true, true) => {
-                        frame.set_pixel(tile_x + 7 - x , tile_y + 7 - y , rgb);
-                        // frame.set_pixel(tile_x + 7 - x, tile_y + 7 - y+250, rgb);
-                    }
-                }
+    for &(i, (low, high)) in rows.iter().rev() {
+        let tile_x = ppu.oam_data[i + 3] as usize;
+        let flip_horizontal = ppu.oam_data[i + 2] >> 6 & 1 == 1;
+        let sprite_palette = sprite_palette(ppu, ppu.oam_data[i + 2] & 0b11);
+        for x in 0..8 {
+            let bit = if flip_horizontal { x } else { 7 - x };
+            let value = (low >> bit) & 1 | ((high >> bit) & 1) << 1;
+            if value == 0 {
+                continue; // skip coloring the pixel
             }
+            frame.set_pixel(tile_x + x, y, pixel_value(ppu, sprite_palette[value as usize]));
         }
     }
 }
+
+/// Scanline `y` of the frame, fetching pattern rows in the order the PPU does
+/// so cartridges that watch the fetches see them as they would. The PPU calls
+/// it as it reaches the line, with the registers and cartridge as they are then.
+pub fn render_scanline(ppu: &NesPPU, frame: &mut Frame, y: usize) {
+    // with a layer switched off its patterns aren't fetched at all, which
+    // cartridges like MMC2 that latch on the fetches can tell
+    if ppu.mask.show_background() {
+        render_background_line(ppu, frame, y);
+    } else {
+        let backdrop = pixel_value(ppu, ppu.palette_table[0]);
+        for x in 0..Frame::WIDTH {
+            frame.set_pixel(x, y, backdrop);
+        }
+    }
+    if ppu.mask.show_sprites() {
+        render_sprite_line(ppu, frame, y);
+    }
+}
@@ -0,0 +1,230 @@
+// NTSC composite video emulation in the spirit of blargg's nes_ntsc.
+//
+// Instead of looking colors up in a palette, every scanline is turned back
+// into the signal the PPU puts on the wire (8 samples per pixel, 12 per cycle
+// of the 3.58 MHz color subcarrier) and then decoded the way a TV would.
+// Decoding luma and chroma from overlapping windows of the same signal is what
+// produces color bleeding, artifact colors on sharp luma edges and the crawl of
+// the chroma dots from frame to frame.
+// http://wiki.nesdev.com/w/index.php/NTSC_video
+use super::frame::Frame;
+use super::palette::{self, ColorPalette, NtscParams, Palette};
+
+const NES_WIDTH: usize = 256;
+const NES_HEIGHT: usize = 240;
+const SAMPLES_PER_PIXEL: usize = 8;
+const LINE_SAMPLES: usize = NES_WIDTH * SAMPLES_PER_PIXEL;
+// blanking around the active part of a line, wide enough for every filter window
+const LINE_PADDING: usize = 32;
+
+// every scanline is 341 * 8 samples long, which moves the subcarrier phase by 4
+const PHASE_PER_LINE: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NtscPreset {
+    Composite,
+    SVideo,
+    Rgb,
+}
+
+impl std::str::FromStr for NtscPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "composite" => Ok(NtscPreset::Composite),
+            "svideo" | "s-video" => Ok(NtscPreset::SVideo),
+            "rgb" => Ok(NtscPreset::Rgb),
+            _ => Err(format!("unknown NTSC filter preset '{}'", s)),
+        }
+    }
+}
+
+pub struct NtscFilter {
+    preset: NtscPreset,
+    params: NtscParams,
+    palette: Palette,
+    // widths of the luma and chroma low-pass (box) filters, in samples
+    luma_width: usize,
+    chroma_width: usize,
+    frame_count: usize,
+
+    // per pixel value: average signal level, i.e. the luma an S-Video cable carries separately
+    luma_levels: Vec<f32>,
+    // prefix sums of one decoded line: luma, in-phase and quadrature products
+    sum_y: Vec<f32>,
+    sum_i: Vec<f32>,
+    sum_q: Vec<f32>,
+
+    pub data: Vec<u8>,
+}
+
+impl NtscFilter {
+    /// Output is widened the same way nes_ntsc does it: 256 pixels become 602.
+    pub const WIDTH: usize = 602;
+    pub const HEIGHT: usize = NES_HEIGHT;
+
+    /// `palette` is only used by the RGB preset, the others decode colors from the signal.
+    pub fn new(preset: NtscPreset, params: NtscParams, palette: Palette) -> Self {
+        let (luma_width, chroma_width) = match preset {
+            NtscPreset::Composite => (12, 24),
+            NtscPreset::SVideo => (4, 24),
+            NtscPreset::Rgb => (1, 1),
+        };
+        let luma_levels = (0..512u16)
+            .map(|pixel| (0..12).map(|phase| palette::composite_signal(pixel, phase)).sum::<f32>() / 12.0)
+            .collect();
+        let line_len = LINE_SAMPLES + 2 * LINE_PADDING + 1;
+
+        NtscFilter {
+            preset,
+            params,
+            palette,
+            luma_width,
+            chroma_width,
+            frame_count: 0,
+            luma_levels,
+            sum_y: vec![0.0; line_len],
+            sum_i: vec![0.0; line_len],
+            sum_q: vec![0.0; line_len],
+            data: vec![0; NtscFilter::WIDTH * NtscFilter::HEIGHT * 3],
+        }
+    }
+
+    pub fn apply(&mut self, frame: &Frame) -> &[u8] {
+        let frame_phase = (self.frame_count % 3) * PHASE_PER_LINE;
+        for y in 0..NES_HEIGHT {
+            match self.preset {
+                NtscPreset::Rgb => self.scale_line(frame, y),
+                _ => {
+                    self.encode_line(frame, y, (frame_phase + y * PHASE_PER_LINE) % 12);
+                    self.decode_line(y);
+                }
+            }
+        }
+        self.frame_count = self.frame_count.wrapping_add(1);
+        &self.data
+    }
+
+    fn encode_line(&mut self, frame: &Frame, y: usize, line_phase: usize) {
+        let (mut acc_y, mut acc_i, mut acc_q) = (0.0f32, 0.0f32, 0.0f32);
+        self.sum_y[0] = 0.0;
+        self.sum_i[0] = 0.0;
+        self.sum_q[0] = 0.0;
+
+        for n in 0..LINE_SAMPLES + 2 * LINE_PADDING {
+            let phase = (line_phase + n) % 12;
+            let (luma, signal) = if !(LINE_PADDING..LINE_PADDING + LINE_SAMPLES).contains(&n) {
+                (0.0, 0.0)
+            } else {
+                let pixel = frame.pixel((n - LINE_PADDING) / SAMPLES_PER_PIXEL, y);
+                let signal = palette::composite_signal(pixel, phase);
+                match self.preset {
+                    NtscPreset::SVideo => {
+                        let luma = self.luma_levels[pixel as usize & 0x1ff];
+                        (luma, signal - luma)
+                    }
+                    _ => (signal, signal),
+                }
+            };
+            let angle = palette::chroma_angle(phase, &self.params);
+            acc_y += luma;
+            acc_i += signal * angle.cos();
+            acc_q += signal * angle.sin();
+            self.sum_y[n + 1] = acc_y;
+            self.sum_i[n + 1] = acc_i;
+            self.sum_q[n + 1] = acc_q;
+        }
+    }
+
+    fn decode_line(&mut self, y: usize) {
+        let window = |sums: &[f32], center: usize, width: usize| {
+            let from = center - width / 2;
+            (sums[from + width] - sums[from]) / width as f32
+        };
+
+        for x in 0..NtscFilter::WIDTH {
+            let center = LINE_PADDING + (2 * x + 1) * LINE_SAMPLES / (2 * NtscFilter::WIDTH);
+            let rgb = palette::yiq_to_rgb(
+                window(&self.sum_y, center, self.luma_width),
+                window(&self.sum_i, center, self.chroma_width),
+                window(&self.sum_q, center, self.chroma_width),
+                &self.params,
+            );
+            self.put(x, y, rgb);
+        }
+    }
+
+    fn scale_line(&mut self, frame: &Frame, y: usize) {
+        for x in 0..NtscFilter::WIDTH {
+            let rgb = self.palette.rgb(frame.pixel(x * NES_WIDTH / NtscFilter::WIDTH, y));
+            self.put(x, y, rgb);
+        }
+    }
+
+    fn put(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
+        let base = (y * NtscFilter::WIDTH + x) * 3;
+        self.data[base] = rgb.0;
+        self.data[base + 1] = rgb.1;
+        self.data[base + 2] = rgb.2;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame_filled_with(pixel: u16) -> Frame {
+        let mut frame = Frame::new();
+        for y in 0..NES_HEIGHT {
+            for x in 0..NES_WIDTH {
+                frame.set_pixel(x, y, pixel);
+            }
+        }
+        frame
+    }
+
+    fn pixel(data: &[u8], x: usize, y: usize) -> (u8, u8, u8) {
+        let base = (y * NtscFilter::WIDTH + x) * 3;
+        (data[base], data[base + 1], data[base + 2])
+    }
+
+    #[test]
+    fn test_flat_color_matches_ntsc_palette() {
+        let expected = Palette::ntsc(&NtscParams::default()).color(0x16, 0);
+        for preset in [NtscPreset::Composite, NtscPreset::SVideo, NtscPreset::Rgb].iter() {
+            let mut filter = NtscFilter::new(*preset, NtscParams::default(), Palette::ntsc(&NtscParams::default()));
+            let data = filter.apply(&frame_filled_with(0x16));
+            let (r, g, b) = pixel(data, NtscFilter::WIDTH / 2, 100);
+            let close = |a: u8, b: u8| (a as i16 - b as i16).abs() <= 3;
+            assert!(
+                close(r, expected.0) && close(g, expected.1) && close(b, expected.2),
+                "{:?}: expected {:?}, got {:?}", preset, expected, (r, g, b)
+            );
+        }
+    }
+
+    #[test]
+    fn test_composite_has_artifact_colors_on_luma_edges() {
+        // alternating black and white columns: no chroma in the source
+        let mut frame = Frame::new();
+        for y in 0..NES_HEIGHT {
+            for x in 0..NES_WIDTH {
+                frame.set_pixel(x, y, if x % 2 == 0 { 0x0f } else { 0x30 });
+            }
+        }
+        let saturation = |data: &[u8]| -> u32 {
+            (0..NtscFilter::WIDTH)
+                .map(|x| {
+                    let (r, g, b) = pixel(data, x, 100);
+                    (r.max(g).max(b) - r.min(g).min(b)) as u32
+                })
+                .sum()
+        };
+
+        let mut composite = NtscFilter::new(NtscPreset::Composite, NtscParams::default(), Palette::system());
+        let mut rgb = NtscFilter::new(NtscPreset::Rgb, NtscParams::default(), Palette::system());
+        assert!(saturation(composite.apply(&frame)) > 1000);
+        assert_eq!(saturation(rgb.apply(&frame)), 0);
+    }
+}
@@ -15,3 +15,315 @@ pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
     (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA), 
     (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
 ];
+
+// Voltage levels of the composite signal, relative to sync, for the four
+// luma rows of the palette (low and high half of the chroma wave).
+// http://wiki.nesdev.com/w/index.php/NTSC_video
+const SIGNAL_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const SIGNAL_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const SIGNAL_BLACK: f32 = 0.518;
+const SIGNAL_WHITE: f32 = 1.962;
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+// Emphasis on a palette loaded without it is approximated by dimming the
+// two channels that are not emphasised.
+const EMPHASIS_DIM: f32 = 0.816;
+
+const PAL_FILE_SIZE: usize = 64 * 3;
+const PAL_FILE_WITH_EMPHASIS_SIZE: usize = 64 * 8 * 3;
+
+/// Parameters for decoding the PPU composite signal into RGB.
+/// Defaults produce a neutral palette close to the ones shipped by FCEUX and Mesen.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NtscParams {
+    /// hue rotation in degrees
+    pub hue: f32,
+    pub saturation: f32,
+    pub contrast: f32,
+    pub brightness: f32,
+    pub gamma: f32,
+}
+
+impl Default for NtscParams {
+    fn default() -> Self {
+        NtscParams {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorBlindness {
+    Protanopia,
+    Deuteranopia,
+    Tritanopia,
+}
+
+impl std::str::FromStr for ColorBlindness {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "protanopia" => Ok(ColorBlindness::Protanopia),
+            "deuteranopia" => Ok(ColorBlindness::Deuteranopia),
+            "tritanopia" => Ok(ColorBlindness::Tritanopia),
+            _ => Err(format!("unknown color blindness type '{}'", s)),
+        }
+    }
+}
+
+/// Level of the composite signal the PPU outputs for a pixel (6-bit color in
+/// bits 0-5, emphasis in bits 6-8) at one of the 12 phases of the color
+/// subcarrier. 0.0 is black and 1.0 is white.
+pub fn composite_signal(pixel: u16, phase: usize) -> f32 {
+    let color = (pixel & 0x3f) as usize;
+    let emphasis = (pixel >> 6) & 0b111;
+    let hue = color & 0x0f;
+    let level = color >> 4;
+
+    let (mut low, mut high) = (SIGNAL_LOW[level], SIGNAL_HIGH[level]);
+    if hue == 0 {
+        low = high;
+    } else if hue == 0x0d {
+        high = low;
+    } else if hue > 0x0d {
+        low = SIGNAL_BLACK;
+        high = SIGNAL_BLACK;
+    }
+
+    let in_phase = |hue: usize| (hue + phase) % 12 < 6;
+    let mut signal = if in_phase(hue) { high } else { low };
+    // emphasis bits attenuate the signal during the red (phase 0),
+    // green (4) and blue (8) parts of the color wave
+    if (emphasis & 0b001 != 0 && in_phase(0))
+        || (emphasis & 0b010 != 0 && in_phase(4))
+        || (emphasis & 0b100 != 0 && in_phase(8))
+    {
+        signal *= EMPHASIS_ATTENUATION;
+    }
+    (signal - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK)
+}
+
+/// Angle of the I/Q demodulation carrier at the given subcarrier phase.
+pub fn chroma_angle(phase: usize, params: &NtscParams) -> f32 {
+    // 3.9 color clocks of delay line up the colorburst with the I/Q axes
+    std::f32::consts::PI * (phase as f32 + 3.9) / 6.0 + params.hue.to_radians()
+}
+
+pub fn yiq_to_rgb(y: f32, i: f32, q: f32, params: &NtscParams) -> (u8, u8, u8) {
+    let y = y * params.contrast + params.brightness;
+    let i = i * params.saturation * params.contrast;
+    let q = q * params.saturation * params.contrast;
+
+    let to_byte = |v: f32| {
+        let v = v.clamp(0.0, 1.0).powf(1.0 / params.gamma);
+        (v * 255.0).round() as u8
+    };
+    (
+        to_byte(y + 0.946882 * i + 0.623557 * q),
+        to_byte(y - 0.274788 * i - 0.635691 * q),
+        to_byte(y - 1.108545 * i + 1.709007 * q),
+    )
+}
+
+/// Maps the pixels of a `Frame` (palette index + emphasis bits) to colors.
+pub trait ColorPalette {
+    fn rgb(&self, pixel: u16) -> (u8, u8, u8);
+}
+
+/// 64 colors for each of the 8 combinations of the PPUMASK emphasis bits.
+/// Entry `emphasis * 64 + color` matches the layout of 1536-byte .pal files.
+#[derive(Clone, Debug)]
+pub struct Palette {
+    colors: Vec<(u8, u8, u8)>,
+}
+
+impl Palette {
+    pub fn system() -> Self {
+        Palette::with_synthesized_emphasis(&SYSTEM_PALLETE)
+    }
+
+    pub fn from_file(path: &str) -> Result<Palette, String> {
+        let raw = std::fs::read(path).map_err(|e| format!("Can't read palette '{}': {}", path, e))?;
+        Palette::from_pal_bytes(&raw)
+    }
+
+    pub fn from_pal_bytes(raw: &[u8]) -> Result<Palette, String> {
+        let colors: Vec<(u8, u8, u8)> = raw.chunks_exact(3).map(|c| (c[0], c[1], c[2])).collect();
+        match raw.len() {
+            PAL_FILE_SIZE => {
+                let mut base = [(0, 0, 0); 64];
+                base.copy_from_slice(&colors);
+                Ok(Palette::with_synthesized_emphasis(&base))
+            }
+            PAL_FILE_WITH_EMPHASIS_SIZE => Ok(Palette { colors }),
+            len => Err(format!(
+                "Palette file should be {} or {} bytes long, got {}",
+                PAL_FILE_SIZE, PAL_FILE_WITH_EMPHASIS_SIZE, len
+            )),
+        }
+    }
+
+    // Decodes the signal the 2C02 generates for every color the same way a
+    // TV would: 12 samples per color clock, demodulated into YIQ.
+    pub fn ntsc(params: &NtscParams) -> Self {
+        let mut colors = Vec::with_capacity(64 * 8);
+        for emphasis in 0..8u8 {
+            for color in 0..64u8 {
+                colors.push(Palette::decode_ntsc_color(color, emphasis, params));
+            }
+        }
+        Palette { colors }
+    }
+
+    fn decode_ntsc_color(color: u8, emphasis: u8, params: &NtscParams) -> (u8, u8, u8) {
+        let pixel = (emphasis as u16) << 6 | color as u16;
+
+        let (mut y, mut i, mut q) = (0.0f32, 0.0f32, 0.0f32);
+        for phase in 0..12 {
+            let value = composite_signal(pixel, phase) / 12.0;
+            let angle = chroma_angle(phase, params);
+            y += value;
+            i += value * angle.cos();
+            q += value * angle.sin();
+        }
+        yiq_to_rgb(y, i, q, params)
+    }
+
+    fn with_synthesized_emphasis(base: &[(u8, u8, u8); 64]) -> Self {
+        let mut colors = Vec::with_capacity(64 * 8);
+        for emphasis in 0..8u8 {
+            let dim = |channel: u8, emphasised: bool| -> u8 {
+                if emphasis == 0 || emphasised {
+                    channel
+                } else {
+                    (channel as f32 * EMPHASIS_DIM) as u8
+                }
+            };
+            for &(r, g, b) in base.iter() {
+                colors.push((
+                    dim(r, emphasis & 0b001 != 0),
+                    dim(g, emphasis & 0b010 != 0),
+                    dim(b, emphasis & 0b100 != 0),
+                ));
+            }
+        }
+        Palette { colors }
+    }
+
+    /// Remaps colors so that hues a viewer with the given color vision deficiency
+    /// can't tell apart are shifted towards ones they can (daltonization).
+    pub fn daltonize(&mut self, kind: ColorBlindness) {
+        // simulation matrices in linear RGB, Machado et al. 2009 (severity 1.0)
+        let simulate: [[f32; 3]; 3] = match kind {
+            ColorBlindness::Protanopia => [
+                [0.152286, 1.052583, -0.204868],
+                [0.114503, 0.786281, 0.099216],
+                [-0.003882, -0.048116, 1.051998],
+            ],
+            ColorBlindness::Deuteranopia => [
+                [0.367322, 0.860646, -0.227968],
+                [0.280085, 0.672501, 0.047413],
+                [-0.011820, 0.042940, 0.968881],
+            ],
+            ColorBlindness::Tritanopia => [
+                [1.255528, -0.076749, -0.178779],
+                [-0.078411, 0.930809, 0.147602],
+                [0.004733, 0.691367, 0.303900],
+            ],
+        };
+
+        for color in self.colors.iter_mut() {
+            let rgb = [color.0 as f32, color.1 as f32, color.2 as f32];
+            let mut seen = [0.0f32; 3];
+            for (row, out) in simulate.iter().zip(seen.iter_mut()) {
+                *out = row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2];
+            }
+            // shift the information that is lost into the channels that are still perceived
+            let err = [rgb[0] - seen[0], rgb[1] - seen[1], rgb[2] - seen[2]];
+            let fix = |v: f32, shift: f32| (v + shift).clamp(0.0, 255.0) as u8;
+            *color = (
+                fix(rgb[0], 0.0),
+                fix(rgb[1], 0.7 * err[0] + err[1]),
+                fix(rgb[2], 0.7 * err[0] + err[2]),
+            );
+        }
+    }
+
+    pub fn color(&self, index: u8, emphasis: u8) -> (u8, u8, u8) {
+        self.colors[((emphasis & 0b111) as usize) * 64 + (index & 0x3f) as usize]
+    }
+}
+
+impl ColorPalette for Palette {
+    fn rgb(&self, pixel: u16) -> (u8, u8, u8) {
+        self.color((pixel & 0x3f) as u8, (pixel >> 6) as u8)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load_pal_without_emphasis() {
+        let raw: Vec<u8> = (0..64 * 3).map(|x| x as u8).collect();
+        let palette = Palette::from_pal_bytes(&raw).unwrap();
+
+        assert_eq!(palette.color(0x01, 0), (3, 4, 5));
+        assert_eq!(palette.color(0x41, 0), (3, 4, 5)); // only 6 bits select the color
+        let (r, g, b) = palette.color(0x3f, 0b001);
+        assert_eq!(r, 189);
+        assert!(g < 190 && b < 191);
+    }
+
+    #[test]
+    fn test_load_pal_with_emphasis() {
+        let mut raw = vec![0; 64 * 8 * 3];
+        raw[(7 * 64 + 2) * 3] = 0xAB;
+        let palette = Palette::from_pal_bytes(&raw).unwrap();
+
+        assert_eq!(palette.color(0x02, 0b111), (0xAB, 0, 0));
+        assert_eq!(palette.color(0x02, 0), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_load_pal_wrong_size() {
+        let e = Palette::from_pal_bytes(&[0; 100]).unwrap_err();
+        assert_eq!(e, "Palette file should be 192 or 1536 bytes long, got 100");
+    }
+
+    #[test]
+    fn test_daltonize() {
+        let mut raw = vec![0; 64 * 3];
+        raw[0x16 * 3] = 255;
+        let mut palette = Palette::from_pal_bytes(&raw).unwrap();
+        palette.daltonize(ColorBlindness::Protanopia);
+
+        // the red a protanope can't see moves into green and blue, black stays
+        assert_eq!(palette.color(0x16, 0), (255, 122, 152));
+        assert_eq!(palette.color(0x0f, 0), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_ntsc_palette() {
+        let palette = Palette::ntsc(&NtscParams::default());
+
+        assert_eq!(palette.color(0x0f, 0), (0, 0, 0));
+        assert_eq!(palette.color(0x30, 0), (255, 255, 255));
+
+        let (r, g, b) = palette.color(0x16, 0);
+        assert!(r > g && r > b, "0x16 should be red, got {:?}", (r, g, b));
+        let (r, g, b) = palette.color(0x2a, 0);
+        assert!(g > r && g > b, "0x2a should be green, got {:?}", (r, g, b));
+        let (r, g, b) = palette.color(0x12, 0);
+        assert!(b > r && b > g, "0x12 should be blue, got {:?}", (r, g, b));
+
+        let (r, g, b) = palette.color(0x20, 0b001);
+        assert!(r > g && r > b, "red emphasis should tint white, got {:?}", (r, g, b));
+    }
+}
@@ -150,7 +150,7 @@ mod test {
 
     #[test]
     fn test_format_trace() {
-        let mut bus = Bus::new(test_rom(), |_ppu, _joypad| {});
+        let mut bus = Bus::new(test_rom(), |_ppu, _apu, _joypad| {});
         bus.mem_write(100, 0xa2);
         bus.mem_write(101, 0x01);
         bus.mem_write(102, 0xca);
@@ -182,7 +182,7 @@ mod test {
 
     #[test]
     fn test_format_mem_access() {
-        let mut bus = Bus::new(test_rom(), |_ppu, _joypad| {});
+        let mut bus = Bus::new(test_rom(), |_ppu, _apu, _joypad| {});
         // ORA ($33), Y
         bus.mem_write(100, 0x11);
         bus.mem_write(101, 0x33);